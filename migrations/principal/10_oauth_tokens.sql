@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS oauth_tokens (
+    identity_pk BIGINT PRIMARY KEY,
+    access_token BYTEA NOT NULL,
+    refresh_token BYTEA,
+    expires_at timestamp,
+    updated_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_identity FOREIGN KEY (identity_pk) REFERENCES user_identities (pk) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_oauth_tokens_expires_at ON oauth_tokens(expires_at);
+
+-- The plaintext tokens are moved to oauth_tokens by TokenVault::import_google_profiles
+ALTER TABLE google_profiles ALTER COLUMN access_token DROP NOT NULL;
+ALTER TABLE google_profiles ALTER COLUMN refresh_token DROP NOT NULL;
+ALTER TABLE google_profiles ALTER COLUMN expires_in DROP NOT NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE oauth_state ADD COLUMN created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP;
+CREATE INDEX idx_oauth_state_created_at ON oauth_state(created_at);
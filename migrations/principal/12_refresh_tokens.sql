@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    pk bigserial PRIMARY KEY,
+    family UUID NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    user_pk BIGINT NOT NULL,
+    expires_at timestamp NOT NULL,
+    used_at timestamp,
+    revoked_at timestamp,
+    created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_user FOREIGN KEY (user_pk) REFERENCES users (pk) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_refresh_tokens_family ON refresh_tokens(family);
+CREATE INDEX idx_refresh_tokens_user_pk ON refresh_tokens(user_pk);
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS api_keys (
+    pk bigserial PRIMARY KEY,
+    user_pk BIGINT NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    prefix VARCHAR(16) NOT NULL UNIQUE,
+    key_hash VARCHAR(64) NOT NULL,
+    scopes TEXT[] NOT NULL DEFAULT '{}',
+    expires_at timestamp,
+    last_used_at timestamp,
+    revoked_at timestamp,
+    created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_user FOREIGN KEY (user_pk) REFERENCES users (pk) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_api_keys_user_pk ON api_keys(user_pk);
@@ -0,0 +1,39 @@
+-- The default groups were inserted with explicit pks
+SELECT setval('groups_pk_seq', (SELECT MAX(pk) FROM groups));
+
+CREATE TABLE IF NOT EXISTS permissions (
+    pk bigserial PRIMARY KEY,
+    name VARCHAR(255) NOT NULL UNIQUE,
+    created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS groups_permissions_m2m (
+    group_pk BIGINT NOT NULL,
+    permission_pk BIGINT NOT NULL,
+    created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (group_pk, permission_pk),
+    CONSTRAINT fk_group FOREIGN KEY (group_pk) REFERENCES groups (pk) ON DELETE CASCADE,
+    CONSTRAINT fk_permission FOREIGN KEY (permission_pk) REFERENCES permissions (pk) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS users_permissions_m2m (
+    user_pk BIGINT NOT NULL,
+    permission_pk BIGINT NOT NULL,
+    created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_pk, permission_pk),
+    CONSTRAINT fk_user FOREIGN KEY (user_pk) REFERENCES users (pk) ON DELETE CASCADE,
+    CONSTRAINT fk_permission FOREIGN KEY (permission_pk) REFERENCES permissions (pk) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_users_permissions_permission_pk ON users_permissions_m2m(permission_pk);
+
+-- The permissions of each user, through their groups or granted directly
+CREATE VIEW user_permissions AS
+    SELECT users_groups_m2m.user_pk, permissions.name
+    FROM users_groups_m2m
+    INNER JOIN groups_permissions_m2m ON groups_permissions_m2m.group_pk = users_groups_m2m.group_pk
+    INNER JOIN permissions ON permissions.pk = groups_permissions_m2m.permission_pk
+    UNION
+    SELECT users_permissions_m2m.user_pk, permissions.name
+    FROM users_permissions_m2m
+    INNER JOIN permissions ON permissions.pk = users_permissions_m2m.permission_pk;
@@ -0,0 +1,32 @@
+CREATE TABLE IF NOT EXISTS organizations (
+    pk bigserial PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS organization_memberships (
+    organization_pk BIGINT NOT NULL,
+    user_pk BIGINT NOT NULL,
+    role VARCHAR(64) NOT NULL,
+    created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (organization_pk, user_pk),
+    CONSTRAINT fk_organization FOREIGN KEY (organization_pk) REFERENCES organizations (pk) ON DELETE CASCADE,
+    CONSTRAINT fk_user FOREIGN KEY (user_pk) REFERENCES users (pk) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_organization_memberships_user ON organization_memberships(user_pk);
+
+CREATE TABLE IF NOT EXISTS organization_invitations (
+    pk bigserial PRIMARY KEY,
+    organization_pk BIGINT NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    role VARCHAR(64) NOT NULL,
+    slug VARCHAR(255) NOT NULL UNIQUE,
+    invited_by_pk BIGINT,
+    expires_at timestamp NOT NULL,
+    accepted_at timestamp,
+    declined_at timestamp,
+    created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_organization FOREIGN KEY (organization_pk) REFERENCES organizations (pk) ON DELETE CASCADE,
+    CONSTRAINT fk_invited_by FOREIGN KEY (invited_by_pk) REFERENCES users (pk) ON DELETE SET NULL
+);
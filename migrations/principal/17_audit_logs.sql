@@ -0,0 +1,102 @@
+-- The actor and target are kept as values, the log outlives the users. Entries are inserted
+-- unsealed, without any lock, then audit_seal chains them: seq, previous_hash and hash are set.
+CREATE TABLE IF NOT EXISTS audit_logs (
+    pk bigserial PRIMARY KEY,
+    actor_pk BIGINT,
+    action VARCHAR(64) NOT NULL,
+    target TEXT,
+    metadata JSONB NOT NULL DEFAULT '{}',
+    created_at timestamp NOT NULL,
+    seq BIGINT UNIQUE,
+    previous_hash CHAR(64),
+    hash CHAR(64) UNIQUE,
+    CHECK ((seq IS NULL) = (hash IS NULL) AND (seq IS NULL) = (previous_hash IS NULL))
+);
+
+CREATE INDEX idx_audit_logs_actor_pk ON audit_logs(actor_pk);
+CREATE INDEX idx_audit_logs_action ON audit_logs(action, created_at);
+CREATE INDEX idx_audit_logs_unsealed ON audit_logs(pk) WHERE hash IS NULL;
+
+-- The last sealed entry, its row lock serializes the sealing
+CREATE TABLE IF NOT EXISTS audit_chain_head (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    seq BIGINT NOT NULL DEFAULT 0,
+    hash CHAR(64) NOT NULL DEFAULT repeat('0', 64)
+);
+
+INSERT INTO audit_chain_head DEFAULT VALUES;
+
+-- Each entry hashes the previous one, so changing or removing an entry breaks the chain
+CREATE OR REPLACE FUNCTION audit_hash(
+    previous_hash TEXT, actor_pk BIGINT, action TEXT, target TEXT, metadata JSONB, created_at TIMESTAMP
+) RETURNS TEXT AS $$
+    SELECT encode(sha256(convert_to(jsonb_build_array(
+        previous_hash, actor_pk, action, target, metadata, to_char(created_at, 'YYYY-MM-DD"T"HH24:MI:SS.US')
+    )::text, 'UTF8')), 'hex');
+$$ LANGUAGE SQL STABLE;
+
+CREATE OR REPLACE FUNCTION audit_record(
+    p_actor_pk BIGINT, p_action TEXT, p_target TEXT, p_metadata JSONB
+) RETURNS BIGINT AS $$
+    INSERT INTO audit_logs (actor_pk, action, target, metadata, created_at)
+    VALUES (p_actor_pk, p_action, p_target, COALESCE(p_metadata, '{}'), clock_timestamp() AT TIME ZONE 'UTC')
+    RETURNING pk;
+$$ LANGUAGE SQL;
+
+-- Chain the committed unsealed entries in pk order, returns how many. Run it in its own short
+-- transaction: the head stays locked until the end of the transaction.
+CREATE OR REPLACE FUNCTION audit_seal() RETURNS BIGINT AS $$
+DECLARE
+    head_seq BIGINT;
+    head_hash TEXT;
+    entry RECORD;
+    sealed BIGINT := 0;
+BEGIN
+    SELECT seq, hash INTO head_seq, head_hash FROM audit_chain_head FOR UPDATE;
+    FOR entry IN SELECT pk, actor_pk, action, target, metadata, created_at FROM audit_logs WHERE hash IS NULL ORDER BY pk LOOP
+        head_seq := head_seq + 1;
+        UPDATE audit_logs SET seq = head_seq, previous_hash = head_hash,
+            hash = audit_hash(head_hash, entry.actor_pk, entry.action, entry.target, entry.metadata, entry.created_at)
+        WHERE pk = entry.pk
+        RETURNING hash INTO head_hash;
+        sealed := sealed + 1;
+    END LOOP;
+    UPDATE audit_chain_head SET seq = head_seq, hash = head_hash;
+    RETURN sealed;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Only the sealing may touch an entry, once
+CREATE OR REPLACE FUNCTION audit_logs_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.hash IS NULL
+        AND (NEW.pk, NEW.actor_pk, NEW.action, NEW.target, NEW.metadata, NEW.created_at)
+            IS NOT DISTINCT FROM (OLD.pk, OLD.actor_pk, OLD.action, OLD.target, OLD.metadata, OLD.created_at) THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'audit_logs is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_logs_no_update BEFORE UPDATE OR DELETE ON audit_logs
+    FOR EACH ROW EXECUTE FUNCTION audit_logs_append_only();
+CREATE TRIGGER audit_logs_no_truncate BEFORE TRUNCATE ON audit_logs
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_logs_append_only();
+
+-- Group and permission changes are recorded whatever code makes them, TG_ARGV[0] names the kind.
+-- They are inserted unsealed, so they take no lock in the transaction making the change.
+CREATE OR REPLACE FUNCTION audit_user_grants() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM audit_record(NULL, TG_ARGV[0] || '.added', NEW.user_pk::text, to_jsonb(NEW) - 'user_pk' - 'created_at');
+        RETURN NEW;
+    END IF;
+    PERFORM audit_record(NULL, TG_ARGV[0] || '.removed', OLD.user_pk::text, to_jsonb(OLD) - 'user_pk' - 'created_at');
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_users_groups AFTER INSERT OR DELETE ON users_groups_m2m
+    FOR EACH ROW EXECUTE FUNCTION audit_user_grants('group');
+CREATE TRIGGER audit_users_permissions AFTER INSERT OR DELETE ON users_permissions_m2m
+    FOR EACH ROW EXECUTE FUNCTION audit_user_grants('permission');
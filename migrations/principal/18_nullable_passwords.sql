@@ -0,0 +1,3 @@
+-- Users without a password, e.g. signed up with a provider or after removing it, have NULL
+ALTER TABLE users ALTER COLUMN password DROP NOT NULL;
+UPDATE users SET password = NULL WHERE password = '';
@@ -0,0 +1,2 @@
+-- Sessions read a groups value of digits alone as legacy group pks, "12" would be admin and user
+ALTER TABLE groups ADD CONSTRAINT groups_name_not_digits CHECK (name !~ '^[0-9]+$');
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS magic_links (
+    slug VARCHAR(255) NOT NULL UNIQUE,
+    created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at timestamp NOT NULL,
+    session_id VARCHAR(255) NOT NULL,
+    email_pk BIGINT NOT NULL,
+    CONSTRAINT fk_email FOREIGN KEY (email_pk) REFERENCES emails (pk) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+CREATE INDEX IF NOT EXISTS idx_email_validations_email_pk ON email_validations(email_pk);
+CREATE INDEX IF NOT EXISTS idx_email_validations_created_at ON email_validations(created_at);
@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deactivated_by_admin BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS delete_after TIMESTAMP;
+
+CREATE INDEX IF NOT EXISTS idx_users_delete_after ON users(delete_after);
@@ -0,0 +1 @@
+CREATE UNIQUE INDEX IF NOT EXISTS idx_emails_one_primary ON emails(user_pk) WHERE is_primary;
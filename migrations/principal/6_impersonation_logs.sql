@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS impersonation_logs (
+    pk bigserial PRIMARY KEY,
+    admin_pk BIGINT NOT NULL,
+    user_pk BIGINT NOT NULL,
+    started_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    stopped_at timestamp,
+    CONSTRAINT fk_admin FOREIGN KEY (admin_pk) REFERENCES users (pk) ON DELETE CASCADE,
+    CONSTRAINT fk_user FOREIGN KEY (user_pk) REFERENCES users (pk) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+ALTER TABLE google_oauth_state RENAME TO oauth_state;
+ALTER TABLE oauth_state ADD COLUMN provider VARCHAR(255) NOT NULL DEFAULT 'google';
+CREATE INDEX idx_oauth_state_csrf_state ON oauth_state(csrf_state);
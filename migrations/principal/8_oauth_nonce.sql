@@ -0,0 +1 @@
+ALTER TABLE oauth_state ADD COLUMN nonce VARCHAR(255);
@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS user_identities (
+    pk bigserial PRIMARY KEY,
+    user_pk BIGINT NOT NULL,
+    provider VARCHAR(255) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    email VARCHAR(255),
+    created_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_user FOREIGN KEY (user_pk) REFERENCES users (pk) ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX idx_user_identities_subject ON user_identities(provider, subject);
+CREATE INDEX idx_user_identities_user_pk ON user_identities(user_pk);
+
+INSERT INTO user_identities (user_pk, provider, subject, email)
+SELECT emails.user_pk, 'google', google_profiles.id, emails.email
+FROM google_profiles
+INNER JOIN emails ON emails.pk = google_profiles.email_pk
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE oauth_state ADD COLUMN link_user_pk BIGINT;
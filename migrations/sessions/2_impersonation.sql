@@ -0,0 +1 @@
+ALTER TABLE web_sessions ADD COLUMN impersonator_pk INTEGER;
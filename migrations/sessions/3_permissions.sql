@@ -0,0 +1 @@
+ALTER TABLE web_sessions ADD COLUMN permissions TEXT NOT NULL DEFAULT '';
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE web_sessions ADD COLUMN organization_pk INTEGER;
\ No newline at end of file
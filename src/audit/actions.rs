@@ -0,0 +1,16 @@
+//! The actions recorded by the crate. Actions are free strings, apps record theirs next to these.
+
+pub const LOGIN: &str = "login";
+pub const LOGIN_FAILED: &str = "login.failed";
+pub const PASSWORD_CHANGED: &str = "password.changed";
+pub const PASSWORD_REMOVED: &str = "password.removed";
+/// Recorded by the database on `users_groups_m2m`
+pub const GROUP_ADDED: &str = "group.added";
+pub const GROUP_REMOVED: &str = "group.removed";
+/// Recorded by the database on `users_permissions_m2m`
+pub const PERMISSION_ADDED: &str = "permission.added";
+pub const PERMISSION_REMOVED: &str = "permission.removed";
+pub const IMPERSONATION_STARTED: &str = "impersonation.started";
+pub const IMPERSONATION_STOPPED: &str = "impersonation.stopped";
+/// For the apps handling the payments webhooks, `PaymentsProcessor` does not see their outcome
+pub const PAYMENT: &str = "payment";
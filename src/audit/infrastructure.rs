@@ -0,0 +1,354 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, PgExecutor, Postgres, QueryBuilder};
+
+use crate::{database::Database, errors::AppError, log_and_wrap_custom_internal};
+
+const MAX_LIMIT: i64 = 500;
+
+/// Append an entry to the audit log and seal it, returns its pk. Call it after the action's
+/// transaction commits: the sealing locks the head of the chain, it must stay short.
+///
+/// ```rust,ignore
+/// audit::record(Some(user.pk), actions::PAYMENT, Some(&invoice), json!({"amount": 10}), &database).await?;
+/// ```
+pub async fn record(
+    actor_pk: Option<i64>,
+    action: &str,
+    target: Option<&str>,
+    metadata: Value,
+    database: &Database,
+) -> Result<i64, AppError> {
+    let pk = sqlx::query_scalar("SELECT audit_record($1, $2, $3, $4);")
+        .bind(actor_pk)
+        .bind(action)
+        .bind(target)
+        .bind(metadata)
+        .fetch_one(&**database)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+    AuditLog::seal(database).await?;
+    Ok(pk)
+}
+
+/// Same as [`record`] on the paths that already fail, e.g. a failed login: an audit error is
+/// logged instead of replacing the original error.
+pub async fn record_or_log(
+    actor_pk: Option<i64>,
+    action: &str,
+    target: Option<&str>,
+    metadata: Value,
+    database: &Database,
+) {
+    if let Err(e) = record(actor_pk, action, target, metadata, database).await {
+        tracing::error!("Cannot record the {} audit entry: {:?}", action, e);
+    }
+}
+
+/// An entry of the tamper-evident audit log. Each `hash` covers the entry and the previous
+/// hash, so editing or deleting past entries breaks the chain from there. The entries written
+/// by the database triggers have no `seq` and hashes until they are sealed.
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct AuditLog {
+    pub pk: i64,
+    pub actor_pk: Option<i64>,
+    pub action: String,
+    pub target: Option<String>,
+    pub metadata: Value,
+    pub created_at: NaiveDateTime,
+    pub seq: Option<i64>,
+    pub previous_hash: Option<String>,
+    pub hash: Option<String>,
+}
+
+impl AuditLog {
+    pub async fn search<'e, E: PgExecutor<'e>>(
+        filter: &AuditFilter,
+        executor: E,
+    ) -> Result<Vec<Self>, AppError> {
+        let mut query = QueryBuilder::<Postgres>::new(
+            "SELECT pk, actor_pk, action, target, metadata, created_at, seq, previous_hash, hash FROM audit_logs WHERE TRUE",
+        );
+        if let Some(actor_pk) = filter.actor_pk {
+            query.push(" AND actor_pk = ").push_bind(actor_pk);
+        }
+        if let Some(action) = &filter.action {
+            query.push(" AND action = ").push_bind(action);
+        }
+        if let Some(target) = &filter.target {
+            query.push(" AND target = ").push_bind(target);
+        }
+        if let Some(since) = filter.since {
+            query.push(" AND created_at >= ").push_bind(since);
+        }
+        if let Some(until) = filter.until {
+            query.push(" AND created_at < ").push_bind(until);
+        }
+        if let Some(before_pk) = filter.before_pk {
+            query.push(" AND pk < ").push_bind(before_pk);
+        }
+        query
+            .push(" ORDER BY pk DESC LIMIT ")
+            .push_bind(filter.limit.clamp(1, MAX_LIMIT));
+
+        query
+            .build_query_as()
+            .fetch_all(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Chain the pending entries, returns how many were sealed
+    pub async fn seal(database: &Database) -> Result<i64, AppError> {
+        sqlx::query_scalar("SELECT audit_seal();")
+            .fetch_one(&**database)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Seals the entries of the triggers when no `record` call does it first
+    pub async fn run_sealer(database: Database) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = Self::seal(&database).await {
+                tracing::error!("Cannot seal the audit log: {:?}", e);
+            }
+        }
+    }
+
+    /// The hash of the last sealed entry. Keep it somewhere else now and then: removing the
+    /// latest entries and the head together leaves a valid chain, only a known head reveals it.
+    pub async fn head<'e, E: PgExecutor<'e>>(executor: E) -> Result<String, AppError> {
+        sqlx::query_scalar("SELECT hash FROM audit_chain_head;")
+            .fetch_one(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Recompute the chain, returns the seq of the first sealed entry that does not match. A
+    /// chain shorter than its head returns the seq after its last entry.
+    pub async fn verify<'e, E: PgExecutor<'e>>(executor: E) -> Result<Option<i64>, AppError> {
+        sqlx::query_scalar(
+            "WITH entries AS (
+                SELECT seq, hash, previous_hash,
+                    audit_hash(previous_hash, actor_pk, action, target, metadata, created_at) AS expected,
+                    LAG(hash, 1, repeat('0', 64)) OVER (ORDER BY seq) AS chained,
+                    LAG(seq, 1, 0::bigint) OVER (ORDER BY seq) AS previous_seq
+                FROM audit_logs WHERE seq IS NOT NULL
+            ),
+            last AS (SELECT COALESCE(MAX(seq), 0) AS seq, (ARRAY_AGG(hash ORDER BY seq DESC))[1] AS hash FROM entries)
+            SELECT seq FROM entries
+            WHERE hash <> expected OR previous_hash <> chained OR seq <> previous_seq + 1
+            UNION ALL
+            SELECT last.seq + 1 FROM last, audit_chain_head head
+            WHERE head.seq <> last.seq OR head.hash IS DISTINCT FROM COALESCE(last.hash, repeat('0', 64))
+            ORDER BY 1 LIMIT 1;",
+        )
+        .fetch_optional(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+}
+
+/// The filters of [`AuditLog::search`], most recent first. Page with `before_pk`, the pk of the
+/// last entry received.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditFilter {
+    pub actor_pk: Option<i64>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub before_pk: Option<i64>,
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+}
+
+impl Default for AuditFilter {
+    fn default() -> Self {
+        Self {
+            actor_pk: None,
+            action: None,
+            target: None,
+            since: None,
+            until: None,
+            before_pk: None,
+            limit: default_limit(),
+        }
+    }
+}
+
+fn default_limit() -> i64 {
+    50
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    use crate::{
+        audit::actions,
+        database::Database,
+        models::{Group, User},
+    };
+
+    use super::*;
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_audit_chain(pool: PgPool) {
+        let database: Database = pool.into();
+        assert_eq!(AuditLog::head(&*database).await.unwrap(), "0".repeat(64));
+
+        record(
+            Some(1),
+            actions::LOGIN,
+            None,
+            json!({"method": "password"}),
+            &database,
+        )
+        .await
+        .unwrap();
+        // Emails go up to 254 characters, anything longer fits too
+        let long_target = format!("{}@example.com", "a".repeat(300));
+        record(
+            None,
+            actions::LOGIN_FAILED,
+            Some(&long_target),
+            json!({"method": "password"}),
+            &database,
+        )
+        .await
+        .unwrap();
+        record(
+            Some(1),
+            actions::PAYMENT,
+            Some("in_1"),
+            json!({"amount": 1.5}),
+            &database,
+        )
+        .await
+        .unwrap();
+
+        let entries = AuditLog::search(&AuditFilter::default(), &*database)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].target.as_ref(), Some(&long_target));
+        assert_eq!(
+            entries.iter().map(|e| e.seq).collect::<Vec<_>>(),
+            vec![Some(3), Some(2), Some(1)]
+        );
+        assert_eq!(entries[2].previous_hash, Some("0".repeat(64)));
+        assert_eq!(entries[1].previous_hash, entries[2].hash);
+        assert_eq!(entries[0].previous_hash, entries[1].hash);
+        assert_eq!(
+            Some(AuditLog::head(&*database).await.unwrap()),
+            entries[0].hash
+        );
+        assert_eq!(AuditLog::verify(&*database).await.unwrap(), None);
+
+        // The table refuses changes, a superuser can still get around it
+        assert!(sqlx::query("DELETE FROM audit_logs;")
+            .execute(&*database)
+            .await
+            .is_err());
+        assert!(sqlx::query("UPDATE audit_logs SET action = 'logout';")
+            .execute(&*database)
+            .await
+            .is_err());
+        sqlx::raw_sql(
+            "ALTER TABLE audit_logs DISABLE TRIGGER audit_logs_no_update;
+            UPDATE audit_logs SET metadata = '{\"method\": \"magic_link\"}' WHERE action = 'login';
+            ALTER TABLE audit_logs ENABLE TRIGGER audit_logs_no_update;",
+        )
+        .execute(&*database)
+        .await
+        .unwrap();
+        assert_eq!(AuditLog::verify(&*database).await.unwrap(), Some(1));
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_audit_truncated_tail(pool: PgPool) {
+        let database: Database = pool.into();
+        for _ in 0..2 {
+            record(None, actions::LOGIN, None, json!({}), &database)
+                .await
+                .unwrap();
+        }
+        sqlx::raw_sql(
+            "ALTER TABLE audit_logs DISABLE TRIGGER audit_logs_no_update;
+            DELETE FROM audit_logs WHERE seq = 2;
+            ALTER TABLE audit_logs ENABLE TRIGGER audit_logs_no_update;",
+        )
+        .execute(&*database)
+        .await
+        .unwrap();
+        assert_eq!(AuditLog::verify(&*database).await.unwrap(), Some(2));
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_audit_search(pool: PgPool) {
+        let database: Database = pool.into();
+        // Group changes are recorded by the database, and sealed later
+        let user = User::create_active("", &*database)
+            .await
+            .unwrap()
+            .add_to_group(Group::ADMIN, &*database)
+            .await
+            .unwrap();
+        let pending = AuditLog::search(&AuditFilter::default(), &*database)
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].hash, None);
+        assert_eq!(AuditLog::seal(&database).await.unwrap(), 1);
+        assert_eq!(AuditLog::seal(&database).await.unwrap(), 0);
+        for _ in 0..3 {
+            record(Some(user.pk), actions::LOGIN, None, json!({}), &database)
+                .await
+                .unwrap();
+        }
+
+        let target = user.pk.to_string();
+        let groups = AuditLog::search(
+            &AuditFilter {
+                action: Some(actions::GROUP_ADDED.into()),
+                target: Some(target.clone()),
+                ..Default::default()
+            },
+            &*database,
+        )
+        .await
+        .unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].metadata, json!({"group_pk": 1}));
+
+        let logins = AuditLog::search(
+            &AuditFilter {
+                actor_pk: Some(user.pk),
+                limit: 2,
+                ..Default::default()
+            },
+            &*database,
+        )
+        .await
+        .unwrap();
+        assert_eq!(logins.len(), 2);
+        let older = AuditLog::search(
+            &AuditFilter {
+                actor_pk: Some(user.pk),
+                before_pk: Some(logins[1].pk),
+                ..Default::default()
+            },
+            &*database,
+        )
+        .await
+        .unwrap();
+        assert_eq!(older.len(), 1);
+        assert!(older[0].pk < logins[1].pk);
+        assert_eq!(AuditLog::verify(&*database).await.unwrap(), None);
+    }
+}
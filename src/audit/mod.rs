@@ -0,0 +1,6 @@
+pub mod actions;
+mod infrastructure;
+mod services;
+
+pub use infrastructure::{record, record_or_log, AuditFilter, AuditLog};
+pub use services::audit_logs_route;
@@ -0,0 +1,27 @@
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+
+use crate::{
+    auth::CurrentUser,
+    database::Database,
+    errors::AppError,
+    models::{Group, User},
+};
+
+use super::infrastructure::{AuditFilter, AuditLog};
+
+/// The audit log for admins, filtered by the query string. Mount it in a `WebsiteService` or
+/// an `APIService`, e.g. `GET /admin/audit-logs?actor_pk=1&action=login`.
+pub async fn audit_logs_route(
+    user: CurrentUser,
+    State(database): State<Database>,
+    Query(filter): Query<AuditFilter>,
+) -> Result<Json<Vec<AuditLog>>, AppError> {
+    let user = User::get_by_pk(user.pk, &*database).await?;
+    if !user.groups.contains(Group::ADMIN) {
+        return Err(AppError::RoleError);
+    }
+    AuditLog::search(&filter, &*database).await.map(Json)
+}
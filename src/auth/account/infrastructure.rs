@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+
+use crate::{
+    database::Database, errors::AppError, log_and_wrap_custom_internal, models::User,
+    sessions::Sessions, state::WebsiteState,
+};
+
+/// The queries used to export the data of a user. Each one gets the user pk as `$1` and its rows
+/// end up under the table name in the archive.
+const EXPORT_QUERIES: &[(&str, &str)] = &[
+    (
+        "users",
+        "SELECT pk, activated_at, deactivated_at, delete_after, created_at FROM users WHERE pk = $1",
+    ),
+    (
+        "emails",
+        "SELECT pk, email, is_primary, activated_at, created_at FROM emails WHERE user_pk = $1",
+    ),
+    (
+        "profiles",
+        "SELECT name, given_name, family_name, picture, created_at FROM profiles WHERE user_pk = $1",
+    ),
+    (
+        "google_profiles",
+        "SELECT google_profiles.id, emails.email, google_profiles.updated_at, google_profiles.created_at
+        FROM google_profiles
+        INNER JOIN emails ON emails.pk = google_profiles.email_pk
+        WHERE emails.user_pk = $1",
+    ),
+    (
+        "user_identities",
+        "SELECT provider, subject, email, created_at, last_used_at FROM user_identities WHERE user_pk = $1",
+    ),
+];
+
+/// GDPR-style archive with every row related to a user, grouped by table
+#[derive(Debug, Default)]
+pub struct UserDataExport(Map<String, Value>);
+
+impl UserDataExport {
+    pub async fn gather(
+        database: &Database,
+        sessions: &Sessions,
+        user_pk: i64,
+    ) -> Result<Self, AppError> {
+        let mut export = Self::default();
+        for (table, query) in EXPORT_QUERIES {
+            export.add_query(database, table, query, user_pk).await?;
+        }
+        export.add(
+            "sessions",
+            Value::Array(sessions.export_user_sessions(user_pk).await?),
+        );
+        Ok(export)
+    }
+
+    /// Add the rows of a query, it must take the user pk as `$1`
+    pub async fn add_query(
+        &mut self,
+        database: &Database,
+        table: &str,
+        query: &str,
+        user_pk: i64,
+    ) -> Result<&mut Self, AppError> {
+        let rows: Value = sqlx::query_scalar(&format!(
+            "SELECT COALESCE(json_agg(row_to_json(t)), '[]'::json) FROM ({}) t;",
+            query
+        ))
+        .bind(user_pk)
+        .fetch_one(&**database)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(self.add(table, rows))
+    }
+
+    pub fn add(&mut self, table: &str, rows: Value) -> &mut Self {
+        self.0.insert(table.to_owned(), rows);
+        self
+    }
+
+    pub fn tables(&self) -> &Map<String, Value> {
+        &self.0
+    }
+
+    pub fn into_value(self) -> Value {
+        Value::Object(self.0)
+    }
+}
+
+/// Revoke the provider tokens of the accounts whose grace period is over, then delete them for
+/// good. Returns the pks of the deleted users.
+pub async fn delete_expired_accounts(state: &WebsiteState) -> Result<Vec<i64>, AppError> {
+    let database = state.database();
+    let pks = User::scheduled_for_deletion(&**database).await?;
+    for pk in &pks {
+        state
+            .token_vault()
+            .revoke_user(state.http_client(), state.oauth_providers(), database, *pk)
+            .await?;
+    }
+    User::delete_scheduled(&pks, &**database).await
+}
+
+/// Deletes the expired accounts every hour, started with the website service
+pub async fn delete_scheduled_accounts(state: WebsiteState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        match delete_expired_accounts(&state).await {
+            Ok(pks) if !pks.is_empty() => tracing::info!(users=?pks, "accounts deleted"),
+            Ok(_) => {}
+            Err(e) => tracing::error!(error=?e, "failed to delete the scheduled accounts"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{
+        auth::{
+            oauth::mock::mock_provider, OAuthCredentials, OauthTokenResponse, OpenIdProvider,
+            StoredToken,
+        },
+        config::{ServiceConfig, WebsiteConfig},
+        models::{EmailAccount, Identity},
+        state::SharedState,
+    };
+
+    use super::*;
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_export_queries(pool: PgPool) {
+        let database: Database = pool.into();
+        let user = User::create_active_default(&*database).await.unwrap();
+        let user_pk = user.pk;
+        EmailAccount::create_primary_active(
+            user,
+            "test_export_queries@example.com".into(),
+            &*database,
+        )
+        .await
+        .unwrap();
+
+        let mut export = UserDataExport::default();
+        for (table, query) in EXPORT_QUERIES {
+            export
+                .add_query(&database, table, query, user_pk)
+                .await
+                .unwrap();
+        }
+
+        let tables = export.tables();
+        assert_eq!(tables["users"][0]["pk"], user_pk);
+        assert!(tables["users"][0].get("password").is_none());
+        assert_eq!(
+            tables["emails"][0]["email"],
+            "test_export_queries@example.com"
+        );
+        assert_eq!(tables["profiles"], Value::Array(vec![]));
+        assert_eq!(tables["google_profiles"], Value::Array(vec![]));
+        assert_eq!(tables["user_identities"], Value::Array(vec![]));
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_delete_expired_accounts(pool: PgPool) {
+        let database: Database = pool.into();
+        let (issuer, mock) = mock_provider().await;
+        let provider = OpenIdProvider::discover(
+            "mock",
+            &issuer,
+            OAuthCredentials::new("client-id", "client-secret"),
+        )
+        .await
+        .unwrap();
+        let state = WebsiteState::new(
+            WebsiteConfig::stub(),
+            SharedState::stub().with_database(database.clone()),
+        )
+        .with_oauth_provider(provider);
+
+        let user = User::create_active_default(&*database).await.unwrap();
+        let identity = Identity::create(user.pk, "mock", "mock-user-1", None, &*database)
+            .await
+            .unwrap();
+        state
+            .token_vault()
+            .store(identity.pk, &OauthTokenResponse::stub(), &*database)
+            .await
+            .unwrap();
+
+        user.schedule_deletion(30, &*database).await.unwrap();
+        assert!(delete_expired_accounts(&state).await.unwrap().is_empty());
+        assert!(mock.lock().unwrap().revoked.is_empty());
+
+        user.schedule_deletion(-1, &*database).await.unwrap();
+        assert_eq!(
+            delete_expired_accounts(&state).await.unwrap(),
+            vec![user.pk]
+        );
+        assert_eq!(mock.lock().unwrap().revoked, vec!["refresh_token"]);
+        assert!(StoredToken::find(identity.pk, &*database)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(User::get_by_pk(user.pk, &*database).await.is_err());
+    }
+}
@@ -0,0 +1,5 @@
+mod infrastructure;
+mod services;
+
+pub use infrastructure::{delete_expired_accounts, delete_scheduled_accounts, UserDataExport};
+pub use services::{AccountEmails, AccountIdentities, AccountLifecycle, LoginMethods};
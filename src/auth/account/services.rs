@@ -0,0 +1,309 @@
+use axum::{
+    extract::{Path, State},
+    http::{header::CONTENT_DISPOSITION, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use validator::Validate;
+
+use crate::{
+    audit::{self, actions},
+    auth::{EmailValidationManager, StoredToken},
+    errors::AppError,
+    log_and_wrap_custom_internal,
+    models::{EmailAccount, EmailAddress, Identity, User, UserSession},
+    sessions::Session,
+    state::WebsiteState,
+    website::{EmptyForm, SecureForm},
+};
+
+use super::infrastructure::UserDataExport;
+
+pub trait AccountLifecycle {
+    fn deactivate_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            session.forbid_impersonation().await?;
+            let user = session_user(&session).await?;
+            user.deactivate(false, &**state.database()).await?;
+            Self::logout(&state, session, &user).await?;
+            Ok(Redirect::to(state.config().login_path()))
+        }
+    }
+
+    /// Deactivate the account and delete it after the configured grace period. Logging in again
+    /// before that cancels the deletion, the provider tokens are revoked when the account goes.
+    fn delete_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            let config = state.config();
+            session.forbid_impersonation().await?;
+            let user = session_user(&session).await?;
+            user.schedule_deletion(config.account_deletion_grace_days, &**state.database())
+                .await?;
+            Self::logout(&state, session, &user).await?;
+            Ok(Redirect::to(config.login_path()))
+        }
+    }
+
+    fn export_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+    ) -> impl std::future::Future<Output = Result<Response, AppError>> + Send {
+        async move {
+            let user = session_user(&session).await?;
+            let mut export =
+                UserDataExport::gather(state.database(), state.sessions(), user.pk).await?;
+            for (table, rows) in Self::export_extra(&state, user.pk).await? {
+                export.add(&table, rows);
+            }
+            Ok((
+                [(
+                    CONTENT_DISPOSITION,
+                    "attachment; filename=\"account-export.json\"",
+                )],
+                Json(export.into_value()),
+            )
+                .into_response())
+        }
+    }
+
+    /// Hook for the app to add its own tables to the export, keyed by table name
+    fn export_extra(
+        _state: &WebsiteState,
+        _user_pk: i64,
+    ) -> impl std::future::Future<Output = Result<Map<String, Value>, AppError>> + Send {
+        async { Ok(Map::new()) }
+    }
+
+    fn admin_deactivate_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        Path(user_pk): Path<i64>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<StatusCode, AppError>> + Send {
+        async move {
+            Self::require_admin(&session).await?;
+            let user = User::get_by_pk(user_pk, &**state.database()).await?;
+            user.deactivate(true, &**state.database()).await?;
+            state.sessions().delete_user_sessions(user.pk).await?;
+            Ok(StatusCode::NO_CONTENT)
+        }
+    }
+
+    fn admin_reactivate_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        Path(user_pk): Path<i64>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<StatusCode, AppError>> + Send {
+        async move {
+            Self::require_admin(&session).await?;
+            User::get_by_pk(user_pk, &**state.database())
+                .await?
+                .reactivate(&**state.database())
+                .await?;
+            Ok(StatusCode::NO_CONTENT)
+        }
+    }
+
+    fn require_admin(
+        session: &Session,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + Send {
+        async {
+            if session.user().await.is_admin() {
+                Ok(())
+            } else {
+                Err(AppError::RoleError)
+            }
+        }
+    }
+
+    fn logout(
+        state: &WebsiteState,
+        session: Session,
+        user: &User,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + Send {
+        async move {
+            let sessions = state.sessions();
+            sessions
+                .reuse_current_as_new_one(
+                    &session,
+                    UserSession::new_anonymous(),
+                    &state.config().session_key,
+                )
+                .await?;
+            sessions.delete_user_sessions(user.pk).await?;
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Validate, Deserialize)]
+pub struct AddEmailForm {
+    #[validate(email)]
+    email: String,
+}
+
+/// Manage the emails of the logged user. New addresses are validated with the same
+/// `/email-validation/{slug}` link as the registration.
+pub trait AccountEmails {
+    fn list_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+    ) -> impl std::future::Future<Output = Result<Json<Vec<EmailAddress>>, AppError>> + Send {
+        async move {
+            let user = session_user(&session).await?;
+            EmailAccount::list_for_user(user.pk, &**state.database())
+                .await
+                .map(Json)
+        }
+    }
+
+    fn add_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        input: SecureForm<AddEmailForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            let config = state.config();
+            let database = state.database();
+            session.forbid_impersonation().await?;
+            let user = session_user(&session).await?;
+            let email =
+                EmailAccount::create_secondary(user, input.data().email, &**database).await?;
+            EmailValidationManager::new(email.pk)
+                .save(database)
+                .await?
+                .send(config, state.mailer(), &email.email)
+                .await?;
+            Ok(Redirect::to(&config.email_validation_redirect))
+        }
+    }
+
+    fn set_primary_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        Path(email_pk): Path<i64>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<StatusCode, AppError>> + Send {
+        async move {
+            session.forbid_impersonation().await?;
+            let user = session_user(&session).await?;
+            let mut tx = state.database().start_transaction().await?;
+            EmailAccount::set_primary(email_pk, user.pk, &mut tx).await?;
+            tx.commit()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            Ok(StatusCode::NO_CONTENT)
+        }
+    }
+
+    fn remove_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        Path(email_pk): Path<i64>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<StatusCode, AppError>> + Send {
+        async move {
+            session.forbid_impersonation().await?;
+            let user = session_user(&session).await?;
+            EmailAccount::delete_secondary(email_pk, user.pk, &**state.database()).await?;
+            Ok(StatusCode::NO_CONTENT)
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginMethods {
+    pub password: bool,
+    pub identities: Vec<Identity>,
+}
+
+/// The ways the logged user can log in. Providers are linked with `start_oauth_link`.
+pub trait AccountIdentities {
+    fn list_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+    ) -> impl std::future::Future<Output = Result<Json<LoginMethods>, AppError>> + Send {
+        async move {
+            let database = state.database();
+            let user = session_user(&session).await?;
+            Ok(Json(LoginMethods {
+                password: user.has_password(&**database).await?,
+                identities: Identity::list_for_user(user.pk, &**database).await?,
+            }))
+        }
+    }
+
+    fn unlink_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        Path(identity_pk): Path<i64>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<StatusCode, AppError>> + Send {
+        async move {
+            session.forbid_impersonation().await?;
+            let user = session_user(&session).await?;
+            let mut tx = state.database().start_transaction().await?;
+            // The tokens are deleted with the identity, they are revoked once it is gone
+            let stored = StoredToken::find(identity_pk, &mut *tx).await?;
+            Identity::delete(identity_pk, user.pk, &mut tx).await?;
+            tx.commit()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            if let Some(stored) = stored {
+                if let Err(e) = state
+                    .token_vault()
+                    .revoke_stored(state.http_client(), state.oauth_providers(), &stored)
+                    .await
+                {
+                    tracing::warn!("Cannot revoke the {} token: {:?}", stored.provider, e);
+                }
+            }
+            Ok(StatusCode::NO_CONTENT)
+        }
+    }
+
+    fn remove_password_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<StatusCode, AppError>> + Send {
+        async move {
+            session.forbid_impersonation().await?;
+            let user = session_user(&session).await?;
+            let mut tx = state.database().start_transaction().await?;
+            user.remove_password(&mut tx).await?;
+            tx.commit()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            audit::record(
+                Some(user.pk),
+                actions::PASSWORD_REMOVED,
+                Some(&user.pk.to_string()),
+                json!({}),
+                state.database(),
+            )
+            .await?;
+            Ok(StatusCode::NO_CONTENT)
+        }
+    }
+}
+
+async fn session_user(session: &Session) -> Result<User, AppError> {
+    session
+        .user()
+        .await
+        .as_ref()
+        .cloned()
+        .ok_or(AppError::Unauthorized)
+}
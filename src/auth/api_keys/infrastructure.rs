@@ -0,0 +1,231 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{NaiveDateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgExecutor};
+
+use crate::{auth::GrantedScopes, errors::AppError, log_and_wrap_custom_internal};
+
+const KEY_PREFIX: &str = "sk";
+
+/// A long-lived credential of a machine client, acting as its user within its scopes. The key
+/// is `sk_<prefix>_<secret>`: the prefix finds the row, only the hash of the secret is stored.
+#[derive(Debug, Clone, FromRow)]
+pub struct ApiKey {
+    pub pk: i64,
+    pub user_pk: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl ApiKey {
+    /// Returns the key with its secret, to show once to the user
+    pub async fn issue<'e, E: PgExecutor<'e>>(
+        user_pk: i64,
+        name: &str,
+        scopes: &[&str],
+        expires_at: Option<NaiveDateTime>,
+        executor: E,
+    ) -> Result<(Self, String), AppError> {
+        let mut prefix = [0u8; 6];
+        OsRng.fill_bytes(&mut prefix);
+        let prefix = hex::encode(prefix);
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let secret = hex::encode(secret);
+
+        let key: Self = sqlx::query_as(
+            "INSERT INTO api_keys (user_pk, name, prefix, key_hash, scopes, expires_at) VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING pk, user_pk, name, prefix, scopes, expires_at, last_used_at, revoked_at, created_at;",
+        )
+        .bind(user_pk)
+        .bind(name)
+        .bind(&prefix)
+        .bind(hash_secret(&secret))
+        .bind(scopes)
+        .bind(expires_at)
+        .fetch_one(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok((key, format!("{KEY_PREFIX}_{prefix}_{secret}")))
+    }
+
+    /// Find the active key and record its use
+    pub async fn authenticate<'e, E: PgExecutor<'e>>(
+        key: &str,
+        executor: E,
+    ) -> Result<Self, AppError> {
+        let Some((KEY_PREFIX, prefix, secret)) = key
+            .split_once('_')
+            .and_then(|(kind, rest)| rest.split_once('_').map(|(p, s)| (kind, p, s)))
+        else {
+            return Err(AppError::Unauthorized);
+        };
+
+        let now = Utc::now().naive_utc();
+        // The key acts as its user, so it stops working with an inactive account too
+        sqlx::query_as(
+            "UPDATE api_keys SET last_used_at = $1 FROM users
+            WHERE prefix = $2 AND key_hash = $3 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > $1)
+                AND users.pk = api_keys.user_pk AND users.activated_at IS NOT NULL
+                AND users.deactivated_at IS NULL AND users.delete_after IS NULL
+            RETURNING api_keys.pk, user_pk, name, prefix, scopes, expires_at, last_used_at, revoked_at, api_keys.created_at;",
+        )
+        .bind(now)
+        .bind(prefix)
+        .bind(hash_secret(secret))
+        .fetch_optional(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?
+        .ok_or(AppError::Unauthorized)
+    }
+
+    pub async fn list_for_user<'e, E: PgExecutor<'e>>(
+        user_pk: i64,
+        executor: E,
+    ) -> Result<Vec<Self>, AppError> {
+        sqlx::query_as(
+            "SELECT pk, user_pk, name, prefix, scopes, expires_at, last_used_at, revoked_at, created_at
+            FROM api_keys WHERE user_pk = $1 AND revoked_at IS NULL ORDER BY created_at DESC;",
+        )
+        .bind(user_pk)
+        .fetch_all(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Only the owner revokes their keys
+    pub async fn revoke<'e, E: PgExecutor<'e>>(
+        pk: i64,
+        user_pk: i64,
+        executor: E,
+    ) -> Result<(), AppError> {
+        let result = sqlx::query(
+            "UPDATE api_keys SET revoked_at = $1 WHERE pk = $2 AND user_pk = $3 AND revoked_at IS NULL;",
+        )
+        .bind(Utc::now().naive_utc())
+        .bind(pk)
+        .bind(user_pk)
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::DoesNotExist);
+        }
+        Ok(())
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        GrantedScopes::grants(&self.scopes, scope)
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use sqlx::PgPool;
+
+    use crate::{database::Database, models::User};
+
+    use super::*;
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_api_keys(pool: PgPool) {
+        let database: Database = pool.into();
+        let user = User::create_active("", &*database).await.unwrap();
+
+        let (issued, key) = ApiKey::issue(user.pk, "ci", &["read", "write"], None, &*database)
+            .await
+            .unwrap();
+        assert!(key.starts_with(&format!("sk_{}_", issued.prefix)));
+        assert!(issued.last_used_at.is_none());
+        let stored: String = sqlx::query_scalar("SELECT key_hash FROM api_keys;")
+            .fetch_one(&*database)
+            .await
+            .unwrap();
+        assert!(!key.contains(&stored));
+
+        let used = ApiKey::authenticate(&key, &*database).await.unwrap();
+        assert_eq!(used.user_pk, user.pk);
+        assert!(used.has_scope("write"));
+        assert!(!used.has_scope("admin"));
+        assert!(used.last_used_at.is_some());
+
+        let wrong_secret = format!("sk_{}_{}", issued.prefix, "0".repeat(64));
+        assert!(ApiKey::authenticate(&wrong_secret, &*database)
+            .await
+            .is_err());
+        assert!(ApiKey::authenticate("nope", &*database).await.is_err());
+        assert!(
+            ApiKey::authenticate(&key.replacen("sk", "pk", 1), &*database)
+                .await
+                .is_err()
+        );
+
+        let (_, expired) = ApiKey::issue(
+            user.pk,
+            "old",
+            &[],
+            Some(Utc::now().naive_utc() - Duration::minutes(1)),
+            &*database,
+        )
+        .await
+        .unwrap();
+        assert!(ApiKey::authenticate(&expired, &*database).await.is_err());
+
+        let other = User::create_active("", &*database).await.unwrap();
+        assert!(ApiKey::revoke(issued.pk, other.pk, &*database)
+            .await
+            .is_err());
+        ApiKey::revoke(issued.pk, user.pk, &*database)
+            .await
+            .unwrap();
+        assert!(ApiKey::authenticate(&key, &*database).await.is_err());
+        assert_eq!(
+            ApiKey::list_for_user(user.pk, &*database)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_api_keys_of_inactive_users(pool: PgPool) {
+        let database: Database = pool.into();
+        let inactive = User::create("", None, &*database).await.unwrap();
+        let (_, key) = ApiKey::issue(inactive.pk, "ci", &[], None, &*database)
+            .await
+            .unwrap();
+        assert!(ApiKey::authenticate(&key, &*database).await.is_err());
+
+        let scheduled = User::create_active("", &*database).await.unwrap();
+        let (_, key) = ApiKey::issue(scheduled.pk, "ci", &[], None, &*database)
+            .await
+            .unwrap();
+        scheduled.schedule_deletion(30, &*database).await.unwrap();
+        assert!(ApiKey::authenticate(&key, &*database).await.is_err());
+
+        // Deactivating revokes the keys, reactivating does not bring them back
+        let user = User::create_active("", &*database).await.unwrap();
+        let (_, key) = ApiKey::issue(user.pk, "ci", &[], None, &*database)
+            .await
+            .unwrap();
+        assert!(ApiKey::authenticate(&key, &*database).await.is_ok());
+        assert_eq!(user.deactivate(true, &*database).await.unwrap(), 1);
+        assert!(ApiKey::list_for_user(user.pk, &*database)
+            .await
+            .unwrap()
+            .is_empty());
+        user.reactivate(&*database).await.unwrap();
+        assert!(ApiKey::authenticate(&key, &*database).await.is_err());
+    }
+}
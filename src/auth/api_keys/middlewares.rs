@@ -0,0 +1,83 @@
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{
+    auth::{AuthMethod, CurrentUser, GrantedScopes},
+    errors::AppError,
+    state::APIState,
+};
+
+use super::infrastructure::ApiKey;
+
+/// The key that authenticated the request, for the handlers behind `api_key_middleware`
+#[derive(Debug, Clone)]
+pub struct ApiKeyRequest {
+    pub key_pk: i64,
+    pub user_pk: i64,
+    pub scopes: Vec<String>,
+}
+
+impl ApiKeyRequest {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        GrantedScopes::grants(&self.scopes, scope)
+    }
+}
+
+impl From<ApiKey> for ApiKeyRequest {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            key_pk: key.pk,
+            user_pk: key.user_pk,
+            scopes: key.scopes,
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for ApiKeyRequest
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Self>()
+            .cloned()
+            .ok_or(AppError::Unauthorized)
+    }
+}
+
+/// `X-API-Key`, or the bearer token
+fn api_key_from_headers(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("X-API-Key") {
+        return key.to_str().ok();
+    }
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Layer for the routes of machine clients, authenticated by an API key
+pub async fn api_key_middleware(
+    State(state): State<APIState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let key = api_key_from_headers(request.headers()).ok_or(AppError::Unauthorized)?;
+    let key = ApiKey::authenticate(key.trim(), &**state.database()).await?;
+    request
+        .extensions_mut()
+        .insert(GrantedScopes(key.scopes.clone()));
+    request
+        .extensions_mut()
+        .insert(CurrentUser::new(key.user_pk, AuthMethod::ApiKey));
+    request.extensions_mut().insert(ApiKeyRequest::from(key));
+    Ok(next.run(request).await)
+}
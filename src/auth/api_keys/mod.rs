@@ -0,0 +1,5 @@
+mod infrastructure;
+mod middlewares;
+
+pub use infrastructure::ApiKey;
+pub use middlewares::{api_key_middleware, ApiKeyRequest};
@@ -1,4 +1,6 @@
-use lettre::Message;
+use askama::Template;
+use chrono::{Duration, NaiveDateTime, Utc};
+use lettre::{message::MultiPart, Message};
 use sqlx::PgConnection;
 use uuid::Uuid;
 
@@ -8,6 +10,7 @@ use crate::{
     errors::AppError,
     log_and_wrap_custom_internal,
     mailing::Mailer,
+    state::WebsiteState,
 };
 
 #[derive(Debug)]
@@ -24,16 +27,24 @@ impl EmailValidationManager {
         }
     }
 
+    /// Consume the validation. Links older than `expiration` hours are rejected.
     pub async fn delete_and_get_email_pk(
         slug: String,
+        expiration: i64,
         tx: &mut PgConnection,
     ) -> Result<Self, AppError> {
-        let email_pk =
-            sqlx::query_scalar("DELETE FROM email_validations WHERE slug = $1 RETURNING email_pk;")
-                .bind(&slug)
-                .fetch_one(&mut *tx)
-                .await
-                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        let (email_pk, expired): (i64, bool) = sqlx::query_as(
+            "DELETE FROM email_validations WHERE slug = $1
+            RETURNING email_pk, created_at < CURRENT_TIMESTAMP - make_interval(hours => $2::int) AS expired;",
+        )
+        .bind(&slug)
+        .bind(expiration)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if expired {
+            return Err(AppError::custom_bad_request("The link has expired"));
+        }
 
         Ok(Self { email_pk, slug })
     }
@@ -44,15 +55,23 @@ impl EmailValidationManager {
         mailer: &Mailer,
         to: &str,
     ) -> Result<Self, AppError> {
-        let body = format!(
-            "Please click the following link to validate your email: {}",
-            config.build_url(&format!("/email-validation/{}", self.slug))
-        );
+        let url = config.build_url(&format!("/email-validation/{}", self.slug));
+        let html = EmailValidationHtml {
+            url: &url,
+            expiration: config.email_validation_expiration,
+        }
+        .render()?;
+        let text = EmailValidationText {
+            url: &url,
+            expiration: config.email_validation_expiration,
+        }
+        .render()?;
+
         let message = Message::builder()
-            .from(config.email_default_sender.parse().unwrap())
+            .from(config.email_validation_sender().parse().unwrap())
             .to(to.parse().unwrap())
-            .subject("Welcome to Smartlink")
-            .body(body)
+            .subject(&config.email_validation_subject)
+            .multipart(MultiPart::alternative_plain_html(text, html))
             .expect("failed to build email");
         mailer.send(&message).await?;
         Ok(self)
@@ -68,4 +87,431 @@ impl EmailValidationManager {
         //TODO: check that the slug is unique
         Ok(self)
     }
+
+    /// Whether no validation was sent to the email in the last `interval` minutes
+    pub async fn can_resend(
+        email_pk: i64,
+        interval: i64,
+        database: &Database,
+    ) -> Result<bool, AppError> {
+        sqlx::query_scalar(
+            "SELECT NOT EXISTS(SELECT 1 FROM email_validations WHERE email_pk = $1 AND created_at > CURRENT_TIMESTAMP - make_interval(mins => $2::int));",
+        )
+        .bind(email_pk)
+        .bind(interval)
+        .fetch_one(&**database)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Remove the previous validations of the email so only the latest link works
+    pub async fn delete_for_email(email_pk: i64, database: &Database) -> Result<u64, AppError> {
+        sqlx::query("DELETE FROM email_validations WHERE email_pk = $1;")
+            .bind(email_pk)
+            .execute(&**database)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+            .map(|r| r.rows_affected())
+    }
+
+    /// Remove the validations older than `expiration` hours
+    pub async fn delete_expired(database: &Database, expiration: i64) -> Result<u64, AppError> {
+        sqlx::query(
+            "DELETE FROM email_validations WHERE created_at < CURRENT_TIMESTAMP - make_interval(hours => $1::int);",
+        )
+        .bind(expiration)
+        .execute(&**database)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+        .map(|r| r.rows_affected())
+    }
+
+    /// Delete the expired validations every hour, forever
+    pub async fn run_cleanup(state: WebsiteState) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) =
+                Self::delete_expired(state.database(), state.config().email_validation_expiration)
+                    .await
+            {
+                tracing::error!("Cannot delete the expired email validations: {:?}", e);
+            }
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "emails/email_validation.html")]
+struct EmailValidationHtml<'a> {
+    url: &'a str,
+    expiration: i64,
+}
+
+#[derive(Template)]
+#[template(path = "emails/email_validation.txt")]
+struct EmailValidationText<'a> {
+    url: &'a str,
+    expiration: i64,
+}
+
+#[derive(Template)]
+#[template(path = "emails/magic_link.html")]
+struct MagicLinkHtml<'a> {
+    url: &'a str,
+    expiration: i64,
+}
+
+#[derive(Template)]
+#[template(path = "emails/magic_link.txt")]
+struct MagicLinkText<'a> {
+    url: &'a str,
+    expiration: i64,
+}
+
+#[derive(Debug)]
+pub struct MagicLinkManager {
+    pub email_pk: i64,
+    pub slug: String,
+    pub session_id: String,
+}
+
+impl MagicLinkManager {
+    pub fn new(email_pk: i64, session_id: String) -> Self {
+        Self {
+            email_pk,
+            slug: Uuid::new_v4().to_string(),
+            session_id,
+        }
+    }
+
+    /// Consume the link. It is deleted so it can be used only once, and it is only valid for the
+    /// browser session that requested it and before it expires.
+    pub async fn delete_and_get_email_pk(
+        slug: String,
+        session_id: &str,
+        tx: &mut PgConnection,
+    ) -> Result<Self, AppError> {
+        let (email_pk, link_session_id, expires_at): (i64, String, NaiveDateTime) = sqlx::query_as(
+            "DELETE FROM magic_links WHERE slug = $1 RETURNING email_pk, session_id, expires_at;",
+        )
+        .bind(&slug)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if link_session_id != session_id {
+            return Err(AppError::Unauthorized);
+        }
+        if expires_at < Utc::now().naive_utc() {
+            return Err(AppError::custom_bad_request("The link has expired"));
+        }
+
+        Ok(Self {
+            email_pk,
+            slug,
+            session_id: link_session_id,
+        })
+    }
+
+    pub async fn send(
+        self,
+        config: &WebsiteConfig,
+        mailer: &Mailer,
+        to: &str,
+    ) -> Result<Self, AppError> {
+        let url = config.build_url(&format!("/magic-link/{}", self.slug));
+        let html = MagicLinkHtml {
+            url: &url,
+            expiration: config.magic_link_expiration,
+        }
+        .render()?;
+        let text = MagicLinkText {
+            url: &url,
+            expiration: config.magic_link_expiration,
+        }
+        .render()?;
+
+        let message = Message::builder()
+            .from(config.email_default_sender.parse().unwrap())
+            .to(to
+                .parse()
+                .map_err(|_| AppError::custom_bad_request("Invalid email"))?)
+            .subject(&config.magic_link_subject)
+            .multipart(MultiPart::alternative_plain_html(text, html))
+            .expect("failed to build email");
+        mailer.send(&message).await?;
+        Ok(self)
+    }
+
+    pub async fn save(self, database: &Database, expiration: i64) -> Result<Self, AppError> {
+        let expires_at = Utc::now().naive_utc() + Duration::minutes(expiration);
+        sqlx::query(
+            "INSERT INTO magic_links (email_pk, slug, session_id, expires_at) VALUES ($1, $2, $3, $4);",
+        )
+        .bind(self.email_pk)
+        .bind(&self.slug)
+        .bind(&self.session_id)
+        .bind(expires_at)
+        .execute(&**database)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(self)
+    }
+
+    /// Remove the links past their expiration
+    pub async fn delete_expired(database: &Database) -> Result<u64, AppError> {
+        sqlx::query("DELETE FROM magic_links WHERE expires_at < $1;")
+            .bind(Utc::now().naive_utc())
+            .execute(&**database)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+            .map(|r| r.rows_affected())
+    }
+
+    /// Delete the expired links every `magic_link_expiration` minutes, forever
+    pub async fn run_cleanup(state: WebsiteState) {
+        let every =
+            std::time::Duration::from_secs(60 * state.config().magic_link_expiration.max(1) as u64);
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(e) = Self::delete_expired(state.database()).await {
+                tracing::error!("Cannot delete the expired magic links: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use sqlx::PgPool;
+
+    use crate::models::{EmailAccount, User};
+
+    use super::*;
+
+    async fn create_email(database: &Database, email: &str) -> EmailAccount {
+        let mut tx = database.start_transaction().await.unwrap();
+        let user = User::create("password", None, &mut *tx).await.unwrap();
+        let email = EmailAccount::create_primary(user, email.into(), None, &mut *tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        email
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_magic_link_single_use(pool: PgPool) {
+        let database: Database = pool.into();
+        let email = create_email(&database, "test_magic_link_single_use@example.com").await;
+
+        let link = MagicLinkManager::new(email.pk, "session".into())
+            .save(&database, 15)
+            .await
+            .unwrap();
+
+        let mut tx = database.start_transaction().await.unwrap();
+        let used = MagicLinkManager::delete_and_get_email_pk(link.slug.clone(), "session", &mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(used.email_pk, email.pk);
+
+        let mut tx = database.start_transaction().await.unwrap();
+        let reused = MagicLinkManager::delete_and_get_email_pk(link.slug, "session", &mut tx).await;
+        assert!(matches!(reused, Err(AppError::DoesNotExist)));
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_magic_link_other_session(pool: PgPool) {
+        let database: Database = pool.into();
+        let email = create_email(&database, "test_magic_link_other_session@example.com").await;
+
+        let link = MagicLinkManager::new(email.pk, "session".into())
+            .save(&database, 15)
+            .await
+            .unwrap();
+
+        let mut tx = database.start_transaction().await.unwrap();
+        let result =
+            MagicLinkManager::delete_and_get_email_pk(link.slug, "other-session", &mut tx).await;
+        assert!(matches!(result, Err(AppError::Unauthorized)));
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_magic_link_expired(pool: PgPool) {
+        let database: Database = pool.into();
+        let email = create_email(&database, "test_magic_link_expired@example.com").await;
+
+        let link = MagicLinkManager::new(email.pk, "session".into())
+            .save(&database, -1)
+            .await
+            .unwrap();
+
+        let mut tx = database.start_transaction().await.unwrap();
+        let result = MagicLinkManager::delete_and_get_email_pk(link.slug, "session", &mut tx).await;
+        assert!(matches!(
+            result,
+            Err(AppError::Custom(StatusCode::BAD_REQUEST, ref message)) if message == "The link has expired"
+        ));
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_magic_link_delete_expired(pool: PgPool) {
+        let database: Database = pool.into();
+        let email = create_email(&database, "test_magic_link_delete_expired@example.com").await;
+
+        let expired = MagicLinkManager::new(email.pk, "session".into())
+            .save(&database, -1)
+            .await
+            .unwrap();
+        let valid = MagicLinkManager::new(email.pk, "session".into())
+            .save(&database, 10)
+            .await
+            .unwrap();
+        assert_eq!(
+            MagicLinkManager::delete_expired(&database).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            MagicLinkManager::delete_expired(&database).await.unwrap(),
+            0
+        );
+
+        let mut tx = database.start_transaction().await.unwrap();
+        assert!(matches!(
+            MagicLinkManager::delete_and_get_email_pk(expired.slug, "session", &mut tx).await,
+            Err(AppError::DoesNotExist)
+        ));
+        drop(tx);
+        let mut tx = database.start_transaction().await.unwrap();
+        assert!(
+            MagicLinkManager::delete_and_get_email_pk(valid.slug, "session", &mut tx)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_set_to_active_activates_once(pool: PgPool) {
+        let database: Database = pool.into();
+        let email = create_email(&database, "test_set_to_active_activates_once@example.com").await;
+
+        assert_eq!(email.user.set_to_active(&*database).await.unwrap(), 1);
+        assert_eq!(email.user.set_to_active(&*database).await.unwrap(), 0);
+        assert!(email.user.is_authenticated(&database).await.unwrap());
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_email_validation_expired(pool: PgPool) {
+        let database: Database = pool.into();
+        let email = create_email(&database, "test_email_validation_expired@example.com").await;
+
+        let validation = EmailValidationManager::new(email.pk)
+            .save(&database)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE email_validations SET created_at = created_at - INTERVAL '3 hours';")
+            .execute(&*database)
+            .await
+            .unwrap();
+
+        let mut tx = database.start_transaction().await.unwrap();
+        let result =
+            EmailValidationManager::delete_and_get_email_pk(validation.slug.clone(), 2, &mut tx)
+                .await;
+        assert!(result.is_err());
+        drop(tx);
+
+        let mut tx = database.start_transaction().await.unwrap();
+        let result =
+            EmailValidationManager::delete_and_get_email_pk(validation.slug, 4, &mut tx).await;
+        assert_eq!(result.unwrap().email_pk, email.pk);
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_email_validation_resend_and_cleanup(pool: PgPool) {
+        let database: Database = pool.into();
+        let email = create_email(&database, "test_email_validation_resend@example.com").await;
+
+        assert!(EmailValidationManager::can_resend(email.pk, 5, &database)
+            .await
+            .unwrap());
+        EmailValidationManager::new(email.pk)
+            .save(&database)
+            .await
+            .unwrap();
+        assert!(!EmailValidationManager::can_resend(email.pk, 5, &database)
+            .await
+            .unwrap());
+
+        assert_eq!(
+            EmailValidationManager::delete_expired(&database, 1)
+                .await
+                .unwrap(),
+            0
+        );
+        sqlx::query("UPDATE email_validations SET created_at = created_at - INTERVAL '2 hours';")
+            .execute(&*database)
+            .await
+            .unwrap();
+        assert!(EmailValidationManager::can_resend(email.pk, 5, &database)
+            .await
+            .unwrap());
+        assert_eq!(
+            EmailValidationManager::delete_expired(&database, 1)
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_email_validation_templates() {
+        let html = EmailValidationHtml {
+            url: "https://test.com/email-validation/slug",
+            expiration: 48,
+        }
+        .render()
+        .unwrap();
+        assert!(html.contains("href=\"https://test.com/email-validation/slug\""));
+
+        let text = EmailValidationText {
+            url: "https://test.com/email-validation/slug",
+            expiration: 48,
+        }
+        .render()
+        .unwrap();
+        assert!(text.contains("https://test.com/email-validation/slug"));
+        assert!(text.contains("48 hours"));
+    }
+
+    #[tokio::test]
+    async fn test_magic_link_email() {
+        let html = MagicLinkHtml {
+            url: "https://test.com/magic-link/slug",
+            expiration: 15,
+        }
+        .render()
+        .unwrap();
+        assert!(html.contains("href=\"https://test.com/magic-link/slug\""));
+
+        let text = MagicLinkText {
+            url: "https://test.com/magic-link/slug",
+            expiration: 15,
+        }
+        .render()
+        .unwrap();
+        assert!(text.contains("https://test.com/magic-link/slug"));
+        assert!(text.contains("15 minutes"));
+
+        let result = MagicLinkManager::new(1, "session".into())
+            .send(&WebsiteConfig::stub(), &Mailer::default(), "not an email")
+            .await;
+        assert!(matches!(
+            result,
+            Err(AppError::Custom(StatusCode::BAD_REQUEST, _))
+        ));
+    }
 }
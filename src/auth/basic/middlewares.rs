@@ -5,12 +5,30 @@ use axum::{
     Extension,
 };
 use axum_extra::{headers::Cookie, TypedHeader};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use std::net::SocketAddr;
 
 use super::services::set_session_cookies;
-use crate::{errors::AppError, models::UserSession, sessions::Session, state::WebsiteState};
+use crate::{
+    config::WebsiteConfig,
+    errors::AppError,
+    models::{User, UserModel, UserSession},
+    sessions::Session,
+    state::WebsiteState,
+};
 
 pub async fn login_required_middleware(
+    state: State<WebsiteState>,
+    session: Extension<Session>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    login_required_model_middleware::<User>(state, session, request, next).await
+}
+
+/// Same as [`login_required_middleware`] but also loading the app's [`UserModel`], available to
+/// the handlers as `Extension<U>`.
+pub async fn login_required_model_middleware<U: UserModel>(
     state: State<WebsiteState>,
     session: Extension<Session>,
     mut request: Request,
@@ -22,11 +40,27 @@ pub async fn login_required_middleware(
     let config = state.config();
 
     if session.is_authenticated(database).await? {
+        if let Some(user) = session.user().await.load::<U>(database).await? {
+            request.extensions_mut().insert(user);
+        }
         request.extensions_mut().insert(session);
         return Ok(next.run(request).await);
     }
-    let next = format!("{}?next={}", config.login_path(), request.uri());
-    Ok(Redirect::to(&next).into_response())
+    Ok(login_redirect(config, &request.uri().to_string()))
+}
+
+/// To the login page, coming back to `next` afterwards when it is safe
+pub(crate) fn login_redirect(config: &WebsiteConfig, next: &str) -> Response {
+    let login = if config.is_safe_redirect(next) {
+        format!(
+            "{}?next={}",
+            config.login_path(),
+            utf8_percent_encode(next, NON_ALPHANUMERIC)
+        )
+    } else {
+        config.login_path().to_owned()
+    };
+    Redirect::to(&login).into_response()
 }
 
 pub async fn sessions_middleware(
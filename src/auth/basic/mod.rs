@@ -1,8 +1,15 @@
 mod infrastructure;
 mod middlewares;
+mod passwords;
 
 mod services;
 
-pub use infrastructure::EmailValidationManager;
-pub use middlewares::{login_required_middleware, sessions_middleware};
-pub use services::{hash_password, verify_password, EmailValidation, Ingress};
+pub use infrastructure::{EmailValidationManager, MagicLinkManager};
+pub(crate) use middlewares::login_redirect;
+pub use middlewares::{
+    login_required_middleware, login_required_model_middleware, sessions_middleware,
+};
+pub use passwords::{
+    hash_password, set_new_password, verify_password, PasswordHasher, PasswordPolicy,
+};
+pub use services::{EmailValidation, Ingress, MagicLink};
@@ -0,0 +1,278 @@
+use std::{collections::HashSet, sync::LazyLock};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Algorithm, Argon2, KeyId, Params, ParamsBuilder, PasswordHash, PasswordHasher as _,
+    PasswordVerifier, Version,
+};
+use serde_json::json;
+
+use crate::{
+    audit::{self, actions},
+    errors::AppError,
+    log_and_wrap_custom_internal,
+    models::User,
+    sessions::Session,
+    state::WebsiteState,
+};
+
+static COMMON_PASSWORDS: LazyLock<HashSet<&'static str>> =
+    LazyLock::new(|| include_str!("common_passwords.txt").lines().collect());
+
+/// Argon2id hasher built from the config. When a pepper is set the hashes are keyed with it and
+/// carry the operator's pepper id as `keyid`, so hashes made before the pepper (or with another
+/// one) are detected and rehashed on the next login. Rotating the pepper needs a new id.
+#[derive(Debug, Clone, Default)]
+pub struct PasswordHasher {
+    params: Params,
+    pepper: Option<Vec<u8>>,
+}
+
+impl PasswordHasher {
+    /// A cost of 0 keeps the argon2 default, an empty pepper disables it. A pepper needs an id
+    /// of 1 to 8 bytes, stored in the clear in every hash.
+    pub fn new(
+        memory_cost: u32,
+        time_cost: u32,
+        parallelism: u32,
+        pepper: &str,
+        pepper_id: &str,
+    ) -> Result<Self, AppError> {
+        let mut builder = ParamsBuilder::new();
+        if memory_cost > 0 {
+            builder.m_cost(memory_cost);
+        }
+        if time_cost > 0 {
+            builder.t_cost(time_cost);
+        }
+        if parallelism > 0 {
+            builder.p_cost(parallelism);
+        }
+
+        let pepper = (!pepper.is_empty()).then(|| pepper.as_bytes().to_vec());
+        if pepper.is_some() {
+            if pepper_id.is_empty() {
+                return Err(AppError::custom_internal("A password pepper needs an id"));
+            }
+            builder.keyid(
+                KeyId::new(pepper_id.as_bytes()).map_err(|e| log_and_wrap_custom_internal!(e))?,
+            );
+        }
+
+        let params = builder
+            .build()
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(Self { params, pepper })
+    }
+
+    fn argon2(&self) -> Result<Argon2<'_>, AppError> {
+        match &self.pepper {
+            Some(pepper) => Argon2::new_with_secret(
+                pepper,
+                Algorithm::Argon2id,
+                Version::V0x13,
+                self.params.clone(),
+            )
+            .map_err(|e| log_and_wrap_custom_internal!(e)),
+            None => Ok(Argon2::new(
+                Algorithm::Argon2id,
+                Version::V0x13,
+                self.params.clone(),
+            )),
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, AppError> {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(self
+            .argon2()?
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(AppError::ErrorHashingPassword)?
+            .to_string())
+    }
+
+    pub fn verify(&self, raw_password: &str, db_password: &str) -> Result<(), AppError> {
+        // A stored value that is not a hash matches no password
+        let parsed_hash = PasswordHash::new(db_password).map_err(AppError::WrongPassword)?;
+        let params = Params::try_from(&parsed_hash).map_err(AppError::ErrorHashingPassword)?;
+
+        let argon2 = if params.keyid().is_empty() {
+            Argon2::default()
+        } else if params.keyid() == self.params.keyid() {
+            self.argon2()?
+        } else {
+            return Err(AppError::custom_internal(
+                "The password was hashed with an unknown pepper",
+            ));
+        };
+
+        argon2
+            .verify_password(raw_password.as_bytes(), &parsed_hash)
+            .map_err(AppError::WrongPassword)
+    }
+
+    /// Whether the stored hash was made with other algorithm, costs or pepper than the current
+    pub fn needs_rehash(&self, db_password: &str) -> bool {
+        let Ok(parsed_hash) = PasswordHash::new(db_password) else {
+            return true;
+        };
+        if parsed_hash.algorithm != Algorithm::Argon2id.ident() {
+            return true;
+        }
+        match Params::try_from(&parsed_hash) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+                    || params.keyid() != self.params.keyid()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn new(min_length: usize) -> Self {
+        Self {
+            min_length,
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self, password: &str) -> Result<(), AppError> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(AppError::WeakPassword(format!(
+                "The password must have at least {} characters",
+                self.min_length
+            )));
+        }
+        if length > self.max_length {
+            return Err(AppError::WeakPassword(format!(
+                "The password must have at most {} characters",
+                self.max_length
+            )));
+        }
+        if COMMON_PASSWORDS.contains(password.to_lowercase().as_str()) {
+            return Err(AppError::WeakPassword(
+                "The password is too common".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Hash with the argon2 defaults and no pepper, prefer the configured [`PasswordHasher`]
+pub fn hash_password(password: &str) -> Result<String, AppError> {
+    PasswordHasher::default().hash(password)
+}
+
+pub fn verify_password(raw_password: &str, db_password: &str) -> Result<(), AppError> {
+    PasswordHasher::default().verify(raw_password, db_password)
+}
+
+/// Check the policy and store the new password of the user, for the password reset and change
+/// flows. An admin impersonating the user can't change it.
+pub async fn set_new_password(
+    state: &WebsiteState,
+    session: &Session,
+    user: &User,
+    password: &str,
+) -> Result<(), AppError> {
+    session.forbid_impersonation().await?;
+    state.config().password_policy().validate(password)?;
+    let password = state.password_hasher().hash(password)?;
+    user.set_password(&password, &**state.database()).await?;
+    audit::record(
+        Some(user.pk),
+        actions::PASSWORD_CHANGED,
+        Some(&user.pk.to_string()),
+        json!({}),
+        state.database(),
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cheap_hasher(pepper: &str, pepper_id: &str) -> PasswordHasher {
+        PasswordHasher::new(1024, 1, 1, pepper, pepper_id).unwrap()
+    }
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hasher = cheap_hasher("", "");
+        let hash = hasher.hash("correct horse battery staple").unwrap();
+        assert!(hasher.verify("correct horse battery staple", &hash).is_ok());
+        assert!(hasher.verify("wrong", &hash).is_err());
+        assert!(!hasher.needs_rehash(&hash));
+        assert!(matches!(
+            hasher.verify("", ""),
+            Err(AppError::WrongPassword(_))
+        ));
+    }
+
+    #[test]
+    fn test_pepper() {
+        let hasher = cheap_hasher("pepper", "v1");
+        let hash = hasher.hash("correct horse battery staple").unwrap();
+        assert!(hasher.verify("correct horse battery staple", &hash).is_ok());
+        assert!(!hasher.needs_rehash(&hash));
+        let keyid = Params::try_from(&PasswordHash::new(&hash).unwrap())
+            .unwrap()
+            .keyid()
+            .to_vec();
+        assert_eq!(keyid, b"v1");
+
+        let other = cheap_hasher("other pepper", "v2");
+        assert!(other.verify("correct horse battery staple", &hash).is_err());
+        assert!(other.needs_rehash(&hash));
+
+        assert!(PasswordHasher::new(1024, 1, 1, "pepper", "").is_err());
+        assert!(PasswordHasher::new(1024, 1, 1, "pepper", "too long id").is_err());
+    }
+
+    #[test]
+    fn test_legacy_hash_needs_rehash() {
+        let legacy = crate::auth::hash_password("correct horse battery staple").unwrap();
+
+        let hasher = cheap_hasher("pepper", "v1");
+        assert!(hasher
+            .verify("correct horse battery staple", &legacy)
+            .is_ok());
+        assert!(hasher.needs_rehash(&legacy));
+
+        let stronger = PasswordHasher::new(1024, 2, 1, "", "").unwrap();
+        let hash = cheap_hasher("", "")
+            .hash("correct horse battery staple")
+            .unwrap();
+        assert!(stronger.needs_rehash(&hash));
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = PasswordPolicy::new(10);
+        assert!(policy.validate("short").is_err());
+        assert!(policy.validate("Password123").is_err());
+        assert!(policy.validate(&"a".repeat(129)).is_err());
+        assert!(policy.validate("correct horse battery staple").is_ok());
+    }
+}
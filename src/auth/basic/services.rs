@@ -1,31 +1,31 @@
-use argon2::{
-    password_hash::{rand_core::OsRng, SaltString},
-    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
-};
 use axum::{
     extract::{Path, Query, State},
-    http::{header::SET_COOKIE, HeaderValue},
+    http::{header::SET_COOKIE, HeaderValue, StatusCode},
     response::Redirect,
     Extension,
 };
 use cookie::{time::Duration, SameSite};
 use hyper::HeaderMap;
 use serde::Deserialize;
+use serde_json::json;
 use sqlx::PgConnection;
 use validator::Validate;
 
 use crate::{
+    audit::{self, actions},
     config::{ServiceConfig, WebsiteConfig},
-    database::Database,
     errors::AppError,
     log_and_wrap_custom_internal,
-    models::{EmailAccount, Group, User, UserWithPassword},
+    models::{EmailAccount, Group, User, UserModel, UserWithPassword},
     sessions::{Session, Sessions},
     state::WebsiteState,
     website::SecureForm,
 };
 
-use super::infrastructure::EmailValidationManager;
+use super::{
+    infrastructure::{EmailValidationManager, MagicLinkManager},
+    passwords::set_new_password,
+};
 
 #[derive(Debug, Deserialize)]
 pub enum IngressProcess {
@@ -41,12 +41,20 @@ pub struct IngressForm {
     process: IngressProcess,
 }
 
+#[derive(Debug, Validate, Deserialize)]
+pub struct ChangePasswordForm {
+    current_password: String,
+    new_password: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct IngressParams {
     next: Option<String>,
 }
 
 pub trait Ingress {
+    type User: UserModel;
+
     fn route(
         state: State<WebsiteState>,
         Extension(session): Extension<Session>,
@@ -72,9 +80,31 @@ pub trait Ingress {
         async move {
             let config = state.config();
 
-            let user = Self::validate_login(state, &input).await?;
-
-            Self::handle_login_session(state.sessions(), session, config, user).await?;
+            let user = match Self::validate_login(state, &input).await {
+                Ok(user) => user,
+                Err(e) => {
+                    audit::record_or_log(
+                        None,
+                        actions::LOGIN_FAILED,
+                        Some(&input.email),
+                        json!({"method": "password"}),
+                        state.database(),
+                    )
+                    .await;
+                    return Err(e);
+                }
+            };
+            audit::record(
+                Some(user.user.pk),
+                actions::LOGIN,
+                None,
+                json!({"method": "password"}),
+                state.database(),
+            )
+            .await?;
+            let user = Self::User::load(user.user, state.database()).await?;
+
+            Self::handle_login_session(state.sessions(), session, config, &user).await?;
 
             Ok(Self::get_login_redirect(config, params))
         }
@@ -85,10 +115,21 @@ pub trait Ingress {
         input: &'a IngressForm,
     ) -> impl std::future::Future<Output = Result<UserWithPassword, AppError>> + Send {
         async {
-            User::find_by_email_with_password(&input.email, state.database())
+            let user = User::find_by_email_with_password(&input.email, state.database())
                 .await?
-                .ok_or(AppError::DoesNotExist)
-                .and_then(|u| verify_password(&input.password, &u.password).map(|_| u))
+                .ok_or(AppError::DoesNotExist)?;
+
+            let hasher = state.password_hasher();
+            hasher.verify(&input.password, &user.password)?;
+            if hasher.needs_rehash(&user.password) {
+                let password = hasher.hash(&input.password)?;
+                user.user
+                    .set_password(&password, &**state.database())
+                    .await?;
+            }
+            // Logging in cancels a self-service deactivation or a pending deletion
+            user.user.reactivate_self(&**state.database()).await?;
+            Ok(user)
         }
     }
 
@@ -96,17 +137,17 @@ pub trait Ingress {
         sessions: &'a Sessions,
         session: Session,
         config: &'a WebsiteConfig,
-        user: UserWithPassword,
+        user: &'a Self::User,
     ) -> impl std::future::Future<Output = Result<(), AppError>> + Send {
         async move {
             sessions
-                .reuse_current_as_new_one(&session, user.user.for_session(), &config.session_key)
+                .reuse_current_as_new_one(&session, user.to_session(), &config.session_key)
                 .await
         }
     }
 
     fn get_login_redirect<'a>(config: &'a WebsiteConfig, params: &'a IngressParams) -> &'a str {
-        params.next.as_ref().unwrap_or(&config.login_redirect_to)
+        config.safe_redirect(params.next.as_deref())
     }
 
     fn register<'a>(
@@ -117,29 +158,31 @@ pub trait Ingress {
     ) -> impl std::future::Future<Output = Result<&'a str, AppError>> + Send {
         async {
             let config = state.config();
-            let database = state.database();
 
-            let user = Self::create_user(database, input, config).await?;
+            config.password_policy().validate(&input.password)?;
+
+            let user = Self::create_user(state, input).await?;
 
             if config.email_validation {
                 Self::handle_email_validation(state, &user).await?;
             } else {
-                Self::handle_register_session(state.sessions(), session, config, user).await?;
+                let user = Self::User::load(user.user, state.database()).await?;
+                Self::handle_register_session(state.sessions(), session, config, &user).await?;
             };
 
             Ok(Self::get_register_redirect(config, params))
         }
     }
 
-    fn create_user<'a>(
-        database: &'a Database,
+    fn create_user(
+        state: &WebsiteState,
         input: IngressForm,
-        config: &'a WebsiteConfig,
     ) -> impl std::future::Future<Output = Result<EmailAccount, AppError>> + Send {
         async move {
-            let mut tx = database.start_transaction().await?;
+            let config = state.config();
+            let mut tx = state.database().start_transaction().await?;
 
-            let password = hash_password(&input.password)?;
+            let password = state.password_hasher().hash(&input.password)?;
 
             let activated_at = if config.email_validation {
                 None
@@ -149,7 +192,7 @@ pub trait Ingress {
 
             let user = User::create(&password, activated_at, &mut *tx)
                 .await?
-                .add_to_group(Group::User, &mut *tx)
+                .add_to_group(Group::USER, &mut *tx)
                 .await?;
 
             let email_account =
@@ -181,15 +224,56 @@ pub trait Ingress {
         sessions: &'a Sessions,
         session: Session,
         config: &'a WebsiteConfig,
-        user: EmailAccount,
+        user: &'a Self::User,
     ) -> impl std::future::Future<Output = Result<(), AppError>> + Send {
         async move {
             sessions
-                .reuse_current_as_new_one(&session, user.user.for_session(), &config.session_key)
+                .reuse_current_as_new_one(&session, user.to_session(), &config.session_key)
+                .await
+        }
+    }
+
+    /// The logged user changes their password, after typing the current one
+    fn change_password_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        input: SecureForm<ChangePasswordForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            Self::change_password(&state, &session, input.data())
                 .await
+                .map(Redirect::to)
         }
     }
 
+    fn change_password<'a>(
+        state: &'a WebsiteState,
+        session: &'a Session,
+        input: ChangePasswordForm,
+    ) -> impl std::future::Future<Output = Result<&'a str, AppError>> + Send {
+        async move {
+            let user = session
+                .user()
+                .await
+                .as_ref()
+                .cloned()
+                .ok_or(AppError::Unauthorized)?;
+            let current = user
+                .password(&**state.database())
+                .await?
+                .ok_or_else(|| AppError::custom_bad_request("The account has no password"))?;
+            state
+                .password_hasher()
+                .verify(&input.current_password, &current)?;
+            set_new_password(state, session, &user, &input.new_password).await?;
+            Ok(Self::get_change_password_redirect(state.config()))
+        }
+    }
+
+    fn get_change_password_redirect(config: &WebsiteConfig) -> &str {
+        &config.login_redirect_to
+    }
+
     fn get_register_redirect<'a>(config: &'a WebsiteConfig, _params: &'a IngressParams) -> &'a str {
         if config.email_validation {
             &config.email_validation_redirect
@@ -199,7 +283,15 @@ pub trait Ingress {
     }
 }
 
+#[derive(Debug, Validate, Deserialize)]
+pub struct EmailValidationResendForm {
+    #[validate(email)]
+    email: String,
+}
+
 pub trait EmailValidation {
+    type User: UserModel;
+
     fn route(
         state: State<WebsiteState>,
         Extension(session): Extension<Session>,
@@ -221,17 +313,71 @@ pub trait EmailValidation {
             let database = state.database();
             let config = state.config();
             let mut tx = database.start_transaction().await?;
-            let validation = EmailValidationManager::delete_and_get_email_pk(slug, &mut tx).await?;
+            let validation = EmailValidationManager::delete_and_get_email_pk(
+                slug,
+                config.email_validation_expiration,
+                &mut tx,
+            )
+            .await?;
             let user = Self::activate_user(validation, &mut tx).await?;
             tx.commit()
                 .await
                 .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            let user = Self::User::load(user.user, database).await?;
 
-            Self::handle_session(state.sessions(), session, config, user).await?;
+            Self::handle_session(state.sessions(), session, config, &user).await?;
             Ok(&config.login_redirect_to)
         }
     }
 
+    fn resend_route(
+        state: State<WebsiteState>,
+        input: SecureForm<EmailValidationResendForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            Self::resend(&state, input.data())
+                .await
+                .map(|r| Redirect::to(r.as_str()))
+        }
+    }
+
+    fn resend(
+        state: &WebsiteState,
+        input: EmailValidationResendForm,
+    ) -> impl std::future::Future<Output = Result<&String, AppError>> + Send {
+        async move {
+            let config = state.config();
+            let database = state.database();
+
+            let Some(email) = EmailAccount::get_pending_by_email(&input.email, &**database).await?
+            else {
+                // Answer the same way for unknown or already validated emails
+                return Ok(&config.email_validation_redirect);
+            };
+
+            if !EmailValidationManager::can_resend(
+                email.pk,
+                config.email_validation_resend_interval,
+                database,
+            )
+            .await?
+            {
+                return Err(AppError::Custom(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "Please wait before asking for a new email".into(),
+                ));
+            }
+
+            EmailValidationManager::delete_for_email(email.pk, database).await?;
+            EmailValidationManager::new(email.pk)
+                .save(database)
+                .await?
+                .send(config, state.mailer(), &email.email)
+                .await?;
+            Ok(&config.email_validation_redirect)
+        }
+    }
+
     fn activate_user(
         validation: EmailValidationManager,
         tx: &mut PgConnection,
@@ -246,33 +392,133 @@ pub trait EmailValidation {
         }
     }
 
-    fn handle_session(
-        sessions: &Sessions,
+    fn handle_session<'a>(
+        sessions: &'a Sessions,
         session: Session,
-        config: &WebsiteConfig,
-        user: EmailAccount,
+        config: &'a WebsiteConfig,
+        user: &'a Self::User,
     ) -> impl std::future::Future<Output = Result<(), AppError>> + Send {
         async move {
             sessions
-                .reuse_current_as_new_one(&session, user.user.for_session(), &config.session_key)
+                .reuse_current_as_new_one(&session, user.to_session(), &config.session_key)
                 .await
         }
     }
 }
 
-pub fn hash_password(password: &str) -> Result<String, AppError> {
-    let salt = SaltString::generate(&mut OsRng);
-    Ok(Argon2::default()
-        .hash_password(password.as_bytes(), &salt)
-        .map_err(AppError::ErrorHashingPassword)?
-        .to_string())
+#[derive(Debug, Validate, Deserialize)]
+pub struct MagicLinkForm {
+    #[validate(email)]
+    email: String,
 }
 
-pub fn verify_password(raw_password: &str, db_password: &str) -> Result<(), AppError> {
-    let parsed_hash = PasswordHash::new(db_password).map_err(AppError::ErrorHashingPassword)?;
-    Argon2::default()
-        .verify_password(raw_password.as_bytes(), &parsed_hash)
-        .map_err(AppError::WrongPassword)
+pub trait MagicLink {
+    type User: UserModel;
+
+    fn request_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        input: SecureForm<MagicLinkForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            Self::request_link(&state, session, input.data())
+                .await
+                .map(|r| Redirect::to(r.as_str()))
+        }
+    }
+
+    fn request_link(
+        state: &WebsiteState,
+        session: Session,
+        input: MagicLinkForm,
+    ) -> impl std::future::Future<Output = Result<&String, AppError>> + Send {
+        async move {
+            let config = state.config();
+            let database = state.database();
+            // Answer the same way whether the email exists or not so accounts can't be enumerated
+            if let Some(email) = EmailAccount::get_by_email(&input.email, &**database).await? {
+                MagicLinkManager::new(email.pk, session.id().await)
+                    .save(database, config.magic_link_expiration)
+                    .await?
+                    .send(config, state.mailer(), &email.email)
+                    .await?;
+            }
+            Ok(&config.magic_link_redirect)
+        }
+    }
+
+    fn route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        Path(slug): Path<String>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            Self::login(&state, session, slug)
+                .await
+                .map(|r| Redirect::to(r.as_str()))
+        }
+    }
+
+    fn login(
+        state: &WebsiteState,
+        session: Session,
+        slug: String,
+    ) -> impl std::future::Future<Output = Result<&String, AppError>> + Send {
+        async move {
+            let database = state.database();
+            let config = state.config();
+            let mut tx = database.start_transaction().await?;
+            let link =
+                MagicLinkManager::delete_and_get_email_pk(slug, &session.id().await, &mut tx)
+                    .await?;
+            let user = Self::activate_user(link, &mut tx).await?;
+            // Logging in cancels a self-service deactivation or a pending deletion
+            user.user.reactivate_self(&mut *tx).await?;
+            tx.commit()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            audit::record(
+                Some(user.user.pk),
+                actions::LOGIN,
+                None,
+                json!({"method": "magic_link"}),
+                database,
+            )
+            .await?;
+            let user = Self::User::load(user.user, database).await?;
+
+            Self::handle_session(state.sessions(), session, config, &user).await?;
+            Ok(&config.login_redirect_to)
+        }
+    }
+
+    /// The user proved they own the email, so the first use activates the account
+    fn activate_user(
+        link: MagicLinkManager,
+        tx: &mut PgConnection,
+    ) -> impl std::future::Future<Output = Result<EmailAccount, AppError>> + Send {
+        async move {
+            let email = EmailAccount::get_by_pk(link.email_pk, &mut *tx)
+                .await?
+                .set_to_active(&mut *tx)
+                .await?;
+            email.user.set_to_active(&mut *tx).await?;
+            Ok(email)
+        }
+    }
+
+    fn handle_session<'a>(
+        sessions: &'a Sessions,
+        session: Session,
+        config: &'a WebsiteConfig,
+        user: &'a Self::User,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + Send {
+        async move {
+            sessions
+                .reuse_current_as_new_one(&session, user.to_session(), &config.session_key)
+                .await
+        }
+    }
 }
 
 pub async fn set_session_cookies(
@@ -334,3 +580,195 @@ pub async fn set_session_cookies(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{
+        audit::{AuditFilter, AuditLog},
+        auth::test_utils::TestSessions,
+        database::Database,
+        models::UserSession,
+    };
+
+    use super::*;
+
+    struct Website;
+
+    impl Ingress for Website {
+        type User = User;
+    }
+
+    impl MagicLink for Website {
+        type User = User;
+    }
+
+    async fn logins(database: &Database) -> usize {
+        AuditLog::search(
+            &AuditFilter {
+                action: Some(actions::LOGIN.into()),
+                ..Default::default()
+            },
+            &**database,
+        )
+        .await
+        .unwrap()
+        .len()
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_login_deactivated_by_admin(pool: PgPool) {
+        let database: Database = pool.into();
+        let sessions = TestSessions::new().await;
+        let state = sessions.website(database.clone());
+        let password = state.password_hasher().hash("password").unwrap();
+        let user = User::create(&password, Some(chrono::Utc::now().naive_utc()), &*database)
+            .await
+            .unwrap();
+        let email = EmailAccount::create_primary_active(
+            user.clone(),
+            "test_login_deactivated_by_admin@example.com".into(),
+            &*database,
+        )
+        .await
+        .unwrap();
+        user.deactivate(true, &*database).await.unwrap();
+
+        let session = sessions.create(UserSession::default()).await;
+        let input = IngressForm {
+            email: email.email.clone(),
+            password: "password".into(),
+            process: IngressProcess::Login,
+        };
+        assert!(matches!(
+            <Website as Ingress>::login(
+                &state,
+                session.clone(),
+                &IngressParams { next: None },
+                input
+            )
+            .await,
+            Err(AppError::AccountDeactivated)
+        ));
+
+        let link = MagicLinkManager::new(email.pk, session.id().await)
+            .save(&database, 10)
+            .await
+            .unwrap();
+        assert!(matches!(
+            <Website as MagicLink>::login(&state, session.clone(), link.slug).await,
+            Err(AppError::AccountDeactivated)
+        ));
+
+        assert_eq!(logins(&database).await, 0);
+        assert_eq!(session.user().await.pk(), None);
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_magic_link_login(pool: PgPool) {
+        let database: Database = pool.into();
+        let sessions = TestSessions::new().await;
+        let state = sessions.website(database.clone());
+        let mut tx = database.start_transaction().await.unwrap();
+        let user = User::create("", None, &mut *tx)
+            .await
+            .unwrap()
+            .add_to_group(Group::USER, &mut *tx)
+            .await
+            .unwrap();
+        let email = EmailAccount::create_primary(
+            user.clone(),
+            "test_magic_link_login@example.com".into(),
+            None,
+            &mut *tx,
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let session = sessions.create(UserSession::default()).await;
+        let link = MagicLinkManager::new(email.pk, session.id().await)
+            .save(&database, 10)
+            .await
+            .unwrap();
+        <Website as MagicLink>::login(&state, session.clone(), link.slug)
+            .await
+            .unwrap();
+
+        let logged = session.user().await;
+        assert_eq!(logged.pk(), Some(user.pk));
+        assert!(logged.groups().unwrap().contains(Group::USER));
+        assert_eq!(logins(&database).await, 1);
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_change_password(pool: PgPool) {
+        let database: Database = pool.into();
+        let sessions = TestSessions::new().await;
+        let state = sessions.website(database.clone());
+        let password = state.password_hasher().hash("first secret").unwrap();
+        let user = User::create(&password, Some(chrono::Utc::now().naive_utc()), &*database)
+            .await
+            .unwrap();
+        let session = sessions
+            .create(UserSession::new_authenticated(user.clone()))
+            .await;
+        let form = |current: &str, new: &str| ChangePasswordForm {
+            current_password: current.into(),
+            new_password: new.into(),
+        };
+        let changes = || async {
+            AuditLog::search(
+                &AuditFilter {
+                    action: Some(actions::PASSWORD_CHANGED.into()),
+                    ..Default::default()
+                },
+                &*database,
+            )
+            .await
+            .unwrap()
+        };
+
+        assert!(matches!(
+            Website::change_password(&state, &session, form("wrong secret", "second secret")).await,
+            Err(AppError::WrongPassword(_))
+        ));
+        assert!(matches!(
+            Website::change_password(&state, &session, form("first secret", "short")).await,
+            Err(AppError::WeakPassword(_))
+        ));
+        assert!(changes().await.is_empty());
+
+        Website::change_password(&state, &session, form("first secret", "second secret"))
+            .await
+            .unwrap();
+        let stored = user.password(&*database).await.unwrap().unwrap();
+        state
+            .password_hasher()
+            .verify("second secret", &stored)
+            .unwrap();
+        let changes = changes().await;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].actor_pk, Some(user.pk));
+        assert_eq!(
+            changes[0].target.as_deref(),
+            Some(user.pk.to_string().as_str())
+        );
+
+        state
+            .sessions()
+            .reuse_current_as_impersonation(
+                &session,
+                UserSession::new_authenticated(user.clone()),
+                Some(user.pk),
+                &state.config().session_key,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            Website::change_password(&state, &session, form("second secret", "third secret")).await,
+            Err(AppError::ImpersonationForbidden)
+        ));
+    }
+}
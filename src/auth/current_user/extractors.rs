@@ -0,0 +1,158 @@
+use axum::{
+    extract::{FromRef, FromRequestParts, OptionalFromRequestParts},
+    http::request::Parts,
+};
+
+use crate::{database::Database, errors::AppError, sessions::Session};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Session,
+    Token,
+    ApiKey,
+}
+
+/// The authenticated user of the request, whether it comes from `sessions_middleware`,
+/// `jwt_middleware` or `api_key_middleware`. The handlers using it can be mounted both in a
+/// `WebsiteService` and in an `APIService`.
+#[derive(Debug, Clone)]
+pub struct CurrentUser {
+    pub pk: i64,
+    pub method: AuthMethod,
+    /// The admin behind the session, when impersonating
+    pub impersonator_pk: Option<i64>,
+}
+
+impl CurrentUser {
+    pub fn new(pk: i64, method: AuthMethod) -> Self {
+        Self {
+            pk,
+            method,
+            impersonator_pk: None,
+        }
+    }
+
+    async fn from_session(
+        session: &Session,
+        database: &Database,
+    ) -> Result<Option<Self>, AppError> {
+        if !session.is_authenticated(database).await? {
+            return Ok(None);
+        }
+        let Some(pk) = session.user_pk().await else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            pk,
+            method: AuthMethod::Session,
+            impersonator_pk: session.impersonator_pk().await,
+        }))
+    }
+}
+
+impl<S> OptionalFromRequestParts<S> for CurrentUser
+where
+    Database: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        // The token middlewares insert it, sessions are checked here
+        if let Some(user) = parts.extensions.get::<Self>() {
+            return Ok(Some(user.clone()));
+        }
+        match parts.extensions.get::<Session>() {
+            Some(session) => Self::from_session(session, &Database::from_ref(state)).await,
+            None => Ok(None),
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for CurrentUser
+where
+    Database: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        <Self as OptionalFromRequestParts<S>>::from_request_parts(parts, state)
+            .await?
+            .ok_or(AppError::Unauthorized)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, routing::get, Extension, Router};
+    use sqlx::PgPool;
+
+    use crate::{
+        auth::test_utils::{self, TestSessions},
+        models::{User, UserSession},
+    };
+
+    use super::*;
+
+    async fn me(user: CurrentUser) -> String {
+        format!("{} {:?}", user.pk, user.method)
+    }
+
+    async fn maybe(user: Option<CurrentUser>) -> String {
+        user.map(|user| user.pk.to_string()).unwrap_or_default()
+    }
+
+    async fn call(
+        router: Router<Database>,
+        database: &Database,
+        uri: &str,
+    ) -> (StatusCode, String) {
+        test_utils::get(router.with_state(database.clone()), uri).await
+    }
+
+    fn routes() -> Router<Database> {
+        Router::new()
+            .route("/me", get(me))
+            .route("/maybe", get(maybe))
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_current_user(pool: PgPool) {
+        let database: Database = pool.into();
+        let user = User::create_active("", &*database).await.unwrap();
+
+        let token = routes().layer(Extension(CurrentUser::new(user.pk, AuthMethod::Token)));
+        assert_eq!(
+            call(token, &database, "/me").await,
+            (StatusCode::OK, format!("{} Token", user.pk))
+        );
+
+        let sessions = TestSessions::new().await;
+        let session = sessions
+            .create(UserSession::new_authenticated(user.clone()))
+            .await;
+        assert_eq!(
+            call(routes().layer(Extension(session)), &database, "/me").await,
+            (StatusCode::OK, format!("{} Session", user.pk))
+        );
+
+        let anonymous = sessions.create(UserSession::default()).await;
+        let anonymous = routes().layer(Extension(anonymous));
+        assert_eq!(
+            call(anonymous.clone(), &database, "/me").await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            call(anonymous, &database, "/maybe").await,
+            (StatusCode::OK, String::new())
+        );
+        assert_eq!(
+            call(routes(), &database, "/me").await.0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
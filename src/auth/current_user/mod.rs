@@ -0,0 +1,3 @@
+mod extractors;
+
+pub use extractors::{AuthMethod, CurrentUser};
@@ -1,9 +0,0 @@
-mod infrastructure;
-mod oauth;
-mod routes;
-mod service;
-
-pub use infrastructure::GoogleUserInfo;
-pub use oauth::{CallbackValidation, OauthTokenResponse};
-pub use routes::{oauth_return, start_oauth};
-pub use service::GoogleOauthCallbackHook;
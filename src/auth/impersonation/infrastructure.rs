@@ -0,0 +1,100 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sqlx::{FromRow, PgExecutor};
+
+use crate::{errors::AppError, log_and_wrap_custom_internal};
+
+/// Audit trail of the admins acting as other users
+#[derive(Debug, FromRow, Serialize)]
+pub struct ImpersonationLog {
+    pub pk: i64,
+    pub admin_pk: i64,
+    pub user_pk: i64,
+    pub started_at: NaiveDateTime,
+    pub stopped_at: Option<NaiveDateTime>,
+}
+
+impl ImpersonationLog {
+    pub async fn start<'e, E: PgExecutor<'e>>(
+        admin_pk: i64,
+        user_pk: i64,
+        executor: E,
+    ) -> Result<Self, AppError> {
+        sqlx::query_as(
+            "INSERT INTO impersonation_logs (admin_pk, user_pk) VALUES ($1, $2) RETURNING *;",
+        )
+        .bind(admin_pk)
+        .bind(user_pk)
+        .fetch_one(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Close the open entries of the admin over the user
+    pub async fn stop<'e, E: PgExecutor<'e>>(
+        admin_pk: i64,
+        user_pk: i64,
+        executor: E,
+    ) -> Result<u64, AppError> {
+        sqlx::query(
+            "UPDATE impersonation_logs SET stopped_at = CURRENT_TIMESTAMP
+            WHERE admin_pk = $1 AND user_pk = $2 AND stopped_at IS NULL;",
+        )
+        .bind(admin_pk)
+        .bind(user_pk)
+        .execute(executor)
+        .await
+        .map(|r| r.rows_affected())
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    pub async fn list_for_user<'e, E: PgExecutor<'e>>(
+        user_pk: i64,
+        executor: E,
+    ) -> Result<Vec<Self>, AppError> {
+        sqlx::query_as(
+            "SELECT * FROM impersonation_logs WHERE user_pk = $1 ORDER BY started_at DESC, pk DESC;",
+        )
+        .bind(user_pk)
+        .fetch_all(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{database::Database, models::User};
+
+    use super::*;
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_start_and_stop(pool: PgPool) {
+        let database: Database = pool.into();
+        let admin_pk = User::create_active_default(&*database).await.unwrap().pk;
+        let user_pk = User::create_active_default(&*database).await.unwrap().pk;
+
+        let log = ImpersonationLog::start(admin_pk, user_pk, &*database)
+            .await
+            .unwrap();
+        assert!(log.stopped_at.is_none());
+
+        let stopped = ImpersonationLog::stop(admin_pk, user_pk, &*database)
+            .await
+            .unwrap();
+        assert_eq!(stopped, 1);
+        let stopped = ImpersonationLog::stop(admin_pk, user_pk, &*database)
+            .await
+            .unwrap();
+        assert_eq!(stopped, 0);
+
+        let logs = ImpersonationLog::list_for_user(user_pk, &*database)
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].admin_pk, admin_pk);
+        assert!(logs[0].stopped_at.is_some());
+    }
+}
@@ -0,0 +1,14 @@
+use axum::{extract::Request, middleware::Next, response::Response, Extension};
+
+use crate::{errors::AppError, sessions::Session};
+
+/// Layer for the routes an admin must not use on behalf of a user, like password changes or
+/// payments.
+pub async fn forbid_impersonation_middleware(
+    Extension(session): Extension<Session>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    session.forbid_impersonation().await?;
+    Ok(next.run(request).await)
+}
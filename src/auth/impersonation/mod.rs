@@ -0,0 +1,7 @@
+mod infrastructure;
+mod middlewares;
+mod services;
+
+pub use infrastructure::ImpersonationLog;
+pub use middlewares::forbid_impersonation_middleware;
+pub use services::{Impersonation, ImpersonationBanner};
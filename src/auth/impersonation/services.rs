@@ -0,0 +1,150 @@
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    response::Redirect,
+    Extension,
+};
+
+use serde_json::json;
+
+use crate::{
+    audit::{self, actions},
+    errors::AppError,
+    models::User,
+    sessions::Session,
+    state::WebsiteState,
+    website::{EmptyForm, SecureForm},
+};
+
+use super::infrastructure::ImpersonationLog;
+
+/// Let admins act as another user to reproduce their issues. The admin is remembered in the
+/// session so they can go back, and every start and stop is stored in `impersonation_logs`.
+/// Both routes are POST forms with the CSRF token.
+pub trait Impersonation {
+    fn start_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        Path(user_pk): Path<i64>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            let database = state.database();
+            let current = session.user().await;
+            if !current.is_admin() || session.is_impersonating().await {
+                return Err(AppError::RoleError);
+            }
+            let admin_pk = current.pk().ok_or(AppError::Unauthorized)?;
+
+            let user = User::get_by_pk(user_pk, &**database).await?;
+            // Acting as another admin would hide who did what
+            if user.pk == admin_pk || user.clone().for_session().is_admin() {
+                return Err(AppError::RoleError);
+            }
+
+            ImpersonationLog::start(admin_pk, user.pk, &**database).await?;
+            audit::record(
+                Some(admin_pk),
+                actions::IMPERSONATION_STARTED,
+                Some(&user.pk.to_string()),
+                json!({}),
+                database,
+            )
+            .await?;
+            state
+                .sessions()
+                .reuse_current_as_impersonation(
+                    &session,
+                    user.for_session(),
+                    Some(admin_pk),
+                    &state.config().session_key,
+                )
+                .await?;
+            Ok(Redirect::to(Self::start_redirect(&state)))
+        }
+    }
+
+    fn stop_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            let database = state.database();
+            let admin_pk = session
+                .impersonator_pk()
+                .await
+                .ok_or_else(|| AppError::custom_bad_request("Not impersonating any user"))?;
+            let user_pk = session.user_pk().await.ok_or(AppError::Unauthorized)?;
+
+            let admin = User::get_by_pk(admin_pk, &**database).await?;
+            ImpersonationLog::stop(admin_pk, user_pk, &**database).await?;
+            audit::record(
+                Some(admin_pk),
+                actions::IMPERSONATION_STOPPED,
+                Some(&user_pk.to_string()),
+                json!({}),
+                database,
+            )
+            .await?;
+            state
+                .sessions()
+                .reuse_current_as_new_one(
+                    &session,
+                    admin.for_session(),
+                    &state.config().session_key,
+                )
+                .await?;
+            Ok(Redirect::to(Self::stop_redirect(&state)))
+        }
+    }
+
+    fn start_redirect(_state: &WebsiteState) -> &str {
+        "/"
+    }
+
+    fn stop_redirect(state: &WebsiteState) -> &str {
+        &state.config().login_redirect_to
+    }
+}
+
+/// Banner for the templates, render it with `{{ banner|safe }}` when
+/// [`ImpersonationBanner::from_session`] returns something.
+#[derive(Debug, Template)]
+#[template(path = "components/impersonation_banner.html")]
+pub struct ImpersonationBanner {
+    pub admin_pk: i64,
+    pub user_pk: i64,
+    pub stop_url: String,
+    pub csrf_token: String,
+}
+
+impl ImpersonationBanner {
+    pub async fn from_session(session: &Session, stop_url: &str) -> Option<Self> {
+        Some(Self {
+            admin_pk: session.impersonator_pk().await?,
+            user_pk: session.user_pk().await?,
+            stop_url: stop_url.to_owned(),
+            csrf_token: session.csrf_token().await,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_banner() {
+        let html = ImpersonationBanner {
+            admin_pk: 1,
+            user_pk: 2,
+            stop_url: "/impersonation/stop".into(),
+            csrf_token: "token".into(),
+        }
+        .render()
+        .unwrap();
+        assert!(html.contains(r#"action="/impersonation/stop""#));
+        assert!(html.contains(r#"name="csrf_token" value="token""#));
+    }
+}
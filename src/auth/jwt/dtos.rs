@@ -1,39 +1,36 @@
 use chrono::{Duration, Utc};
-use jsonwebtoken::{get_current_timestamp, DecodingKey, EncodingKey};
+use jsonwebtoken::get_current_timestamp;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::errors::AppError;
+use crate::{auth::GrantedScopes, errors::AppError};
 
 #[derive(Clone)]
 pub struct JWTUserRequest<T: Clone> {
     pub id: i64,
     pub private: T,
+    pub scopes: Vec<String>,
+    /// To revoke the token, with its expiration
+    pub jti: String,
+    pub expires_at: i64,
 }
 
 impl<T: Clone> JWTUserRequest<T> {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        GrantedScopes::grants(&self.scopes, scope)
+    }
+
     pub fn new(claims: JWTClaims<T>) -> Result<Self, AppError> {
         Ok(Self {
             id: claims.sub.parse::<i64>().map_err(AppError::JWTModified)?,
             private: claims.private.clone(),
+            scopes: claims.scopes(),
+            jti: claims.jti,
+            expires_at: claims.exp,
         })
     }
 }
 
-#[derive(Clone)]
-pub struct Keys {
-    pub encoding: EncodingKey,
-    pub decoding: DecodingKey,
-}
-
-impl Keys {
-    pub fn new(secret: &[u8]) -> Self {
-        Self {
-            encoding: EncodingKey::from_secret(secret),
-            decoding: DecodingKey::from_secret(secret),
-        }
-    }
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JWTClaims<T> {
     // Registered claims
@@ -43,6 +40,9 @@ pub struct JWTClaims<T> {
     exp: i64,    // Expiration time
     iat: u64,    // Issued at
     jti: String, // JWT ID
+    // Space separated, as in RFC 8693
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    scope: String,
 
     // Private claims
     #[serde(flatten)]
@@ -50,15 +50,58 @@ pub struct JWTClaims<T> {
 }
 
 impl<T> JWTClaims<T> {
-    pub fn new(user_id: i64, site: &str, private: T) -> Self {
+    pub fn new(user_id: i64, site: &str, expiration: Duration, private: T) -> Self {
         JWTClaims {
             iss: site.to_owned(),
             sub: user_id.to_string(),
             aud: site.to_owned(),
-            exp: (Utc::now() + Duration::days(1)).timestamp(),
+            exp: (Utc::now() + expiration).timestamp(),
             iat: get_current_timestamp(),
-            jti: String::new(),
+            jti: Uuid::new_v4().to_string(),
+            scope: String::new(),
             private,
         }
     }
+
+    pub fn with_scopes<S: AsRef<str>>(mut self, scopes: &[S]) -> Self {
+        self.scope = scopes
+            .iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<_>>()
+            .join(" ");
+        self
+    }
+
+    pub fn scopes(&self) -> Vec<String> {
+        self.scope.split_whitespace().map(str::to_owned).collect()
+    }
+
+    pub fn jti(&self) -> &str {
+        &self.jti
+    }
+
+    pub fn exp(&self) -> i64 {
+        self.exp
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenCredentials {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// The response of the login and refresh endpoints
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    /// Seconds before the access token expires
+    pub expires_in: i64,
 }
@@ -0,0 +1,282 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgExecutor, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{database::Database, errors::AppError, log_and_wrap_custom_internal, state::APIState};
+
+/// An opaque refresh token of the API, only its hash is stored. Each use rotates it: the token
+/// is marked as used and a new one of the same family is issued.
+#[derive(Debug, FromRow)]
+pub struct RefreshToken {
+    pub pk: i64,
+    pub family: Uuid,
+    pub user_pk: i64,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl RefreshToken {
+    /// Returns the token to hand to the client. Without a family it starts a new one, at login.
+    pub async fn issue<'e, E: PgExecutor<'e>>(
+        user_pk: i64,
+        family: Option<Uuid>,
+        expiration: Duration,
+        executor: E,
+    ) -> Result<String, AppError> {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+
+        sqlx::query(
+            "INSERT INTO refresh_tokens (family, token_hash, user_pk, expires_at) VALUES ($1, $2, $3, $4);",
+        )
+        .bind(family.unwrap_or_else(Uuid::new_v4))
+        .bind(hash_token(&token))
+        .bind(user_pk)
+        .bind(Utc::now().naive_utc() + expiration)
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(token)
+    }
+
+    /// Use the token, returns it to issue the next one of its family. A token used twice was
+    /// copied, by the client or a thief, so its whole family is revoked. The token is only used
+    /// once the returned transaction commits: issue the next one in it, a failure keeps the
+    /// client's token valid.
+    pub async fn consume<'d>(
+        token: &str,
+        database: &'d Database,
+    ) -> Result<(Self, Transaction<'d, Postgres>), AppError> {
+        let mut tx = database.start_transaction().await?;
+
+        let stored: Self = sqlx::query_as(
+            "SELECT pk, family, user_pk, expires_at, used_at, revoked_at FROM refresh_tokens WHERE token_hash = $1 FOR UPDATE;",
+        )
+        .bind(hash_token(token))
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?
+        .ok_or(AppError::Unauthorized)?;
+
+        if stored.revoked_at.is_some() || stored.expires_at <= Utc::now().naive_utc() {
+            return Err(AppError::Unauthorized);
+        }
+        if stored.used_at.is_some() {
+            Self::revoke_family(stored.family, &mut *tx).await?;
+            tx.commit()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            tracing::warn!(
+                "Refresh token reused for user {}, its family is revoked",
+                stored.user_pk
+            );
+            return Err(AppError::Unauthorized);
+        }
+
+        sqlx::query("UPDATE refresh_tokens SET used_at = $1 WHERE pk = $2;")
+            .bind(Utc::now().naive_utc())
+            .bind(stored.pk)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok((stored, tx))
+    }
+
+    pub async fn revoke_family<'e, E: PgExecutor<'e>>(
+        family: Uuid,
+        executor: E,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = $1 WHERE family = $2 AND revoked_at IS NULL;",
+        )
+        .bind(Utc::now().naive_utc())
+        .bind(family)
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(())
+    }
+
+    /// Revoke the family of the token, to log out
+    pub async fn revoke<'e, E: PgExecutor<'e>>(token: &str, executor: E) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = $1
+            WHERE family = (SELECT family FROM refresh_tokens WHERE token_hash = $2) AND revoked_at IS NULL;",
+        )
+        .bind(Utc::now().naive_utc())
+        .bind(hash_token(token))
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(())
+    }
+
+    /// Log the user out of every API client
+    pub async fn revoke_all_for_user<'e, E: PgExecutor<'e>>(
+        user_pk: i64,
+        executor: E,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = $1 WHERE user_pk = $2 AND revoked_at IS NULL;",
+        )
+        .bind(Utc::now().naive_utc())
+        .bind(user_pk)
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(())
+    }
+}
+
+/// The access tokens invalidated before their expiration, by `jti`. They are kept until they
+/// expire, then the cleanup deletes them.
+pub struct RevokedToken;
+
+impl RevokedToken {
+    pub async fn revoke<'e, E: PgExecutor<'e>>(
+        jti: &str,
+        expires_at: i64,
+        executor: E,
+    ) -> Result<(), AppError> {
+        let expires_at = DateTime::from_timestamp(expires_at, 0)
+            .ok_or_else(|| AppError::custom_bad_request("Invalid expiration"))?
+            .naive_utc();
+        sqlx::query(
+            "INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, $2) ON CONFLICT (jti) DO NOTHING;",
+        )
+        .bind(jti)
+        .bind(expires_at)
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(())
+    }
+
+    pub async fn is_revoked<'e, E: PgExecutor<'e>>(
+        jti: &str,
+        executor: E,
+    ) -> Result<bool, AppError> {
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = $1);")
+            .bind(jti)
+            .fetch_one(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Expired tokens are rejected anyway. Returns the number of deleted rows.
+    pub async fn delete_expired<'e, E: PgExecutor<'e>>(executor: E) -> Result<u64, AppError> {
+        sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < $1;")
+            .bind(Utc::now().naive_utc())
+            .execute(executor)
+            .await
+            .map(|result| result.rows_affected())
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    pub async fn run_cleanup(state: APIState) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = Self::delete_expired(&**state.database()).await {
+                tracing::error!("Cannot delete the expired revoked tokens: {:?}", e);
+            }
+        }
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{
+        config::{APIConfig, ServiceConfig},
+        models::User,
+    };
+
+    use super::*;
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_refresh_token_rotation(pool: PgPool) {
+        let database: Database = pool.into();
+        let user = User::create_active("", &*database).await.unwrap();
+        let expiration = APIConfig::stub().refresh_token_lifetime();
+
+        let first = RefreshToken::issue(user.pk, None, expiration, &*database)
+            .await
+            .unwrap();
+        let stored: String = sqlx::query_scalar("SELECT token_hash FROM refresh_tokens;")
+            .fetch_one(&*database)
+            .await
+            .unwrap();
+        assert_ne!(stored, first);
+
+        // Without a commit the token stays unused
+        let (used, tx) = RefreshToken::consume(&first, &database).await.unwrap();
+        drop(tx);
+        let (used_again, mut tx) = RefreshToken::consume(&first, &database).await.unwrap();
+        assert_eq!(used_again.pk, used.pk);
+        assert_eq!(used.user_pk, user.pk);
+        let second = RefreshToken::issue(user.pk, Some(used.family), expiration, &mut *tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        let (used, mut tx) = RefreshToken::consume(&second, &database).await.unwrap();
+        let third = RefreshToken::issue(user.pk, Some(used.family), expiration, &mut *tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        // Replaying a rotated token revokes the family, including the latest one
+        let other = RefreshToken::issue(user.pk, None, expiration, &*database)
+            .await
+            .unwrap();
+        assert!(matches!(
+            RefreshToken::consume(&first, &database).await,
+            Err(AppError::Unauthorized)
+        ));
+        assert!(RefreshToken::consume(&third, &database).await.is_err());
+        assert!(RefreshToken::consume("unknown", &database).await.is_err());
+
+        RefreshToken::revoke(&other, &*database).await.unwrap();
+        assert!(RefreshToken::consume(&other, &database).await.is_err());
+
+        let expired = RefreshToken::issue(user.pk, None, Duration::seconds(-1), &*database)
+            .await
+            .unwrap();
+        assert!(RefreshToken::consume(&expired, &database).await.is_err());
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_revoked_tokens(pool: PgPool) {
+        let database: Database = pool.into();
+        let expires_at = (Utc::now() + Duration::minutes(15)).timestamp();
+
+        assert!(!RevokedToken::is_revoked("jti", &*database).await.unwrap());
+        RevokedToken::revoke("jti", expires_at, &*database)
+            .await
+            .unwrap();
+        // Logging out twice is fine
+        RevokedToken::revoke("jti", expires_at, &*database)
+            .await
+            .unwrap();
+        assert!(RevokedToken::is_revoked("jti", &*database).await.unwrap());
+
+        RevokedToken::revoke(
+            "expired",
+            (Utc::now() - Duration::minutes(1)).timestamp(),
+            &*database,
+        )
+        .await
+        .unwrap();
+        assert_eq!(RevokedToken::delete_expired(&*database).await.unwrap(), 1);
+        assert!(RevokedToken::is_revoked("jti", &*database).await.unwrap());
+    }
+}
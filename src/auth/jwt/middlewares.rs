@@ -7,12 +7,15 @@ use axum_extra::{
     headers::{authorization::Bearer, Authorization},
     TypedHeader,
 };
-use jsonwebtoken::decode;
 use serde::Deserialize;
 
-use crate::{errors::AppError, state::APIState};
+use crate::{
+    auth::{AuthMethod, CurrentUser, GrantedScopes},
+    errors::AppError,
+    state::APIState,
+};
 
-use super::{dtos::JWTClaims, JWTUserRequest};
+use super::{dtos::JWTClaims, infrastructure::RevokedToken, JWTUserRequest};
 
 pub async fn jwt_middleware<
     U: for<'a> Deserialize<'a> + std::marker::Send + std::marker::Sync + Clone + 'static,
@@ -22,11 +25,81 @@ pub async fn jwt_middleware<
     mut request: Request,
     next: Next,
 ) -> Result<Response, AppError> {
-    let token = decode::<JWTClaims<U>>(bearer.token(), state.decoding(), state.validator())
-        .map_err(AppError::JWTError)?;
+    let token = state
+        .keys()
+        .decode::<JWTClaims<U>>(bearer.token(), state.validator())?;
+    // Tokens without an id can't be revoked
+    if token.claims.jti().is_empty()
+        || RevokedToken::is_revoked(token.claims.jti(), &**state.database()).await?
+    {
+        return Err(AppError::Unauthorized);
+    }
 
     let user = JWTUserRequest::new(token.claims)?;
+    request
+        .extensions_mut()
+        .insert(GrantedScopes(user.scopes.clone()));
+    request
+        .extensions_mut()
+        .insert(CurrentUser::new(user.id, AuthMethod::Token));
     request.extensions_mut().insert(user);
 
     Ok(next.run(request).await)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use serde::Serialize;
+    use sqlx::PgPool;
+
+    use crate::{
+        auth::{create_token, test_utils},
+        config::{APIConfig, ServiceConfig},
+        database::Database,
+        state::SharedState,
+    };
+
+    use super::*;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Claims {}
+
+    async fn call(state: &APIState, token: &str) -> StatusCode {
+        let router = Router::new()
+            .route("/", get(|| async {}))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                jwt_middleware::<Claims>,
+            ))
+            .with_state(state.clone());
+        let request = Request::get("/")
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+        test_utils::send(router, request).await.0
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_revoked_token(pool: PgPool) {
+        let database: Database = pool.into();
+        let state = APIState::new(
+            APIConfig::stub(),
+            SharedState::stub().with_database(database),
+        )
+        .unwrap();
+        let token = create_token(1, Claims {}, &["read"], state.config(), state.keys()).unwrap();
+        assert_eq!(call(&state, &token).await, StatusCode::OK);
+
+        let claims = state
+            .keys()
+            .decode::<JWTClaims<Claims>>(&token, state.validator())
+            .unwrap()
+            .claims;
+        assert_eq!(claims.scopes(), vec!["read".to_owned()]);
+        RevokedToken::revoke(claims.jti(), claims.exp(), &**state.database())
+            .await
+            .unwrap();
+        assert_eq!(call(&state, &token).await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,8 +1,14 @@
 mod dtos;
+mod infrastructure;
+mod keys;
 mod middlewares;
 
 mod services;
 
-pub use dtos::{JWTUserRequest, Keys};
+pub use dtos::{JWTUserRequest, RefreshRequest, TokenCredentials, TokenPair};
+pub use infrastructure::{RefreshToken, RevokedToken};
+pub use keys::{Keys, SigningKey};
 pub use middlewares::jwt_middleware;
-pub use services::{create_token, create_validator};
+pub use services::{
+    create_token, create_token_expiring_in, create_validator, jwks_route, TokenIngress,
+};
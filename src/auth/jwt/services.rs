@@ -1,32 +1,225 @@
-use jsonwebtoken::{encode, EncodingKey, Header, Validation};
+use axum::{extract::State, http::StatusCode, Json};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use chrono::Duration;
+use jsonwebtoken::{jwk::JwkSet, Validation};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgExecutor;
 
-use crate::errors::AppError;
+use crate::{
+    audit::{self, actions},
+    config::{APIConfig, ServiceConfig},
+    errors::AppError,
+    log_and_wrap_custom_internal,
+    models::User,
+    state::APIState,
+};
 
-use super::dtos::JWTClaims;
+use super::{
+    dtos::{JWTClaims, RefreshRequest, TokenCredentials, TokenPair},
+    infrastructure::{RefreshToken, RevokedToken},
+    keys::Keys,
+};
 
 pub fn create_token<
     T: for<'a> Deserialize<'a> + std::marker::Send + std::marker::Sync + Clone + 'static + Serialize,
 >(
     user_id: i64,
     private_claims: T,
-    domain: &str,
-    encoding: &EncodingKey,
+    scopes: &[&str],
+    config: &APIConfig,
+    keys: &Keys,
 ) -> Result<String, AppError> {
-    encode(
-        &Header::default(),
-        &JWTClaims::new(user_id, domain, private_claims),
-        encoding,
+    create_token_expiring_in(
+        user_id,
+        private_claims,
+        scopes,
+        config.domain(),
+        keys,
+        config.access_token_lifetime(),
     )
-    .map_err(AppError::JWTError)
 }
 
-pub fn create_validator(domain: &str) -> Validation {
+pub fn create_token_expiring_in<
+    T: for<'a> Deserialize<'a> + std::marker::Send + std::marker::Sync + Clone + 'static + Serialize,
+>(
+    user_id: i64,
+    private_claims: T,
+    scopes: &[&str],
+    domain: &str,
+    keys: &Keys,
+    expiration: Duration,
+) -> Result<String, AppError> {
+    keys.encode(&JWTClaims::new(user_id, domain, expiration, private_claims).with_scopes(scopes))
+}
+
+/// The public keys of the tokens, to be served at `/.well-known/jwks.json`
+pub async fn jwks_route(State(state): State<APIState>) -> Json<JwkSet> {
+    Json(state.keys().jwks())
+}
+
+pub fn create_validator(config: &APIConfig) -> Validation {
     let mut validation = Validation::default();
-    validation.set_audience(&[domain]);
-    validation.set_issuer(&[domain]);
-    validation.leeway = 60 * 60 * 60 * 24 * 30; //TODO: keep at one hour instead of days
-    validation.reject_tokens_expiring_in_less_than = 86400u64;
-    validation.set_required_spec_claims(&["iss", "sub", "aud", "exp", "iat", "jti", "private"]);
+    validation.set_audience(&[config.domain()]);
+    validation.set_issuer(&[config.domain()]);
+    validation.leeway = config.jwt_leeway;
+    validation.set_required_spec_claims(&config.jwt_required_claims());
     validation
 }
+
+/// Login, refresh and logout endpoints for the API. They hand out short-lived access tokens
+/// with opaque refresh tokens, rotated on each refresh.
+pub trait TokenIngress {
+    type Claims: for<'a> Deserialize<'a> + Serialize + Send + Sync + Clone + 'static;
+
+    /// The private claims of the user's access tokens
+    fn claims(
+        state: &APIState,
+        user_pk: i64,
+    ) -> impl std::future::Future<Output = Result<Self::Claims, AppError>> + Send;
+
+    /// The scopes granted to the user's access tokens, none by default
+    fn scopes(
+        _state: &APIState,
+        _user_pk: i64,
+    ) -> impl std::future::Future<Output = Result<Vec<String>, AppError>> + Send {
+        async { Ok(Vec::new()) }
+    }
+
+    /// Check the credentials, returns the user. The default verifies the password with the
+    /// configured hasher, and rehashes it when the config changed.
+    fn authenticate<'a>(
+        state: &'a APIState,
+        credentials: &'a TokenCredentials,
+    ) -> impl std::future::Future<Output = Result<i64, AppError>> + Send {
+        async move {
+            let user = User::find_by_email_with_password(&credentials.email, state.database())
+                .await?
+                .ok_or(AppError::Unauthorized)?;
+            let hasher = state.password_hasher();
+            hasher
+                .verify(&credentials.password, &user.password)
+                .map_err(|_| AppError::Unauthorized)?;
+            if hasher.needs_rehash(&user.password) {
+                let password = hasher.hash(&credentials.password)?;
+                user.user
+                    .set_password(&password, &**state.database())
+                    .await?;
+            }
+            Ok(user.user.pk)
+        }
+    }
+
+    fn login_route(
+        state: State<APIState>,
+        Json(credentials): Json<TokenCredentials>,
+    ) -> impl std::future::Future<Output = Result<Json<TokenPair>, AppError>> + Send {
+        async move {
+            let database = state.database();
+            let user_pk = match Self::authenticate(&state, &credentials).await {
+                Ok(user_pk) => user_pk,
+                Err(e) => {
+                    audit::record_or_log(
+                        None,
+                        actions::LOGIN_FAILED,
+                        Some(&credentials.email),
+                        json!({"method": "token"}),
+                        database,
+                    )
+                    .await;
+                    return Err(e);
+                }
+            };
+            audit::record(
+                Some(user_pk),
+                actions::LOGIN,
+                None,
+                json!({"method": "token"}),
+                database,
+            )
+            .await?;
+            Self::issue(&state, user_pk, None, &**database)
+                .await
+                .map(Json)
+        }
+    }
+
+    fn refresh_route(
+        state: State<APIState>,
+        Json(request): Json<RefreshRequest>,
+    ) -> impl std::future::Future<Output = Result<Json<TokenPair>, AppError>> + Send {
+        async move {
+            // A failed issue rolls back, the client can retry with the same token
+            let (used, mut tx) =
+                RefreshToken::consume(&request.refresh_token, state.database()).await?;
+            let pair = Self::issue(&state, used.user_pk, Some(used.family), &mut *tx).await?;
+            tx.commit()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            Ok(Json(pair))
+        }
+    }
+
+    /// Revokes the refresh token's family, and the access token when it is sent too
+    fn logout_route(
+        state: State<APIState>,
+        bearer: Option<TypedHeader<Authorization<Bearer>>>,
+        Json(request): Json<RefreshRequest>,
+    ) -> impl std::future::Future<Output = Result<StatusCode, AppError>> + Send {
+        async move {
+            RefreshToken::revoke(&request.refresh_token, &**state.database()).await?;
+            if let Some(TypedHeader(bearer)) = bearer {
+                let token = state
+                    .keys()
+                    .decode::<JWTClaims<Self::Claims>>(bearer.token(), state.validator())?;
+                RevokedToken::revoke(token.claims.jti(), token.claims.exp(), &**state.database())
+                    .await?;
+            }
+            Ok(StatusCode::NO_CONTENT)
+        }
+    }
+
+    /// The refresh token is stored with `executor`, the transaction of a refresh
+    fn issue<'e, E: PgExecutor<'e>>(
+        state: &APIState,
+        user_pk: i64,
+        family: Option<uuid::Uuid>,
+        executor: E,
+    ) -> impl std::future::Future<Output = Result<TokenPair, AppError>> + Send {
+        async move {
+            let database = state.database();
+            // Deactivated users keep their tokens until they expire, but get no new ones
+            if !User::get_by_pk(user_pk, &**database)
+                .await?
+                .is_authenticated(database)
+                .await?
+            {
+                return Err(AppError::Unauthorized);
+            }
+
+            let config = state.config();
+            let expiration = config.access_token_lifetime();
+            let access_token = state.keys().encode(
+                &JWTClaims::new(
+                    user_pk,
+                    state.domain(),
+                    expiration,
+                    Self::claims(state, user_pk).await?,
+                )
+                .with_scopes(&Self::scopes(state, user_pk).await?),
+            )?;
+            let refresh_token =
+                RefreshToken::issue(user_pk, family, config.refresh_token_lifetime(), executor)
+                    .await?;
+            Ok(TokenPair {
+                access_token,
+                refresh_token,
+                token_type: "Bearer".into(),
+                expires_in: expiration.num_seconds(),
+            })
+        }
+    }
+}
@@ -1,13 +1,51 @@
+mod account;
+mod api_keys;
 mod basic;
-mod google;
+mod current_user;
+mod impersonation;
 mod jwt;
+mod oauth;
+mod organizations;
+mod permissions;
+mod scopes;
+#[cfg(test)]
+mod test_utils;
 
+pub use account::{
+    delete_expired_accounts, delete_scheduled_accounts, AccountEmails, AccountIdentities,
+    AccountLifecycle, LoginMethods, UserDataExport,
+};
+pub use api_keys::{api_key_middleware, ApiKey, ApiKeyRequest};
 pub use basic::{
-    hash_password, login_required_middleware, sessions_middleware, verify_password,
-    EmailValidation, EmailValidationManager, Ingress,
+    hash_password, login_required_middleware, login_required_model_middleware, sessions_middleware,
+    set_new_password, verify_password, EmailValidation, EmailValidationManager, Ingress, MagicLink,
+    MagicLinkManager, PasswordHasher, PasswordPolicy,
+};
+pub use current_user::{AuthMethod, CurrentUser};
+pub use impersonation::{
+    forbid_impersonation_middleware, Impersonation, ImpersonationBanner, ImpersonationLog,
+};
+pub use jwt::{
+    create_token, create_token_expiring_in, create_validator, jwks_route, jwt_middleware,
+    JWTUserRequest, Keys, RefreshRequest, RefreshToken, RevokedToken, SigningKey, TokenCredentials,
+    TokenIngress, TokenPair,
+};
+pub use oauth::{
+    get_json, map_oidc_claims, oauth_return, start_oauth, start_oauth_link, AuthorizationOptions,
+    CallbackState, CallbackValidation, GitHubProvider, GoogleProvider, IdTokenClaims,
+    IdTokenFields, IdTokenVerifier, JwksCache, MicrosoftProvider, OAuthCallbackHook, OAuthClient,
+    OAuthCredentials, OAuthEndpoints, OAuthProvider, OAuthProviders, OAuthUserInfo,
+    OauthTokenResponse, OpenIdProvider, StoredToken, TokenVault,
+};
+pub use organizations::{
+    CurrentOrganization, Invitation, InvitationForm, Membership, Organization, OrganizationForm,
+    Organizations, TenantTransaction, ORGANIZATION_HEADER,
+};
+pub use permissions::{
+    require_group, require_permission, Action, Authorized, Delete, DenyPage, Edit, Permission,
+    Policy, RequireGroup, RequirePermission, RequiredGroup, RequiredPermission, Roles, View,
 };
-pub use google::{
-    oauth_return, start_oauth, CallbackValidation, GoogleOauthCallbackHook, GoogleUserInfo,
-    OauthTokenResponse,
+pub use scopes::{
+    require_scope, GrantedScopes, RequireScope, Scope, SecuritySchemes, API_KEY_SECURITY,
+    BEARER_SECURITY,
 };
-pub use jwt::{create_token, create_validator, jwt_middleware, JWTUserRequest, Keys};
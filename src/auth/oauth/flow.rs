@@ -0,0 +1,553 @@
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use oauth2::{
+    basic::{
+        BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+        BasicTokenType,
+    },
+    reqwest::async_http_client,
+    AccessToken, AuthorizationCode, Client, CsrfToken, ExtraTokenFields, PkceCodeChallenge,
+    PkceCodeVerifier, RefreshToken, Scope, StandardRevocableToken, StandardTokenResponse,
+    TokenResponse,
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{
+    config::WebsiteConfig, database::Database, errors::AppError, log_and_wrap_custom_internal,
+    state::WebsiteState,
+};
+
+use super::{id_token::IdTokenClaims, providers::OAuthProvider};
+
+/// The `id_token` that OpenID Connect providers add to the token response
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct IdTokenFields {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+impl ExtraTokenFields for IdTokenFields {}
+
+pub type OAuthClient = Client<
+    BasicErrorResponse,
+    StandardTokenResponse<IdTokenFields, BasicTokenType>,
+    BasicTokenType,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+>;
+
+#[derive(Debug)]
+pub struct OauthTokenResponse(pub StandardTokenResponse<IdTokenFields, BasicTokenType>);
+
+impl OauthTokenResponse {
+    pub fn stub() -> Self {
+        let mut token = StandardTokenResponse::new(
+            AccessToken::new("access_token".into()),
+            BasicTokenType::Bearer,
+            IdTokenFields::default(),
+        );
+        token.set_refresh_token(Some(RefreshToken::new("refresh_token".into())));
+        token.set_expires_in(Some(&Duration::new(3600, 0)));
+        Self(token)
+    }
+
+    pub fn access_token(&self) -> &str {
+        self.0.access_token().secret()
+    }
+
+    pub fn refresh_token(&self) -> Option<&String> {
+        self.0.refresh_token().map(|t| t.secret())
+    }
+
+    pub fn expires_in(&self) -> Option<i64> {
+        //TODO: change this shit
+        self.0.expires_in().map(|d| d.as_secs() as i64)
+    }
+
+    pub fn id_token(&self) -> Option<&str> {
+        self.0.extra_fields().id_token.as_deref()
+    }
+
+    /// Verify the `id_token` when the provider has a verifier, in which case it is required
+    pub async fn verify_id_token(
+        &self,
+        provider: &dyn OAuthProvider,
+        nonce: Option<&str>,
+    ) -> Result<Option<IdTokenClaims>, AppError> {
+        let Some(verifier) = provider.id_token_verifier() else {
+            return Ok(None);
+        };
+        let id_token = self.id_token().ok_or(AppError::Unauthorized)?;
+        verifier.verify(id_token, nonce).await.map(Some)
+    }
+
+    pub async fn login(
+        config: &WebsiteConfig,
+        provider: &dyn OAuthProvider,
+        code: String,
+        pkce_code: String,
+    ) -> Result<Self, AppError> {
+        provider
+            .client(config)?
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(pkce_code))
+            .request_async(async_http_client)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+            .map(Self)
+    }
+
+    pub async fn new_token(
+        config: &WebsiteConfig,
+        provider: &dyn OAuthProvider,
+        refresh_token: String,
+    ) -> Result<Self, AppError> {
+        provider
+            .client(config)?
+            .exchange_refresh_token(&RefreshToken::new(refresh_token))
+            .request_async(async_http_client)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+            .map(Self)
+    }
+}
+
+/// Per-call authorization parameters, they take precedence over the provider's ones. It can be
+/// read from the query of the `start_oauth` routes.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthorizationOptions {
+    pub prompt: Option<String>,
+    pub access_type: Option<String>,
+    pub login_hint: Option<String>,
+    /// Space separated scopes asked on top of the provider's, for incremental authorization
+    pub scope: Option<String>,
+}
+
+impl AuthorizationOptions {
+    pub fn prompt(mut self, prompt: &str) -> Self {
+        self.prompt = Some(prompt.to_owned());
+        self
+    }
+
+    pub fn access_type(mut self, access_type: &str) -> Self {
+        self.access_type = Some(access_type.to_owned());
+        self
+    }
+
+    pub fn login_hint(mut self, login_hint: &str) -> Self {
+        self.login_hint = Some(login_hint.to_owned());
+        self
+    }
+
+    pub fn scopes(mut self, scopes: &[&str]) -> Self {
+        self.scope = Some(scopes.join(" "));
+        self
+    }
+
+    fn params(&self) -> Vec<(&str, &str)> {
+        let mut params = Vec::new();
+        for (name, value) in [
+            ("prompt", &self.prompt),
+            ("access_type", &self.access_type),
+            ("login_hint", &self.login_hint),
+        ] {
+            if let Some(value) = value {
+                params.push((name, value.as_str()));
+            }
+        }
+        if self.extra_scopes().next().is_some() {
+            // Keeps the scopes granted before, ignored by the providers not supporting it
+            params.push(("include_granted_scopes", "true"));
+        }
+        params
+    }
+
+    fn extra_scopes(&self) -> impl Iterator<Item = Scope> + '_ {
+        self.scope
+            .iter()
+            .flat_map(|scope| scope.split_whitespace())
+            .map(|scope| Scope::new(scope.to_owned()))
+    }
+}
+
+/// What was stored when the flow started
+#[derive(Debug, FromRow)]
+pub struct CallbackState {
+    pub pkce_code_verifier: String,
+    pub return_url: String,
+    pub nonce: Option<String>,
+    /// Set when the flow links the provider to this logged user instead of logging in
+    pub link_user_pk: Option<i64>,
+}
+
+pub struct CallbackValidation {
+    authorize_url: Url,
+    pkce_code_verifier: PkceCodeVerifier,
+    csrf_state: CsrfToken,
+    nonce: Option<String>,
+    link_user_pk: Option<i64>,
+    provider: String,
+}
+
+impl CallbackValidation {
+    /// Consume the state of the callback. It is only valid for the provider that started the
+    /// flow, and for `oauth_state_expiration` minutes.
+    pub async fn validate(
+        config: &WebsiteConfig,
+        params_state: String,
+        provider: &dyn OAuthProvider,
+        database: &Database,
+    ) -> Result<CallbackState, AppError> {
+        let csrf_state = CsrfToken::new(params_state);
+
+        // In one statement, so two callbacks racing with the same state can't both get it
+        sqlx::query_as(
+            "DELETE FROM oauth_state WHERE csrf_state = $1 AND provider = $2 AND created_at > $3
+            RETURNING pkce_code_verifier, return_url, nonce, link_user_pk;",
+        )
+        .bind(csrf_state.secret())
+        .bind(provider.name())
+        .bind(Self::expired_before(config))
+        .fetch_one(&**database)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    fn expired_before(config: &WebsiteConfig) -> NaiveDateTime {
+        Utc::now().naive_utc() - chrono::Duration::minutes(config.oauth_state_expiration)
+    }
+
+    /// Delete the flows that were never finished, returns how many
+    pub async fn delete_expired(
+        config: &WebsiteConfig,
+        database: &Database,
+    ) -> Result<u64, AppError> {
+        Ok(
+            sqlx::query("DELETE FROM oauth_state WHERE created_at <= $1;")
+                .bind(Self::expired_before(config))
+                .execute(&**database)
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?
+                .rows_affected(),
+        )
+    }
+
+    /// Delete the expired flows every `oauth_state_expiration` minutes, forever
+    pub async fn run_cleanup(state: WebsiteState) {
+        let every = std::time::Duration::from_secs(
+            60 * state.config().oauth_state_expiration.max(1) as u64,
+        );
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(e) = Self::delete_expired(state.config(), state.database()).await {
+                tracing::error!("Cannot delete the expired oauth states: {:?}", e);
+            }
+        }
+    }
+
+    pub fn new(config: &WebsiteConfig, provider: &dyn OAuthProvider) -> Result<Self, AppError> {
+        Self::with_options(config, provider, &AuthorizationOptions::default())
+    }
+
+    pub fn with_options(
+        config: &WebsiteConfig,
+        provider: &dyn OAuthProvider,
+        options: &AuthorizationOptions,
+    ) -> Result<Self, AppError> {
+        let client = provider.client(config)?;
+
+        let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
+        let mut request = client
+            .authorize_url(CsrfToken::new_random)
+            .add_scopes(provider.scopes())
+            .add_scopes(options.extra_scopes())
+            .set_pkce_challenge(pkce_code_challenge);
+        let params = options.params();
+        for (name, value) in provider.extra_authorization_params() {
+            if !params.iter().any(|(option, _)| *option == name) {
+                request = request.add_extra_param(name, value);
+            }
+        }
+        for (name, value) in params {
+            request = request.add_extra_param(name, value);
+        }
+        // Binds the ID token to this flow, against replays
+        let nonce = provider
+            .id_token_verifier()
+            .map(|_| CsrfToken::new_random().secret().to_owned());
+        if let Some(nonce) = &nonce {
+            request = request.add_extra_param("nonce", nonce);
+        }
+        let (authorize_url, csrf_state) = request.url();
+        Ok(Self {
+            authorize_url,
+            pkce_code_verifier,
+            csrf_state,
+            nonce,
+            link_user_pk: None,
+            provider: provider.name().to_owned(),
+        })
+    }
+
+    pub fn linking(mut self, user_pk: i64) -> Self {
+        self.link_user_pk = Some(user_pk);
+        self
+    }
+
+    pub async fn save(self, database: &Database, return_url: &str) -> Result<Self, AppError> {
+        let mut tx = database.start_transaction().await?;
+
+        sqlx::query(
+            "INSERT INTO oauth_state (csrf_state, pkce_code_verifier, return_url, provider, nonce, link_user_pk) VALUES ($1, $2, $3, $4, $5, $6);",
+        )
+        .bind(self.csrf_state.secret())
+        .bind(self.pkce_code_verifier.secret())
+        .bind(return_url)
+        .bind(&self.provider)
+        .bind(&self.nonce)
+        .bind(self.link_user_pk)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+
+        Ok(self)
+    }
+
+    pub fn authorize_url(self) -> Url {
+        self.authorize_url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{
+        auth::{GoogleProvider, IdTokenVerifier, JwksCache, OAuthCredentials, OpenIdProvider},
+        config::ServiceConfig,
+        http::HttpClient,
+    };
+
+    use super::{super::mock::mock_provider, *};
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_oauth_flow_with_mock_provider(pool: PgPool) {
+        let database: Database = pool.into();
+        let config = WebsiteConfig::stub();
+        let (issuer, mock) = mock_provider().await;
+        let credentials = OAuthCredentials::new("client-id", "client-secret");
+        let provider = OpenIdProvider::discover("mock", &issuer, credentials.clone())
+            .await
+            .unwrap();
+        let other = OpenIdProvider::discover("other", &issuer, credentials)
+            .await
+            .unwrap();
+
+        let validation = CallbackValidation::new(&config, &provider)
+            .unwrap()
+            .save(&database, "/next")
+            .await
+            .unwrap();
+        let csrf_state = validation.csrf_state.secret().clone();
+        let authorize_url = validation.authorize_url();
+        assert!(authorize_url
+            .as_str()
+            .starts_with(&format!("{issuer}/authorize")));
+        let query: Vec<(String, String)> = authorize_url.query_pairs().into_owned().collect();
+        assert!(query.contains(&("client_id".into(), "client-id".into())));
+        assert!(query.contains(&("scope".into(), "openid email profile".into())));
+        assert!(query
+            .iter()
+            .any(|(k, v)| k == "redirect_uri" && v.ends_with("/oauth/mock/callback")));
+        let nonce = query
+            .iter()
+            .find(|(k, _)| k == "nonce")
+            .map(|(_, v)| v.clone())
+            .unwrap();
+
+        assert!(
+            CallbackValidation::validate(&config, csrf_state.clone(), &other, &database)
+                .await
+                .is_err()
+        );
+        let callback =
+            CallbackValidation::validate(&config, csrf_state.clone(), &provider, &database)
+                .await
+                .unwrap();
+        assert_eq!(callback.return_url, "/next");
+        assert_eq!(callback.nonce.as_deref(), Some(nonce.as_str()));
+        assert!(
+            CallbackValidation::validate(&config, csrf_state, &provider, &database)
+                .await
+                .is_err()
+        );
+
+        mock.lock().unwrap().nonce = Some(nonce.clone());
+        let token = OauthTokenResponse::login(
+            &config,
+            &provider,
+            "code".into(),
+            callback.pkce_code_verifier,
+        )
+        .await
+        .unwrap();
+        assert_eq!(token.access_token(), "mock-access-token");
+        assert_eq!(token.refresh_token().unwrap(), "mock-refresh-token");
+
+        let claims = token
+            .verify_id_token(&provider, Some(&nonce))
+            .await
+            .unwrap();
+        assert!(token
+            .verify_id_token(&provider, Some("replayed"))
+            .await
+            .is_err());
+
+        let http_client = HttpClient::new();
+        let user_info = provider
+            .user_info(&http_client, token.access_token())
+            .await
+            .unwrap()
+            .with_id_token(claims)
+            .unwrap()
+            .validate_email()
+            .unwrap();
+        assert_eq!(user_info.provider, "mock");
+        assert_eq!(user_info.id, "mock-user-1");
+        assert_eq!(user_info.email, "mock@example.com");
+        assert_eq!(user_info.name.as_deref(), Some("Mock User"));
+        assert_eq!(user_info.id_token.unwrap().sub, "mock-user-1");
+
+        assert!(provider
+            .user_info(&http_client, "wrong-token")
+            .await
+            .is_err());
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_authorization_options_and_expiry(pool: PgPool) {
+        let database: Database = pool.into();
+        let config = WebsiteConfig::stub();
+        let provider = GoogleProvider::new(
+            OAuthCredentials::new("client-id", "client-secret"),
+            vec![Scope::new("openid".into())],
+        );
+
+        let query = |validation: &CallbackValidation| -> Vec<(String, String)> {
+            validation
+                .authorize_url
+                .query_pairs()
+                .into_owned()
+                .collect()
+        };
+        let validation = CallbackValidation::new(&config, &provider).unwrap();
+        let params = query(&validation);
+        assert!(params.contains(&("access_type".into(), "offline".into())));
+        assert!(!params.iter().any(|(k, _)| k == "prompt"));
+        assert!(params
+            .iter()
+            .any(|(k, v)| k == "redirect_uri" && v.ends_with("/oauth/google/callback")));
+
+        let options = AuthorizationOptions::default()
+            .prompt("consent")
+            .access_type("online")
+            .login_hint("user@example.com")
+            .scopes(&["drive", "calendar"]);
+        let params =
+            query(&CallbackValidation::with_options(&config, &provider, &options).unwrap());
+        for param in [
+            ("prompt", "consent"),
+            ("access_type", "online"),
+            ("login_hint", "user@example.com"),
+            ("scope", "openid drive calendar"),
+            ("include_granted_scopes", "true"),
+        ] {
+            assert!(params.contains(&(param.0.into(), param.1.into())));
+        }
+        assert_eq!(params.iter().filter(|(k, _)| k == "access_type").count(), 1);
+
+        let validation = validation.save(&database, "/next").await.unwrap();
+        let csrf_state = validation.csrf_state.secret().clone();
+        sqlx::query("UPDATE oauth_state SET created_at = created_at - INTERVAL '11 minutes';")
+            .execute(&*database)
+            .await
+            .unwrap();
+        let fresh = CallbackValidation::new(&config, &provider)
+            .unwrap()
+            .save(&database, "/next")
+            .await
+            .unwrap();
+        assert!(
+            CallbackValidation::validate(&config, csrf_state, &provider, &database)
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            CallbackValidation::delete_expired(&config, &database)
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(CallbackValidation::validate(
+            &config,
+            fresh.csrf_state.secret().clone(),
+            &provider,
+            &database
+        )
+        .await
+        .is_ok());
+    }
+
+    async fn login(provider: &OpenIdProvider) -> OauthTokenResponse {
+        OauthTokenResponse::login(
+            &WebsiteConfig::stub(),
+            provider,
+            "code".into(),
+            "pkce".into(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_jwks_rotation() {
+        let (issuer, mock) = mock_provider().await;
+        let jwks = JwksCache::new(&format!("{issuer}/jwks"));
+        let provider = OpenIdProvider::discover(
+            "mock",
+            &issuer,
+            OAuthCredentials::new("client-id", "client-secret"),
+        )
+        .await
+        .unwrap()
+        .with_id_token_verifier(IdTokenVerifier::new(&issuer, "client-id", jwks));
+
+        let token = login(&provider).await;
+        assert!(token.verify_id_token(&provider, None).await.is_ok());
+
+        // The new key is fetched, but not before the refresh interval
+        mock.lock().unwrap().key = 1;
+        let token = login(&provider).await;
+        assert!(token.verify_id_token(&provider, None).await.is_err());
+
+        let jwks = JwksCache::new(&format!("{issuer}/jwks"))
+            .with_min_refresh_interval(std::time::Duration::ZERO);
+        mock.lock().unwrap().key = 0;
+        let provider =
+            provider.with_id_token_verifier(IdTokenVerifier::new(&issuer, "client-id", jwks));
+        let token = login(&provider).await;
+        assert!(token.verify_id_token(&provider, None).await.is_ok());
+        mock.lock().unwrap().key = 1;
+        let token = login(&provider).await;
+        assert!(token.verify_id_token(&provider, None).await.is_ok());
+    }
+}
@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{PgConnection, PgExecutor};
+
+use crate::{
+    errors::AppError,
+    models::{EmailAccount, Identity, User},
+};
+
+use super::id_token::IdTokenClaims;
+
+/// The profile of the user as given by any [`super::OAuthProvider`]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OAuthUserInfo {
+    pub provider: String,
+    pub id: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub name: Option<String>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub picture: Option<String>,
+    /// The verified claims of the ID token, for OpenID Connect providers
+    pub id_token: Option<IdTokenClaims>,
+    /// The response of the provider, for the fields that aren't mapped
+    pub raw: Value,
+}
+
+impl OAuthUserInfo {
+    pub fn stub() -> Self {
+        Self {
+            provider: "google".to_string(),
+            id: "google-id-666".to_string(),
+            email: "OAuthUserInfo@example.com".to_string(),
+            email_verified: true,
+            name: Some("Test User".to_string()),
+            given_name: Some("Test".to_string()),
+            family_name: Some("User".to_string()),
+            picture: Some("http://example.com/picture.jpg".to_string()),
+            id_token: None,
+            raw: Value::Null,
+        }
+    }
+
+    /// Attach the claims of the ID token, which must be about the same user
+    pub fn with_id_token(mut self, claims: Option<IdTokenClaims>) -> Result<Self, AppError> {
+        if let Some(claims) = &claims {
+            if claims.sub != self.id {
+                return Err(AppError::Unauthorized);
+            }
+        }
+        self.id_token = claims;
+        Ok(self)
+    }
+
+    pub fn validate_email(self) -> Result<Self, AppError> {
+        if self.email_verified && !self.email.is_empty() {
+            Ok(self)
+        } else {
+            Err(AppError::custom_bad_request(&format!(
+                "You need to validate your email with {}",
+                self.provider
+            )))
+        }
+    }
+
+    /// The user this provider account logs in as. The first time, an account with the same
+    /// email is linked, but only when both the provider and this site verified the email:
+    /// otherwise anyone could register someone else's email and wait for them to show up.
+    pub async fn find_linked_user(&self, tx: &mut PgConnection) -> Result<Option<User>, AppError> {
+        if let Some(identity) = Identity::find(&self.provider, &self.id, &mut *tx).await? {
+            identity.touch(&mut *tx).await?;
+            return User::get_by_pk(identity.user_pk, &mut *tx).await.map(Some);
+        }
+        if !self.email_verified {
+            return Ok(None);
+        }
+        if EmailAccount::get_pending_by_email(&self.email, &mut *tx)
+            .await?
+            .is_some()
+        {
+            return Err(AppError::custom_bad_request(
+                "An account with this email exists, log in to link it from your settings",
+            ));
+        }
+        match EmailAccount::get_by_email(&self.email, &mut *tx).await? {
+            Some(account) => {
+                self.link(account.user.pk, &mut *tx).await?;
+                Ok(Some(account.user))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub async fn link<'e, E: PgExecutor<'e>>(
+        &self,
+        user_pk: i64,
+        executor: E,
+    ) -> Result<Identity, AppError> {
+        Identity::create(
+            user_pk,
+            &self.provider,
+            &self.id,
+            (!self.email.is_empty()).then_some(self.email.as_str()),
+            executor,
+        )
+        .await
+        .map_err(|e| match e {
+            AppError::UniqueViolation(_) => {
+                AppError::custom_bad_request("This account is already linked to another user")
+            }
+            e => e,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::database::Database;
+
+    use super::*;
+
+    fn user_info(id: &str, email: &str, email_verified: bool) -> OAuthUserInfo {
+        OAuthUserInfo {
+            id: id.into(),
+            email: email.into(),
+            email_verified,
+            ..OAuthUserInfo::stub()
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_find_linked_user(pool: PgPool) {
+        let database: Database = pool.into();
+        let user = User::create_active_default(&*database).await.unwrap();
+        EmailAccount::create_primary_active(user.clone(), "linked@example.com".into(), &*database)
+            .await
+            .unwrap();
+        let pending = User::create_active_default(&*database).await.unwrap();
+        let pending_pk = pending.pk;
+        EmailAccount::create_primary(pending, "pending@example.com".into(), None, &*database)
+            .await
+            .unwrap();
+        let mut tx = database.start_transaction().await.unwrap();
+
+        // Unverified by the provider, nothing is linked
+        let unverified = user_info("1", "linked@example.com", false);
+        assert!(unverified
+            .find_linked_user(&mut tx)
+            .await
+            .unwrap()
+            .is_none());
+
+        let verified = user_info("1", "linked@example.com", true);
+        let found = verified.find_linked_user(&mut tx).await.unwrap().unwrap();
+        assert_eq!(found.pk, user.pk);
+
+        // Once linked the provider account is enough, even with another email
+        let renamed = user_info("1", "renamed@example.com", false);
+        let found = renamed.find_linked_user(&mut tx).await.unwrap().unwrap();
+        assert_eq!(found.pk, user.pk);
+
+        let unvalidated = user_info("2", "pending@example.com", true);
+        assert!(unvalidated.find_linked_user(&mut tx).await.is_err());
+
+        let unknown = user_info("3", "unknown@example.com", true);
+        assert!(unknown.find_linked_user(&mut tx).await.unwrap().is_none());
+
+        assert!(verified.link(pending_pk, &mut *tx).await.is_err());
+    }
+}
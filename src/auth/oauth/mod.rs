@@ -0,0 +1,23 @@
+mod flow;
+mod id_token;
+mod infrastructure;
+#[cfg(test)]
+pub(crate) mod mock;
+mod providers;
+mod routes;
+mod service;
+mod vault;
+
+pub use flow::{
+    AuthorizationOptions, CallbackState, CallbackValidation, IdTokenFields, OAuthClient,
+    OauthTokenResponse,
+};
+pub use id_token::{IdTokenClaims, IdTokenVerifier, JwksCache};
+pub use infrastructure::OAuthUserInfo;
+pub use providers::{
+    get_json, map_oidc_claims, GitHubProvider, GoogleProvider, MicrosoftProvider, OAuthCredentials,
+    OAuthEndpoints, OAuthProvider, OAuthProviders, OpenIdProvider,
+};
+pub use routes::{oauth_return, start_oauth, start_oauth_link};
+pub use service::OAuthCallbackHook;
+pub use vault::{StoredToken, TokenVault};
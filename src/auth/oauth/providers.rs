@@ -0,0 +1,642 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use futures::future::BoxFuture;
+use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, RevocationUrl, Scope, TokenUrl};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    config::{ServiceConfig, WebsiteConfig},
+    errors::AppError,
+    http::HttpClient,
+    log_and_wrap_custom_internal,
+};
+
+use super::{
+    flow::OAuthClient,
+    id_token::{IdTokenVerifier, JwksCache, TENANT_ID_PLACEHOLDER},
+    infrastructure::OAuthUserInfo,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OAuthEndpoints {
+    #[serde(rename = "authorization_endpoint")]
+    pub authorization: String,
+    #[serde(rename = "token_endpoint")]
+    pub token: String,
+    #[serde(rename = "userinfo_endpoint")]
+    pub userinfo: String,
+    #[serde(rename = "revocation_endpoint")]
+    pub revocation: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OAuthCredentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl OAuthCredentials {
+    pub fn new(client_id: impl Into<String>, client_secret: impl Into<String>) -> Self {
+        Self {
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+        }
+    }
+}
+
+/// An OAuth2 authorization server the users can log in with. The provider is picked by its
+/// `name` from the `{provider}` segment of the `start_oauth` and `oauth_return` routes.
+pub trait OAuthProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn endpoints(&self) -> &OAuthEndpoints;
+
+    fn credentials(&self) -> &OAuthCredentials;
+
+    fn scopes(&self) -> Vec<Scope>;
+
+    fn extra_authorization_params(&self) -> Vec<(&str, &str)> {
+        Vec::new()
+    }
+
+    /// Turn the userinfo response into the common profile
+    fn map_profile(&self, profile: Value) -> Result<OAuthUserInfo, AppError>;
+
+    /// OpenID Connect providers verify the `id_token` of the token response with it
+    fn id_token_verifier(&self) -> Option<&IdTokenVerifier> {
+        None
+    }
+
+    /// Where the provider redirects back, it must match the route of `oauth_return`
+    fn callback_path(&self, config: &WebsiteConfig) -> String {
+        config.oauth_callback_path(self.name())
+    }
+
+    fn client(&self, config: &WebsiteConfig) -> Result<OAuthClient, AppError> {
+        let endpoints = self.endpoints();
+        let credentials = self.credentials();
+        let auth_url = AuthUrl::new(endpoints.authorization.clone())
+            .map_err(|_| AppError::custom_internal("OAuth: invalid authorization endpoint URL"))?;
+        let token_url = TokenUrl::new(endpoints.token.clone())
+            .map_err(|_| AppError::custom_internal("OAuth: invalid token endpoint URL"))?;
+
+        let mut client = OAuthClient::new(
+            ClientId::new(credentials.client_id.clone()),
+            Some(ClientSecret::new(credentials.client_secret.clone())),
+            auth_url,
+            Some(token_url),
+        )
+        .set_redirect_uri(
+            RedirectUrl::new(config.build_url(&self.callback_path(config)))
+                .map_err(|_| AppError::custom_internal("OAuth: invalid redirect URL"))?,
+        );
+        if let Some(revocation) = &endpoints.revocation {
+            client = client.set_revocation_uri(RevocationUrl::new(revocation.clone()).map_err(
+                |_| AppError::custom_internal("OAuth: invalid revocation endpoint URL"),
+            )?);
+        }
+        Ok(client)
+    }
+
+    fn user_info<'a>(
+        &'a self,
+        http_client: &'a HttpClient,
+        access_token: &'a str,
+    ) -> BoxFuture<'a, Result<OAuthUserInfo, AppError>> {
+        Box::pin(async move {
+            let profile = get_json(http_client, &self.endpoints().userinfo, access_token).await?;
+            self.map_profile(profile)
+        })
+    }
+
+    /// Revoke a token at the revocation endpoint (RFC 7009), when the provider has one. The
+    /// hint is `access_token` or `refresh_token`.
+    fn revoke_token<'a>(
+        &'a self,
+        http_client: &'a HttpClient,
+        token: &'a str,
+        hint: &'a str,
+    ) -> BoxFuture<'a, Result<(), AppError>> {
+        Box::pin(async move {
+            let Some(revocation) = &self.endpoints().revocation else {
+                return Ok(());
+            };
+            let credentials = self.credentials();
+            http_client
+                .post(revocation)
+                .form(&[
+                    ("token", token),
+                    ("token_type_hint", hint),
+                    ("client_id", &credentials.client_id),
+                    ("client_secret", &credentials.client_secret),
+                ])
+                .send()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?
+                .error_for_status()
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            Ok(())
+        })
+    }
+}
+
+pub async fn get_json(
+    http_client: &HttpClient,
+    url: &str,
+    access_token: &str,
+) -> Result<Value, AppError> {
+    http_client
+        .get(url)
+        .bearer_auth(access_token)
+        .header(reqwest::header::USER_AGENT, "stefn")
+        .send()
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?
+        .error_for_status()
+        .map_err(|e| log_and_wrap_custom_internal!(e))?
+        .json()
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+}
+
+fn string_claim(profile: &Value, claim: &str) -> Option<String> {
+    match profile.get(claim)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// Map the standard OpenID Connect claims
+pub fn map_oidc_claims(provider: &str, profile: Value) -> Result<OAuthUserInfo, AppError> {
+    let id = string_claim(&profile, "sub")
+        .ok_or_else(|| AppError::custom_internal("OAuth: the profile has no subject"))?;
+    let email = string_claim(&profile, "email")
+        .ok_or_else(|| AppError::custom_bad_request("Your account has no email"))?;
+    // Some providers send it as a string
+    let email_verified = match profile.get("email_verified") {
+        Some(Value::Bool(verified)) => *verified,
+        Some(Value::String(verified)) => verified == "true",
+        _ => false,
+    };
+    Ok(OAuthUserInfo {
+        provider: provider.to_owned(),
+        id,
+        email,
+        email_verified,
+        name: string_claim(&profile, "name"),
+        given_name: string_claim(&profile, "given_name"),
+        family_name: string_claim(&profile, "family_name"),
+        picture: string_claim(&profile, "picture"),
+        id_token: None,
+        raw: profile,
+    })
+}
+
+fn has_openid_scope(scopes: &[Scope]) -> bool {
+    scopes.iter().any(|s| s.as_str() == "openid")
+}
+
+pub struct GoogleProvider {
+    endpoints: OAuthEndpoints,
+    credentials: OAuthCredentials,
+    scopes: Vec<Scope>,
+    id_token_verifier: Option<IdTokenVerifier>,
+}
+
+impl GoogleProvider {
+    /// The ID token is only verified when the `openid` scope is requested
+    pub fn new(credentials: OAuthCredentials, scopes: Vec<Scope>) -> Self {
+        let id_token_verifier = has_openid_scope(&scopes).then(|| {
+            IdTokenVerifier::new(
+                "https://accounts.google.com",
+                &credentials.client_id,
+                JwksCache::new("https://www.googleapis.com/oauth2/v3/certs"),
+            )
+            .also_issued_by("accounts.google.com")
+        });
+        Self {
+            endpoints: OAuthEndpoints {
+                authorization: "https://accounts.google.com/o/oauth2/auth".into(),
+                token: "https://oauth2.googleapis.com/token".into(),
+                userinfo: "https://openidconnect.googleapis.com/v1/userinfo".into(),
+                revocation: Some("https://oauth2.googleapis.com/revoke".into()),
+            },
+            credentials,
+            scopes,
+            id_token_verifier,
+        }
+    }
+
+    pub fn from_config(config: &WebsiteConfig) -> Self {
+        Self::new(
+            OAuthCredentials::new(&config.google_client_id, &config.google_client_secret),
+            config.google_scopes(),
+        )
+    }
+}
+
+impl OAuthProvider for GoogleProvider {
+    fn name(&self) -> &str {
+        "google"
+    }
+
+    fn endpoints(&self) -> &OAuthEndpoints {
+        &self.endpoints
+    }
+
+    fn credentials(&self) -> &OAuthCredentials {
+        &self.credentials
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        self.scopes.clone()
+    }
+
+    fn extra_authorization_params(&self) -> Vec<(&str, &str)> {
+        // Refresh tokens, `prompt=consent` can be asked per call with `AuthorizationOptions`
+        vec![("access_type", "offline")]
+    }
+
+    fn id_token_verifier(&self) -> Option<&IdTokenVerifier> {
+        self.id_token_verifier.as_ref()
+    }
+
+    fn map_profile(&self, profile: Value) -> Result<OAuthUserInfo, AppError> {
+        map_oidc_claims(self.name(), profile)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GitHubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+pub struct GitHubProvider {
+    endpoints: OAuthEndpoints,
+    credentials: OAuthCredentials,
+}
+
+impl GitHubProvider {
+    const EMAILS_URL: &'static str = "https://api.github.com/user/emails";
+
+    pub fn new(credentials: OAuthCredentials) -> Self {
+        Self {
+            endpoints: OAuthEndpoints {
+                authorization: "https://github.com/login/oauth/authorize".into(),
+                token: "https://github.com/login/oauth/access_token".into(),
+                userinfo: "https://api.github.com/user".into(),
+                revocation: None,
+            },
+            credentials,
+        }
+    }
+}
+
+impl OAuthProvider for GitHubProvider {
+    fn name(&self) -> &str {
+        "github"
+    }
+
+    fn endpoints(&self) -> &OAuthEndpoints {
+        &self.endpoints
+    }
+
+    fn credentials(&self) -> &OAuthCredentials {
+        &self.credentials
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        vec![
+            Scope::new("read:user".into()),
+            Scope::new("user:email".into()),
+        ]
+    }
+
+    fn map_profile(&self, profile: Value) -> Result<OAuthUserInfo, AppError> {
+        let id = string_claim(&profile, "id")
+            .ok_or_else(|| AppError::custom_internal("OAuth: the profile has no id"))?;
+        Ok(OAuthUserInfo {
+            provider: self.name().to_owned(),
+            id,
+            email: string_claim(&profile, "email").unwrap_or_default(),
+            // The public email of the profile isn't necessarily verified
+            email_verified: false,
+            name: string_claim(&profile, "name"),
+            given_name: None,
+            family_name: None,
+            picture: string_claim(&profile, "avatar_url"),
+            id_token: None,
+            raw: profile,
+        })
+    }
+
+    /// The profile only has the public email, the verified primary one comes from another
+    /// endpoint.
+    fn user_info<'a>(
+        &'a self,
+        http_client: &'a HttpClient,
+        access_token: &'a str,
+    ) -> BoxFuture<'a, Result<OAuthUserInfo, AppError>> {
+        Box::pin(async move {
+            let mut user_info = self.map_profile(
+                get_json(http_client, &self.endpoints.userinfo, access_token).await?,
+            )?;
+            let emails: Vec<GitHubEmail> = serde_json::from_value(
+                get_json(http_client, Self::EMAILS_URL, access_token).await?,
+            )
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            if let Some(primary) = emails.into_iter().find(|e| e.primary) {
+                user_info.email = primary.email;
+                user_info.email_verified = primary.verified;
+            }
+            Ok(user_info)
+        })
+    }
+}
+
+/// Microsoft identity platform. Its userinfo doesn't say whether the email is verified, and in
+/// multi-tenant apps anyone can set any email, so emails are only trusted with
+/// [`MicrosoftProvider::trust_emails`], for single-tenant apps. The ID token is verified against
+/// the keys and the issuer of the tenant.
+pub struct MicrosoftProvider {
+    endpoints: OAuthEndpoints,
+    credentials: OAuthCredentials,
+    id_token_verifier: IdTokenVerifier,
+    trust_emails: bool,
+}
+
+impl MicrosoftProvider {
+    /// The tenant of the personal accounts, the issuer of the `consumers` tokens
+    const CONSUMERS_TENANT_ID: &'static str = "9188040d-6c67-4c5b-b112-36a304b66dad";
+
+    /// `tenant` is the directory id, or `common`, `organizations` or `consumers`
+    pub fn new(tenant: &str, credentials: OAuthCredentials) -> Self {
+        let base = format!("https://login.microsoftonline.com/{}/oauth2/v2.0", tenant);
+        let issuer_tenant = match tenant {
+            "common" | "organizations" => TENANT_ID_PLACEHOLDER,
+            "consumers" => Self::CONSUMERS_TENANT_ID,
+            tenant => tenant,
+        };
+        let id_token_verifier = IdTokenVerifier::new(
+            &format!("https://login.microsoftonline.com/{}/v2.0", issuer_tenant),
+            &credentials.client_id,
+            JwksCache::new(&format!(
+                "https://login.microsoftonline.com/{}/discovery/v2.0/keys",
+                tenant
+            )),
+        );
+        Self {
+            endpoints: OAuthEndpoints {
+                authorization: format!("{}/authorize", base),
+                token: format!("{}/token", base),
+                userinfo: "https://graph.microsoft.com/oidc/userinfo".into(),
+                revocation: None,
+            },
+            credentials,
+            id_token_verifier,
+            trust_emails: false,
+        }
+    }
+
+    pub fn trust_emails(mut self) -> Self {
+        self.trust_emails = true;
+        self
+    }
+}
+
+impl OAuthProvider for MicrosoftProvider {
+    fn name(&self) -> &str {
+        "microsoft"
+    }
+
+    fn endpoints(&self) -> &OAuthEndpoints {
+        &self.endpoints
+    }
+
+    fn credentials(&self) -> &OAuthCredentials {
+        &self.credentials
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        ["openid", "email", "profile", "offline_access"]
+            .into_iter()
+            .map(|s| Scope::new(s.into()))
+            .collect()
+    }
+
+    fn id_token_verifier(&self) -> Option<&IdTokenVerifier> {
+        Some(&self.id_token_verifier)
+    }
+
+    fn map_profile(&self, profile: Value) -> Result<OAuthUserInfo, AppError> {
+        let mut user_info = map_oidc_claims(self.name(), profile)?;
+        user_info.email_verified = self.trust_emails;
+        Ok(user_info)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscoveryDocument {
+    issuer: String,
+    jwks_uri: String,
+    #[serde(flatten)]
+    endpoints: OAuthEndpoints,
+}
+
+/// Any OpenID Connect provider, configured from its discovery document
+pub struct OpenIdProvider {
+    name: String,
+    issuer: String,
+    endpoints: OAuthEndpoints,
+    credentials: OAuthCredentials,
+    scopes: Vec<Scope>,
+    id_token_verifier: IdTokenVerifier,
+}
+
+impl OpenIdProvider {
+    pub async fn discover(
+        name: &str,
+        issuer: &str,
+        credentials: OAuthCredentials,
+    ) -> Result<Self, AppError> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let document: DiscoveryDocument = reqwest::get(url)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?
+            .error_for_status()
+            .map_err(|e| log_and_wrap_custom_internal!(e))?
+            .json()
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        if document.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+            return Err(AppError::custom_internal(
+                "OAuth: the discovery document is for another issuer",
+            ));
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            id_token_verifier: IdTokenVerifier::new(
+                &document.issuer,
+                &credentials.client_id,
+                JwksCache::new(&document.jwks_uri),
+            ),
+            issuer: document.issuer,
+            endpoints: document.endpoints,
+            credentials,
+            scopes: ["openid", "email", "profile"]
+                .into_iter()
+                .map(|s| Scope::new(s.into()))
+                .collect(),
+        })
+    }
+
+    pub fn with_scopes(mut self, scopes: Vec<Scope>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    /// Replace the verifier, for example to pin the keys or change the refresh interval
+    pub fn with_id_token_verifier(mut self, verifier: IdTokenVerifier) -> Self {
+        self.id_token_verifier = verifier;
+        self
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+}
+
+impl OAuthProvider for OpenIdProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn endpoints(&self) -> &OAuthEndpoints {
+        &self.endpoints
+    }
+
+    fn credentials(&self) -> &OAuthCredentials {
+        &self.credentials
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        self.scopes.clone()
+    }
+
+    fn map_profile(&self, profile: Value) -> Result<OAuthUserInfo, AppError> {
+        map_oidc_claims(&self.name, profile)
+    }
+
+    fn id_token_verifier(&self) -> Option<&IdTokenVerifier> {
+        has_openid_scope(&self.scopes).then_some(&self.id_token_verifier)
+    }
+}
+
+/// The providers of the app by name. It is shared by the clones of the state, so providers can
+/// be added from the router factory.
+#[derive(Clone, Default)]
+pub struct OAuthProviders(Arc<RwLock<HashMap<String, Arc<dyn OAuthProvider>>>>);
+
+impl OAuthProviders {
+    // Note: a panic while holding the lock can't leave the map half updated, so a poisoned lock
+    // is still used
+    pub fn add(&self, provider: impl OAuthProvider + 'static) -> &Self {
+        self.0
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(provider.name().to_owned(), Arc::new(provider));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<dyn OAuthProvider>, AppError> {
+        self.0
+            .read()
+            .map_err(|e| log_and_wrap_custom_internal!(e))?
+            .get(name)
+            .cloned()
+            .ok_or(AppError::DoesNotExist)
+    }
+}
+
+impl fmt::Debug for OAuthProviders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let providers = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        f.debug_list().entries(providers.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_map_oidc_claims() {
+        let user_info = map_oidc_claims(
+            "oidc",
+            json!({"sub": "1", "email": "a@example.com", "email_verified": "true"}),
+        )
+        .unwrap();
+        assert_eq!(user_info.id, "1");
+        assert!(user_info.email_verified);
+        assert!(user_info.validate_email().is_ok());
+
+        let user_info =
+            map_oidc_claims("oidc", json!({"sub": "1", "email": "a@example.com"})).unwrap();
+        assert!(user_info.validate_email().is_err());
+
+        assert!(map_oidc_claims("oidc", json!({"email": "a@example.com"})).is_err());
+    }
+
+    #[test]
+    fn test_provider_profiles() {
+        let credentials = OAuthCredentials::new("id", "secret");
+
+        let github = GitHubProvider::new(credentials.clone());
+        let user_info = github
+            .map_profile(json!({"id": 42, "email": "a@example.com", "avatar_url": "pic"}))
+            .unwrap();
+        assert_eq!(user_info.id, "42");
+        assert!(!user_info.email_verified);
+        assert_eq!(user_info.picture.as_deref(), Some("pic"));
+
+        let profile = json!({"sub": "abc", "email": "a@example.com"});
+        let microsoft = MicrosoftProvider::new("common", credentials.clone());
+        assert!(
+            !microsoft
+                .map_profile(profile.clone())
+                .unwrap()
+                .email_verified
+        );
+        let microsoft = MicrosoftProvider::new("tenant", credentials).trust_emails();
+        assert!(microsoft.map_profile(profile).unwrap().email_verified);
+        assert_eq!(
+            microsoft.endpoints().token,
+            "https://login.microsoftonline.com/tenant/oauth2/v2.0/token"
+        );
+        assert!(microsoft.id_token_verifier().is_some());
+    }
+
+    #[test]
+    fn test_providers_registry() {
+        let providers = OAuthProviders::default();
+        providers.add(GitHubProvider::new(OAuthCredentials::new("id", "secret")));
+        assert_eq!(providers.get("github").unwrap().name(), "github");
+        assert!(matches!(
+            providers.get("google"),
+            Err(AppError::DoesNotExist)
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+use axum::{
+    extract::{Extension, Path, Query, State},
+    response::Redirect,
+};
+use serde::Deserialize;
+
+use crate::{errors::AppError, sessions::Session, state::WebsiteState};
+
+use super::{
+    flow::{AuthorizationOptions, CallbackValidation, OauthTokenResponse},
+    service::OAuthCallbackHook,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct LoginParam {
+    next: Option<String>,
+    #[serde(flatten)]
+    options: AuthorizationOptions,
+}
+
+pub async fn start_oauth(
+    state: State<WebsiteState>,
+    Extension(session): Extension<Session>,
+    Path(provider): Path<String>,
+    Query(params): Query<LoginParam>,
+) -> Result<Redirect, AppError> {
+    let config = state.config();
+    let database = state.database();
+    let provider = state.oauth_providers().get(&provider)?;
+    let provider = provider.as_ref();
+    let return_url = config.safe_redirect(params.next.as_deref());
+
+    if session.is_authenticated(database).await? {
+        return Ok(Redirect::to(return_url));
+    };
+
+    let authorize_url = CallbackValidation::with_options(config, provider, &params.options)?
+        .save(database, return_url)
+        .await?
+        .authorize_url();
+
+    Ok(Redirect::to(authorize_url.as_str()))
+}
+
+/// Start the flow to add a provider as a login method of the logged user, from the account
+/// settings
+pub async fn start_oauth_link(
+    state: State<WebsiteState>,
+    Extension(session): Extension<Session>,
+    Path(provider): Path<String>,
+    Query(params): Query<LoginParam>,
+) -> Result<Redirect, AppError> {
+    let config = state.config();
+    let database = state.database();
+    let provider = state.oauth_providers().get(&provider)?;
+    let provider = provider.as_ref();
+    let return_url = config.safe_redirect(params.next.as_deref());
+
+    session.forbid_impersonation().await?;
+    if !session.is_authenticated(database).await? {
+        return Err(AppError::Unauthorized);
+    }
+    let user_pk = session.user_pk().await.ok_or(AppError::Unauthorized)?;
+
+    let authorize_url = CallbackValidation::with_options(config, provider, &params.options)?
+        .linking(user_pk)
+        .save(database, return_url)
+        .await?
+        .authorize_url();
+
+    Ok(Redirect::to(authorize_url.as_str()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OauthParams {
+    pub state: String,
+    pub code: String,
+}
+
+pub async fn oauth_return<T: OAuthCallbackHook + Send>(
+    state: State<WebsiteState>,
+    Extension(session): Extension<Session>,
+    Path(provider): Path<String>,
+    Query(params): Query<OauthParams>,
+) -> Result<Redirect, AppError> {
+    let config = state.config();
+    let database = state.database();
+    let provider = state.oauth_providers().get(&provider)?;
+    let provider = provider.as_ref();
+
+    let callback = CallbackValidation::validate(config, params.state, provider, database).await?;
+
+    let token_response =
+        OauthTokenResponse::login(config, provider, params.code, callback.pkce_code_verifier)
+            .await?;
+    let id_token = token_response
+        .verify_id_token(provider, callback.nonce.as_deref())
+        .await?;
+
+    let user_info = provider
+        .user_info(state.http_client(), token_response.access_token())
+        .await?
+        .with_id_token(id_token)?;
+
+    match callback.link_user_pk {
+        Some(user_pk) => {
+            // The session must still be the one that started the linking
+            if session.user_pk().await != Some(user_pk) {
+                return Err(AppError::Unauthorized);
+            }
+            let identity = user_info.link(user_pk, &**database).await?;
+            state
+                .token_vault()
+                .store(identity.pk, &token_response, &**database)
+                .await?;
+        }
+        None => T::run(&token_response, user_info, session, &state).await?,
+    }
+
+    Ok(Redirect::to(&callback.return_url))
+}
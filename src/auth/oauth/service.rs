@@ -0,0 +1,218 @@
+use serde_json::json;
+
+use crate::{
+    audit::{self, actions},
+    database::Database,
+    errors::AppError,
+    log_and_wrap_custom_internal,
+    models::{User, UserModel},
+    sessions::Session,
+    state::WebsiteState,
+};
+
+use super::{flow::OauthTokenResponse, infrastructure::OAuthUserInfo};
+
+pub trait OAuthCallbackHook {
+    type User: UserModel;
+
+    fn find_user(
+        user_info: &OAuthUserInfo,
+        database: &Database,
+    ) -> impl std::future::Future<Output = Result<Option<Self::User>, AppError>> + Send;
+
+    fn create_user(
+        state: &WebsiteState,
+        token_response: &OauthTokenResponse,
+        user_info: OAuthUserInfo,
+    ) -> impl std::future::Future<Output = Result<Self::User, AppError>> + Send;
+
+    fn user_found_hook(
+        database: &Database,
+        token_response: &OauthTokenResponse,
+        user: Self::User,
+    ) -> impl std::future::Future<Output = Result<Self::User, AppError>> + Send;
+
+    /// Log in with the linked user, or the app's `find_user`, or a new one, linking the provider
+    /// account to it for the next time
+    fn run(
+        token_response: &OauthTokenResponse,
+        user_info: OAuthUserInfo,
+        session: Session,
+        state: &WebsiteState,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + Send {
+        async move {
+            let user_info = user_info.validate_email()?;
+            let database = state.database();
+
+            let mut tx = database.start_transaction().await?;
+            let linked = user_info.find_linked_user(&mut tx).await?;
+            // Logging in cancels a self-service deactivation or a pending deletion
+            if let Some(user) = &linked {
+                user.reactivate_self(&mut *tx).await?;
+            }
+            tx.commit()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+
+            let user = match linked {
+                Some(user) => {
+                    let user = Self::User::load(user, database).await?;
+                    Self::user_found_hook(database, token_response, user).await?
+                }
+                None => match Self::find_user(&user_info, database).await? {
+                    Some(user) => {
+                        User::get_by_pk(user.pk(), &**database)
+                            .await?
+                            .reactivate_self(&**database)
+                            .await?;
+                        user_info.link(user.pk(), &**database).await?;
+                        Self::user_found_hook(database, token_response, user).await?
+                    }
+                    None => {
+                        let user =
+                            Self::create_user(state, token_response, user_info.clone()).await?;
+                        user_info.link(user.pk(), &**database).await?;
+                        user
+                    }
+                },
+            };
+            state
+                .token_vault()
+                .store_for(&user_info, token_response, database)
+                .await?;
+            audit::record(
+                Some(user.pk()),
+                actions::LOGIN,
+                None,
+                json!({"method": "oauth", "provider": user_info.provider}),
+                database,
+            )
+            .await?;
+
+            Self::update_session(state, session, user).await
+        }
+    }
+
+    fn update_session(
+        state: &WebsiteState,
+        session: Session,
+        user: Self::User,
+    ) -> impl std::future::Future<Output = Result<(), AppError>> + Send {
+        async move {
+            state
+                .sessions()
+                .reuse_current_as_new_one(&session, user.to_session(), &state.config().session_key)
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{
+        auth::test_utils::TestSessions,
+        models::{Identity, UserSession},
+    };
+
+    use super::*;
+
+    struct Hook;
+
+    impl OAuthCallbackHook for Hook {
+        type User = User;
+
+        async fn find_user(
+            _user_info: &OAuthUserInfo,
+            _database: &Database,
+        ) -> Result<Option<User>, AppError> {
+            Ok(None)
+        }
+
+        async fn create_user(
+            _state: &WebsiteState,
+            _token_response: &OauthTokenResponse,
+            _user_info: OAuthUserInfo,
+        ) -> Result<User, AppError> {
+            Err(AppError::DoesNotExist)
+        }
+
+        async fn user_found_hook(
+            _database: &Database,
+            _token_response: &OauthTokenResponse,
+            user: User,
+        ) -> Result<User, AppError> {
+            Ok(user)
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_login_reactivates(pool: PgPool) {
+        let database: Database = pool.into();
+        let sessions = TestSessions::new().await;
+        let state = sessions.website(database.clone());
+        let user_info = OAuthUserInfo::stub();
+        let user = User::create_active("", &*database).await.unwrap();
+        Identity::create(
+            user.pk,
+            &user_info.provider,
+            &user_info.id,
+            None,
+            &*database,
+        )
+        .await
+        .unwrap();
+        user.schedule_deletion(-1, &*database).await.unwrap();
+        assert!(!user.is_authenticated(&database).await.unwrap());
+
+        let session = sessions.create(UserSession::default()).await;
+        Hook::run(
+            &OauthTokenResponse::stub(),
+            user_info,
+            session.clone(),
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(session.user().await.pk(), Some(user.pk));
+        assert!(user.is_authenticated(&database).await.unwrap());
+        assert!(User::scheduled_for_deletion(&*database)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_login_deactivated_by_admin(pool: PgPool) {
+        let database: Database = pool.into();
+        let sessions = TestSessions::new().await;
+        let state = sessions.website(database.clone());
+        let user_info = OAuthUserInfo::stub();
+        let user = User::create_active("", &*database).await.unwrap();
+        Identity::create(
+            user.pk,
+            &user_info.provider,
+            &user_info.id,
+            None,
+            &*database,
+        )
+        .await
+        .unwrap();
+        user.deactivate(true, &*database).await.unwrap();
+
+        let session = sessions.create(UserSession::default()).await;
+        assert!(matches!(
+            Hook::run(
+                &OauthTokenResponse::stub(),
+                user_info,
+                session.clone(),
+                &state
+            )
+            .await,
+            Err(AppError::AccountDeactivated)
+        ));
+        assert_eq!(session.user().await.pk(), None);
+        assert!(!user.is_authenticated(&database).await.unwrap());
+    }
+}
@@ -0,0 +1,559 @@
+use std::fmt;
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{FromRow, PgExecutor};
+
+use crate::{
+    config::WebsiteConfig, database::Database, errors::AppError, http::HttpClient,
+    log_and_wrap_custom_internal, models::Identity, state::WebsiteState,
+};
+
+use super::{flow::OauthTokenResponse, infrastructure::OAuthUserInfo, providers::OAuthProviders};
+
+const NONCE_LENGTH: usize = 12;
+
+/// The provider tokens of an identity, still encrypted
+#[derive(Debug, FromRow)]
+pub struct StoredToken {
+    pub identity_pk: i64,
+    pub provider: String,
+    access_token: Vec<u8>,
+    refresh_token: Option<Vec<u8>>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl StoredToken {
+    pub async fn find<'e, E: PgExecutor<'e>>(
+        identity_pk: i64,
+        executor: E,
+    ) -> Result<Option<Self>, AppError> {
+        sqlx::query_as(
+            "SELECT oauth_tokens.identity_pk, user_identities.provider, oauth_tokens.access_token, oauth_tokens.refresh_token, oauth_tokens.expires_at
+            FROM oauth_tokens
+            INNER JOIN user_identities ON user_identities.pk = oauth_tokens.identity_pk
+            WHERE oauth_tokens.identity_pk = $1;",
+        )
+        .bind(identity_pk)
+        .fetch_optional(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    fn expires_within(&self, margin: Duration) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now().naive_utc() + margin)
+    }
+}
+
+/// Encrypts the access and refresh tokens of the providers at rest with AES-256-GCM. The
+/// identity is authenticated with them, so a token can't be moved to another identity's row.
+#[derive(Clone)]
+pub struct TokenVault(Aes256Gcm);
+
+impl TokenVault {
+    pub fn new(key: &[u8]) -> Result<Self, AppError> {
+        Aes256Gcm::new_from_slice(key)
+            .map(Self)
+            .map_err(|_| AppError::custom_internal("The token key must have 32 bytes"))
+    }
+
+    fn encrypt(&self, identity_pk: i64, token: &str) -> Result<Vec<u8>, AppError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: token.as_bytes(),
+                    aad: &identity_pk.to_be_bytes(),
+                },
+            )
+            .map_err(|_| AppError::custom_internal("Cannot encrypt the token"))?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn decrypt(&self, identity_pk: i64, sealed: &[u8]) -> Result<String, AppError> {
+        if sealed.len() < NONCE_LENGTH {
+            return Err(AppError::custom_internal("The stored token is truncated"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let token = self
+            .0
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &identity_pk.to_be_bytes(),
+                },
+            )
+            .map_err(|_| AppError::custom_internal("Cannot decrypt the token"))?;
+        String::from_utf8(token).map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    async fn save<'e, E: PgExecutor<'e>>(
+        &self,
+        identity_pk: i64,
+        access_token: &str,
+        refresh_token: Option<&str>,
+        expires_at: Option<NaiveDateTime>,
+        executor: E,
+    ) -> Result<(), AppError> {
+        let refresh_token = refresh_token
+            .map(|token| self.encrypt(identity_pk, token))
+            .transpose()?;
+        // Refresh responses usually omit the refresh token, the previous one stays valid
+        sqlx::query(
+            "INSERT INTO oauth_tokens (identity_pk, access_token, refresh_token, expires_at) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (identity_pk) DO UPDATE SET access_token = EXCLUDED.access_token, refresh_token = COALESCE(EXCLUDED.refresh_token, oauth_tokens.refresh_token), expires_at = EXCLUDED.expires_at, updated_at = CURRENT_TIMESTAMP;",
+        )
+        .bind(identity_pk)
+        .bind(self.encrypt(identity_pk, access_token)?)
+        .bind(refresh_token)
+        .bind(expires_at)
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(())
+    }
+
+    pub async fn store<'e, E: PgExecutor<'e>>(
+        &self,
+        identity_pk: i64,
+        token_response: &OauthTokenResponse,
+        executor: E,
+    ) -> Result<(), AppError> {
+        let expires_at = token_response
+            .expires_in()
+            .map(|seconds| Utc::now().naive_utc() + Duration::seconds(seconds));
+        self.save(
+            identity_pk,
+            token_response.access_token(),
+            token_response.refresh_token().map(String::as_str),
+            expires_at,
+            executor,
+        )
+        .await
+    }
+
+    /// Store the tokens of the identity the user logged in or linked with
+    pub async fn store_for(
+        &self,
+        user_info: &OAuthUserInfo,
+        token_response: &OauthTokenResponse,
+        database: &Database,
+    ) -> Result<(), AppError> {
+        let identity = Identity::find(&user_info.provider, &user_info.id, &**database)
+            .await?
+            .ok_or(AppError::DoesNotExist)?;
+        self.store(identity.pk, token_response, &**database).await
+    }
+
+    /// A valid access token of the identity, refreshed when it expires within a minute
+    pub async fn access_token(
+        &self,
+        config: &WebsiteConfig,
+        providers: &OAuthProviders,
+        database: &Database,
+        identity_pk: i64,
+    ) -> Result<String, AppError> {
+        let stored = StoredToken::find(identity_pk, &**database)
+            .await?
+            .ok_or(AppError::DoesNotExist)?;
+        if stored.expires_within(Duration::minutes(1)) {
+            self.refresh(config, providers, database, &stored).await
+        } else {
+            self.decrypt(identity_pk, &stored.access_token)
+        }
+    }
+
+    /// A valid access token of the user for the provider
+    pub async fn access_token_for(
+        &self,
+        config: &WebsiteConfig,
+        providers: &OAuthProviders,
+        database: &Database,
+        user_pk: i64,
+        provider: &str,
+    ) -> Result<String, AppError> {
+        let identity = Identity::list_for_user(user_pk, &**database)
+            .await?
+            .into_iter()
+            .find(|identity| identity.provider == provider)
+            .ok_or(AppError::DoesNotExist)?;
+        self.access_token(config, providers, database, identity.pk)
+            .await
+    }
+
+    async fn refresh(
+        &self,
+        config: &WebsiteConfig,
+        providers: &OAuthProviders,
+        database: &Database,
+        stored: &StoredToken,
+    ) -> Result<String, AppError> {
+        // Without a refresh token the user has to log in again
+        let refresh_token = stored
+            .refresh_token
+            .as_ref()
+            .ok_or(AppError::Unauthorized)?;
+        let provider = providers.get(&stored.provider)?;
+        let token_response = OauthTokenResponse::new_token(
+            config,
+            provider.as_ref(),
+            self.decrypt(stored.identity_pk, refresh_token)?,
+        )
+        .await?;
+        self.store(stored.identity_pk, &token_response, &**database)
+            .await?;
+        Ok(token_response.access_token().to_owned())
+    }
+
+    /// Refresh the tokens expiring within the margin, returns how many were refreshed
+    pub async fn refresh_expiring(
+        &self,
+        config: &WebsiteConfig,
+        providers: &OAuthProviders,
+        database: &Database,
+        margin: Duration,
+    ) -> Result<usize, AppError> {
+        let expiring: Vec<StoredToken> = sqlx::query_as(
+            "SELECT oauth_tokens.identity_pk, user_identities.provider, oauth_tokens.access_token, oauth_tokens.refresh_token, oauth_tokens.expires_at
+            FROM oauth_tokens
+            INNER JOIN user_identities ON user_identities.pk = oauth_tokens.identity_pk
+            WHERE oauth_tokens.refresh_token IS NOT NULL AND oauth_tokens.expires_at <= $1;",
+        )
+        .bind(Utc::now().naive_utc() + margin)
+        .fetch_all(&**database)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+
+        let mut refreshed = 0;
+        for stored in expiring {
+            match self.refresh(config, providers, database, &stored).await {
+                Ok(_) => refreshed += 1,
+                Err(e) => tracing::warn!(
+                    "Cannot refresh the {} token of identity {}: {:?}",
+                    stored.provider,
+                    stored.identity_pk,
+                    e
+                ),
+            }
+        }
+        Ok(refreshed)
+    }
+
+    /// Move the plaintext tokens of the legacy `google_profiles` into the vault
+    pub async fn import_google_profiles(&self, database: &Database) -> Result<usize, AppError> {
+        let profiles: Vec<(i64, String, Option<String>, Option<NaiveDateTime>)> = sqlx::query_as(
+            "SELECT user_identities.pk, google_profiles.access_token, google_profiles.refresh_token, google_profiles.updated_at + make_interval(secs => google_profiles.expires_in)
+            FROM google_profiles
+            INNER JOIN user_identities ON user_identities.provider = 'google' AND user_identities.subject = google_profiles.id
+            WHERE google_profiles.access_token IS NOT NULL;",
+        )
+        .fetch_all(&**database)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+
+        let imported = profiles.len();
+        for (identity_pk, access_token, refresh_token, expires_at) in profiles {
+            let mut tx = database.start_transaction().await?;
+            self.save(
+                identity_pk,
+                &access_token,
+                refresh_token.as_deref(),
+                expires_at,
+                &mut *tx,
+            )
+            .await?;
+            sqlx::query(
+                "UPDATE google_profiles SET access_token = NULL, refresh_token = NULL, expires_in = NULL
+                WHERE id = (SELECT subject FROM user_identities WHERE pk = $1);",
+            )
+            .bind(identity_pk)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            tx.commit()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        }
+        Ok(imported)
+    }
+
+    /// Revoke the tokens at the provider. The refresh token is preferred, revoking it also
+    /// revokes the access tokens issued with it.
+    pub async fn revoke_stored(
+        &self,
+        http_client: &HttpClient,
+        providers: &OAuthProviders,
+        stored: &StoredToken,
+    ) -> Result<(), AppError> {
+        let provider = providers.get(&stored.provider)?;
+        match &stored.refresh_token {
+            Some(refresh_token) => {
+                let token = self.decrypt(stored.identity_pk, refresh_token)?;
+                provider
+                    .revoke_token(http_client, &token, "refresh_token")
+                    .await
+            }
+            None => {
+                let token = self.decrypt(stored.identity_pk, &stored.access_token)?;
+                provider
+                    .revoke_token(http_client, &token, "access_token")
+                    .await
+            }
+        }
+    }
+
+    /// Revoke and delete the tokens of the identity. The revocation is best effort, the tokens
+    /// are deleted even when the provider fails.
+    pub async fn revoke(
+        &self,
+        http_client: &HttpClient,
+        providers: &OAuthProviders,
+        database: &Database,
+        identity_pk: i64,
+    ) -> Result<(), AppError> {
+        let Some(stored) = StoredToken::find(identity_pk, &**database).await? else {
+            return Ok(());
+        };
+        if let Err(e) = self.revoke_stored(http_client, providers, &stored).await {
+            tracing::warn!(
+                "Cannot revoke the {} token of identity {}: {:?}",
+                stored.provider,
+                identity_pk,
+                e
+            );
+        }
+        sqlx::query("DELETE FROM oauth_tokens WHERE identity_pk = $1;")
+            .bind(identity_pk)
+            .execute(&**database)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(())
+    }
+
+    /// Revoke the tokens of every identity of the user
+    pub async fn revoke_user(
+        &self,
+        http_client: &HttpClient,
+        providers: &OAuthProviders,
+        database: &Database,
+        user_pk: i64,
+    ) -> Result<(), AppError> {
+        for identity in Identity::list_for_user(user_pk, &**database).await? {
+            self.revoke(http_client, providers, database, identity.pk)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Import the legacy tokens, then refresh the expiring ones every
+    /// `oauth_token_refresh_interval` minutes, forever
+    pub async fn run_refresher(state: WebsiteState) {
+        let vault = state.token_vault();
+        if let Err(e) = vault.import_google_profiles(state.database()).await {
+            tracing::error!("Cannot import the google profiles tokens: {:?}", e);
+        }
+
+        let every =
+            std::time::Duration::from_secs(60 * state.config().oauth_token_refresh_interval.max(1));
+        // Tokens expiring before the next run are refreshed now
+        let margin = Duration::from_std(every * 2).unwrap_or(Duration::hours(1));
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(e) = vault
+                .refresh_expiring(
+                    state.config(),
+                    state.oauth_providers(),
+                    state.database(),
+                    margin,
+                )
+                .await
+            {
+                tracing::error!("Cannot refresh the oauth tokens: {:?}", e);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for TokenVault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TokenVault")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{
+        auth::{OAuthCredentials, OpenIdProvider},
+        config::ServiceConfig,
+        models::User,
+    };
+
+    use super::{super::mock::mock_provider, *};
+
+    fn vault() -> TokenVault {
+        TokenVault::new(&[7; 32]).unwrap()
+    }
+
+    #[test]
+    fn test_token_encryption() {
+        let vault = vault();
+        assert!(TokenVault::new(&[7; 16]).is_err());
+
+        let sealed = vault.encrypt(1, "secret-token").unwrap();
+        assert_ne!(sealed, vault.encrypt(1, "secret-token").unwrap());
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(vault.decrypt(1, &sealed).unwrap(), "secret-token");
+
+        assert!(vault.decrypt(2, &sealed).is_err());
+        assert!(TokenVault::new(&[8; 32])
+            .unwrap()
+            .decrypt(1, &sealed)
+            .is_err());
+        assert!(vault.decrypt(1, &sealed[..8]).is_err());
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_token_refresh_and_revocation(pool: PgPool) {
+        let database: Database = pool.into();
+        let config = WebsiteConfig::stub();
+        let vault = vault();
+        let (issuer, mock) = mock_provider().await;
+        let providers = OAuthProviders::default();
+        providers.add(
+            OpenIdProvider::discover(
+                "mock",
+                &issuer,
+                OAuthCredentials::new("client-id", "client-secret"),
+            )
+            .await
+            .unwrap(),
+        );
+        let user = User::create_active("", &*database).await.unwrap();
+        let identity = Identity::create(user.pk, "mock", "mock-user-1", None, &*database)
+            .await
+            .unwrap();
+
+        vault
+            .store(identity.pk, &OauthTokenResponse::stub(), &*database)
+            .await
+            .unwrap();
+        assert_eq!(
+            vault
+                .access_token_for(&config, &providers, &database, user.pk, "mock")
+                .await
+                .unwrap(),
+            "access_token"
+        );
+        assert_eq!(mock.lock().unwrap().refreshed, 0);
+
+        // Expiring soon, the refresh token is kept since the response has none
+        let expire = |pk: i64| {
+            sqlx::query(
+                "UPDATE oauth_tokens SET expires_at = CURRENT_TIMESTAMP WHERE identity_pk = $1;",
+            )
+            .bind(pk)
+        };
+        expire(identity.pk).execute(&*database).await.unwrap();
+        assert_eq!(
+            vault
+                .access_token(&config, &providers, &database, identity.pk)
+                .await
+                .unwrap(),
+            "mock-access-token-1"
+        );
+        assert_eq!(
+            vault
+                .refresh_expiring(&config, &providers, &database, Duration::minutes(1))
+                .await
+                .unwrap(),
+            0
+        );
+        expire(identity.pk).execute(&*database).await.unwrap();
+        assert_eq!(
+            vault
+                .refresh_expiring(&config, &providers, &database, Duration::minutes(1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            vault
+                .access_token(&config, &providers, &database, identity.pk)
+                .await
+                .unwrap(),
+            "mock-access-token-2"
+        );
+
+        vault
+            .revoke_user(&HttpClient::new(), &providers, &database, user.pk)
+            .await
+            .unwrap();
+        assert_eq!(mock.lock().unwrap().revoked, vec!["refresh_token"]);
+        assert!(StoredToken::find(identity.pk, &*database)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            vault
+                .access_token(&config, &providers, &database, identity.pk)
+                .await,
+            Err(AppError::DoesNotExist)
+        ));
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_import_google_profiles(pool: PgPool) {
+        let database: Database = pool.into();
+        let vault = vault();
+        let user = User::create_active("", &*database).await.unwrap();
+        let identity = Identity::create(user.pk, "google", "google-1", None, &*database)
+            .await
+            .unwrap();
+        sqlx::query(
+            "WITH email AS (INSERT INTO emails (user_pk, email) VALUES ($1, 'user@example.com') RETURNING pk)
+            INSERT INTO google_profiles (id, email_pk, access_token, refresh_token, expires_in)
+            SELECT 'google-1', pk, 'plain-access', 'plain-refresh', 3600 FROM email;",
+        )
+        .bind(user.pk)
+        .execute(&*database)
+        .await
+        .unwrap();
+
+        assert_eq!(vault.import_google_profiles(&database).await.unwrap(), 1);
+        assert_eq!(vault.import_google_profiles(&database).await.unwrap(), 0);
+
+        let stored = StoredToken::find(identity.pk, &*database)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            vault.decrypt(identity.pk, &stored.access_token).unwrap(),
+            "plain-access"
+        );
+        assert_eq!(
+            vault
+                .decrypt(identity.pk, stored.refresh_token.as_ref().unwrap())
+                .unwrap(),
+            "plain-refresh"
+        );
+        assert!(!stored.expires_within(Duration::minutes(30)));
+
+        let plaintext: (Option<String>, Option<String>) = sqlx::query_as(
+            "SELECT access_token, refresh_token FROM google_profiles WHERE id = 'google-1';",
+        )
+        .fetch_one(&*database)
+        .await
+        .unwrap();
+        assert_eq!(plaintext, (None, None));
+    }
+}
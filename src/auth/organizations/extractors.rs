@@ -0,0 +1,208 @@
+use std::ops::{Deref, DerefMut};
+
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::request::Parts,
+};
+use sqlx::{PgConnection, Postgres, Transaction};
+
+use crate::{
+    auth::CurrentUser, database::Database, errors::AppError, log_and_wrap_custom_internal,
+    sessions::Session,
+};
+
+use super::infrastructure::{Membership, Organization};
+
+/// Header selecting the organization of token and API key requests, sessions keep theirs
+pub const ORGANIZATION_HEADER: &str = "X-Organization";
+
+/// The organization the request works in, with the role of the current user in it. It comes
+/// from the active organization of the `Session`, or from the `X-Organization` header. Users
+/// outside the organization get a 404 so they cannot probe which ones exist.
+#[derive(Debug, Clone)]
+pub struct CurrentOrganization {
+    pub organization: Organization,
+    pub membership: Membership,
+}
+
+impl CurrentOrganization {
+    pub fn pk(&self) -> i64 {
+        self.organization.pk
+    }
+
+    pub fn role(&self) -> &str {
+        &self.membership.role
+    }
+
+    async fn selected_pk(parts: &Parts) -> Result<i64, AppError> {
+        if let Some(session) = parts.extensions.get::<Session>() {
+            if let Some(pk) = session.organization_pk().await {
+                return Ok(pk);
+            }
+        }
+        parts
+            .headers
+            .get(ORGANIZATION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .ok_or_else(|| AppError::custom_bad_request("No organization selected"))
+    }
+}
+
+impl<S> FromRequestParts<S> for CurrentOrganization
+where
+    Database: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = CurrentUser::from_request_parts(parts, state).await?;
+        let organization_pk = Self::selected_pk(parts).await?;
+        let database = Database::from_ref(state);
+
+        let membership = Membership::find(organization_pk, user.pk, &*database)
+            .await?
+            .ok_or(AppError::DoesNotExist)?;
+        let organization = Organization::get_by_pk(organization_pk, &*database).await?;
+        Ok(Self {
+            organization,
+            membership,
+        })
+    }
+}
+
+/// A transaction of the request with `app.tenant_id` set to the current organization, so the
+/// tables under a `TenantPolicy` only show its rows. Dropping it rolls back, handlers writing
+/// through it call `commit`.
+pub struct TenantTransaction {
+    pub organization: CurrentOrganization,
+    tx: Transaction<'static, Postgres>,
+}
+
+impl TenantTransaction {
+    pub async fn commit(self) -> Result<(), AppError> {
+        self.tx
+            .commit()
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+}
+
+impl Deref for TenantTransaction {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tx
+    }
+}
+
+impl DerefMut for TenantTransaction {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tx
+    }
+}
+
+impl<S> FromRequestParts<S> for TenantTransaction
+where
+    Database: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let organization = CurrentOrganization::from_request_parts(parts, state).await?;
+        let tx = Database::from_ref(state)
+            .start_tenant_transaction(organization.pk())
+            .await?;
+        Ok(Self { organization, tx })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::Request, http::StatusCode, routing::get, Extension, Router};
+    use sqlx::PgPool;
+
+    use crate::{
+        auth::{test_utils, AuthMethod},
+        models::User,
+    };
+
+    use super::*;
+
+    async fn current(organization: CurrentOrganization) -> String {
+        format!("{} {}", organization.organization.name, organization.role())
+    }
+
+    async fn tenant(mut tx: TenantTransaction) -> String {
+        let tenant: String = sqlx::query_scalar("SELECT current_setting('app.tenant_id');")
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+        assert_eq!(tenant, tx.organization.pk().to_string());
+        tenant
+    }
+
+    async fn call(user_pk: i64, header: Option<&str>, database: &Database) -> (StatusCode, String) {
+        call_uri("/", user_pk, header, database).await
+    }
+
+    async fn call_uri(
+        uri: &str,
+        user_pk: i64,
+        header: Option<&str>,
+        database: &Database,
+    ) -> (StatusCode, String) {
+        let mut request = Request::get(uri);
+        if let Some(header) = header {
+            request = request.header(ORGANIZATION_HEADER, header);
+        }
+        let router = Router::new()
+            .route("/", get(current))
+            .route("/tenant", get(tenant))
+            .layer(Extension(CurrentUser::new(user_pk, AuthMethod::Token)))
+            .with_state(database.clone());
+        test_utils::send(router, request.body(Body::empty()).unwrap()).await
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_current_organization(pool: PgPool) {
+        let database: Database = pool.into();
+        let owner = User::create_active("", &*database).await.unwrap();
+        let stranger = User::create_active("", &*database).await.unwrap();
+        let mut tx = database.start_transaction().await.unwrap();
+        let acme = Organization::create("Acme", owner.pk, &mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        let acme_pk = acme.pk.to_string();
+
+        assert_eq!(
+            call(owner.pk, Some(&acme_pk), &database).await,
+            (StatusCode::OK, "Acme owner".to_owned())
+        );
+        assert_eq!(
+            call(stranger.pk, Some(&acme_pk), &database).await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            call(owner.pk, Some("999"), &database).await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            call(owner.pk, None, &database).await.0,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            call_uri("/tenant", owner.pk, Some(&acme_pk), &database).await,
+            (StatusCode::OK, acme_pk.clone())
+        );
+        assert_eq!(
+            call_uri("/tenant", stranger.pk, Some(&acme_pk), &database)
+                .await
+                .0,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,648 @@
+use askama::Template;
+use chrono::{Duration, NaiveDateTime, Utc};
+use lettre::{message::MultiPart, Message};
+use sqlx::{FromRow, PgConnection, PgExecutor};
+use uuid::Uuid;
+
+use crate::{
+    config::{ServiceConfig, WebsiteConfig},
+    errors::AppError,
+    log_and_wrap_custom_internal,
+    mailing::Mailer,
+};
+
+#[derive(Debug, Clone, FromRow)]
+pub struct Organization {
+    pub pk: i64,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl Organization {
+    /// The creator becomes its owner
+    pub async fn create(
+        name: &str,
+        owner_pk: i64,
+        tx: &mut PgConnection,
+    ) -> Result<Self, AppError> {
+        let organization: Self = sqlx::query_as(
+            "INSERT INTO organizations (name) VALUES ($1) RETURNING pk, name, created_at;",
+        )
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Membership::add(organization.pk, owner_pk, Membership::OWNER, &mut *tx).await?;
+        Ok(organization)
+    }
+
+    pub async fn get_by_pk<'e, E: PgExecutor<'e>>(pk: i64, executor: E) -> Result<Self, AppError> {
+        sqlx::query_as("SELECT pk, name, created_at FROM organizations WHERE pk = $1;")
+            .bind(pk)
+            .fetch_optional(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?
+            .ok_or(AppError::DoesNotExist)
+    }
+
+    pub async fn for_user<'e, E: PgExecutor<'e>>(
+        user_pk: i64,
+        executor: E,
+    ) -> Result<Vec<Self>, AppError> {
+        sqlx::query_as(
+            "SELECT o.pk, o.name, o.created_at FROM organizations o
+            JOIN organization_memberships m ON m.organization_pk = o.pk
+            WHERE m.user_pk = $1 ORDER BY o.name;",
+        )
+        .bind(user_pk)
+        .fetch_all(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+}
+
+/// The role of a user in an organization. Roles are free strings so apps can add theirs, the
+/// constants are the ones used by this crate.
+#[derive(Debug, Clone, FromRow)]
+pub struct Membership {
+    pub organization_pk: i64,
+    pub user_pk: i64,
+    pub role: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl Membership {
+    pub const OWNER: &'static str = "owner";
+    pub const ADMIN: &'static str = "admin";
+    pub const MEMBER: &'static str = "member";
+
+    pub async fn find<'e, E: PgExecutor<'e>>(
+        organization_pk: i64,
+        user_pk: i64,
+        executor: E,
+    ) -> Result<Option<Self>, AppError> {
+        sqlx::query_as(
+            "SELECT organization_pk, user_pk, role, created_at FROM organization_memberships
+            WHERE organization_pk = $1 AND user_pk = $2;",
+        )
+        .bind(organization_pk)
+        .bind(user_pk)
+        .fetch_optional(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    pub async fn list<'e, E: PgExecutor<'e>>(
+        organization_pk: i64,
+        executor: E,
+    ) -> Result<Vec<Self>, AppError> {
+        sqlx::query_as(
+            "SELECT organization_pk, user_pk, role, created_at FROM organization_memberships
+            WHERE organization_pk = $1 ORDER BY created_at;",
+        )
+        .bind(organization_pk)
+        .fetch_all(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Adding an existing member keeps their role
+    pub async fn add<'e, E: PgExecutor<'e>>(
+        organization_pk: i64,
+        user_pk: i64,
+        role: &str,
+        executor: E,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO organization_memberships (organization_pk, user_pk, role) VALUES ($1, $2, $3)
+            ON CONFLICT (organization_pk, user_pk) DO NOTHING;",
+        )
+        .bind(organization_pk)
+        .bind(user_pk)
+        .bind(role)
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(())
+    }
+
+    /// The last owner cannot be demoted, the organization would have nobody to manage it. Call
+    /// it in a transaction, the owners stay locked until it ends.
+    pub async fn set_role(
+        organization_pk: i64,
+        user_pk: i64,
+        role: &str,
+        tx: &mut PgConnection,
+    ) -> Result<(), AppError> {
+        Self::check_last_owner(organization_pk, user_pk, role == Self::OWNER, &mut *tx).await?;
+        let result = sqlx::query(
+            "UPDATE organization_memberships SET role = $1 WHERE organization_pk = $2 AND user_pk = $3;",
+        )
+        .bind(role)
+        .bind(organization_pk)
+        .bind(user_pk)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::DoesNotExist);
+        }
+        Ok(())
+    }
+
+    /// The last owner cannot leave, same as [`Membership::set_role`]
+    pub async fn remove(
+        organization_pk: i64,
+        user_pk: i64,
+        tx: &mut PgConnection,
+    ) -> Result<(), AppError> {
+        Self::check_last_owner(organization_pk, user_pk, false, &mut *tx).await?;
+        let result = sqlx::query(
+            "DELETE FROM organization_memberships WHERE organization_pk = $1 AND user_pk = $2;",
+        )
+        .bind(organization_pk)
+        .bind(user_pk)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::DoesNotExist);
+        }
+        Ok(())
+    }
+
+    /// Locks the owner rows first, so two owners demoting each other at the same time can't
+    /// both see the other one still in place
+    async fn check_last_owner(
+        organization_pk: i64,
+        user_pk: i64,
+        stays_owner: bool,
+        tx: &mut PgConnection,
+    ) -> Result<(), AppError> {
+        let owners: Vec<i64> = sqlx::query_scalar(
+            "SELECT user_pk FROM organization_memberships WHERE organization_pk = $1 AND role = $2
+            FOR UPDATE;",
+        )
+        .bind(organization_pk)
+        .bind(Self::OWNER)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        if !stays_owner && owners == [user_pk] {
+            return Err(AppError::custom_bad_request(
+                "The organization needs at least one owner",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_owner(&self) -> bool {
+        self.role == Self::OWNER
+    }
+
+    /// Owners and admins manage the members
+    pub fn can_manage(&self) -> bool {
+        self.role == Self::OWNER || self.role == Self::ADMIN
+    }
+}
+
+/// An invitation to join an organization, sent by email. It is answered once, by a user owning
+/// the invited email.
+#[derive(Debug, Clone, FromRow)]
+pub struct Invitation {
+    pub pk: i64,
+    pub organization_pk: i64,
+    pub email: String,
+    pub role: String,
+    pub slug: String,
+    pub invited_by_pk: Option<i64>,
+    pub expires_at: NaiveDateTime,
+    pub accepted_at: Option<NaiveDateTime>,
+    pub declined_at: Option<NaiveDateTime>,
+}
+
+impl Invitation {
+    /// Expires after `expiration` hours
+    pub async fn create<'e, E: PgExecutor<'e>>(
+        organization_pk: i64,
+        email: &str,
+        role: &str,
+        invited_by_pk: i64,
+        expiration: i64,
+        executor: E,
+    ) -> Result<Self, AppError> {
+        sqlx::query_as(
+            "INSERT INTO organization_invitations (organization_pk, email, role, slug, invited_by_pk, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING pk, organization_pk, email, role, slug, invited_by_pk, expires_at, accepted_at, declined_at;",
+        )
+        .bind(organization_pk)
+        .bind(email)
+        .bind(role)
+        .bind(Uuid::new_v4().to_string())
+        .bind(invited_by_pk)
+        .bind(Utc::now().naive_utc() + Duration::hours(expiration))
+        .fetch_one(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    pub async fn send(
+        self,
+        config: &WebsiteConfig,
+        mailer: &Mailer,
+        organization: &str,
+    ) -> Result<Self, AppError> {
+        let url = config.build_url(&format!("/organization-invitation/{}", self.slug));
+        let html = OrganizationInvitationHtml {
+            url: &url,
+            organization,
+            expiration: config.organization_invitation_expiration,
+        }
+        .render()?;
+        let text = OrganizationInvitationText {
+            url: &url,
+            organization,
+            expiration: config.organization_invitation_expiration,
+        }
+        .render()?;
+
+        let message = Message::builder()
+            .from(config.email_default_sender.parse().unwrap())
+            .to(self
+                .email
+                .parse()
+                .map_err(|_| AppError::custom_bad_request("Invalid email"))?)
+            .subject(&config.organization_invitation_subject)
+            .multipart(MultiPart::alternative_plain_html(text, html))
+            .expect("failed to build email");
+        mailer.send(&message).await?;
+        Ok(self)
+    }
+
+    pub async fn pending_for_organization<'e, E: PgExecutor<'e>>(
+        organization_pk: i64,
+        executor: E,
+    ) -> Result<Vec<Self>, AppError> {
+        sqlx::query_as(
+            "SELECT pk, organization_pk, email, role, slug, invited_by_pk, expires_at, accepted_at, declined_at
+            FROM organization_invitations
+            WHERE organization_pk = $1 AND accepted_at IS NULL AND declined_at IS NULL AND expires_at > $2
+            ORDER BY created_at DESC;",
+        )
+        .bind(organization_pk)
+        .bind(Utc::now().naive_utc())
+        .fetch_all(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// For the confirmation page. Invitations of other users look unknown.
+    pub async fn find_for_user<'e, E: PgExecutor<'e>>(
+        slug: &str,
+        user_pk: i64,
+        executor: E,
+    ) -> Result<Self, AppError> {
+        sqlx::query_as(&format!("{SELECT_FOR_USER};"))
+            .bind(slug)
+            .bind(user_pk)
+            .fetch_optional(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?
+            .ok_or(AppError::DoesNotExist)
+    }
+
+    pub fn is_answered(&self) -> bool {
+        self.accepted_at.is_some() || self.declined_at.is_some()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now().naive_utc()
+    }
+
+    /// The user joins the organization with the invited role, if one of their validated emails
+    /// is the invited one
+    pub async fn accept(slug: &str, user_pk: i64, tx: &mut PgConnection) -> Result<Self, AppError> {
+        let invitation = Self::answerable(slug, user_pk, &mut *tx).await?;
+        let now = Utc::now().naive_utc();
+        sqlx::query("UPDATE organization_invitations SET accepted_at = $1 WHERE pk = $2;")
+            .bind(now)
+            .bind(invitation.pk)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Membership::add(
+            invitation.organization_pk,
+            user_pk,
+            &invitation.role,
+            &mut *tx,
+        )
+        .await?;
+        Ok(Self {
+            accepted_at: Some(now),
+            ..invitation
+        })
+    }
+
+    pub async fn decline(
+        slug: &str,
+        user_pk: i64,
+        tx: &mut PgConnection,
+    ) -> Result<Self, AppError> {
+        let invitation = Self::answerable(slug, user_pk, &mut *tx).await?;
+        let now = Utc::now().naive_utc();
+        sqlx::query("UPDATE organization_invitations SET declined_at = $1 WHERE pk = $2;")
+            .bind(now)
+            .bind(invitation.pk)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(Self {
+            declined_at: Some(now),
+            ..invitation
+        })
+    }
+
+    /// Locks the invitation. Invitations of other users look unknown.
+    async fn answerable(slug: &str, user_pk: i64, tx: &mut PgConnection) -> Result<Self, AppError> {
+        let invitation: Self = sqlx::query_as(&format!("{SELECT_FOR_USER} FOR UPDATE OF i;"))
+            .bind(slug)
+            .bind(user_pk)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?
+            .ok_or(AppError::DoesNotExist)?;
+
+        if invitation.is_answered() {
+            return Err(AppError::custom_bad_request(
+                "The invitation was already answered",
+            ));
+        }
+        if invitation.is_expired() {
+            return Err(AppError::custom_bad_request("The invitation has expired"));
+        }
+        Ok(invitation)
+    }
+}
+
+/// The invitation when one of the user's validated emails is the invited one
+const SELECT_FOR_USER: &str = "SELECT i.pk, i.organization_pk, i.email, i.role, i.slug, i.invited_by_pk, i.expires_at, i.accepted_at, i.declined_at
+    FROM organization_invitations i
+    JOIN emails e ON LOWER(e.email) = LOWER(i.email)
+    WHERE i.slug = $1 AND e.user_pk = $2 AND e.activated_at IS NOT NULL";
+
+#[derive(Template)]
+#[template(path = "emails/organization_invitation.html")]
+struct OrganizationInvitationHtml<'a> {
+    url: &'a str,
+    organization: &'a str,
+    expiration: i64,
+}
+
+#[derive(Template)]
+#[template(path = "emails/organization_invitation.txt")]
+struct OrganizationInvitationText<'a> {
+    url: &'a str,
+    organization: &'a str,
+    expiration: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{
+        database::Database,
+        models::{EmailAccount, User},
+    };
+
+    use super::*;
+
+    async fn organization(owner_pk: i64, database: &Database) -> Organization {
+        let mut tx = database.start_transaction().await.unwrap();
+        let organization = Organization::create("Acme", owner_pk, &mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        organization
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_memberships(pool: PgPool) {
+        let database: Database = pool.into();
+        let owner = User::create_active("", &*database).await.unwrap();
+        let member = User::create_active("", &*database).await.unwrap();
+        let acme = organization(owner.pk, &database).await;
+        let mut conn = database.acquire().await.unwrap();
+
+        let membership = Membership::find(acme.pk, owner.pk, &*database)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(membership.is_owner());
+        assert!(Membership::find(acme.pk, member.pk, &*database)
+            .await
+            .unwrap()
+            .is_none());
+
+        Membership::add(acme.pk, member.pk, Membership::MEMBER, &*database)
+            .await
+            .unwrap();
+        Membership::add(acme.pk, member.pk, Membership::OWNER, &*database)
+            .await
+            .unwrap();
+        let membership = Membership::find(acme.pk, member.pk, &*database)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!membership.can_manage());
+
+        Membership::set_role(acme.pk, member.pk, Membership::ADMIN, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            Membership::list(acme.pk, &*database).await.unwrap().len(),
+            2
+        );
+        assert_eq!(
+            Organization::for_user(member.pk, &*database)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // The last owner stays
+        assert!(matches!(
+            Membership::set_role(acme.pk, owner.pk, Membership::MEMBER, &mut conn).await,
+            Err(AppError::Custom(..))
+        ));
+        assert!(matches!(
+            Membership::remove(acme.pk, owner.pk, &mut conn).await,
+            Err(AppError::Custom(..))
+        ));
+        Membership::set_role(acme.pk, member.pk, Membership::OWNER, &mut conn)
+            .await
+            .unwrap();
+        Membership::set_role(acme.pk, owner.pk, Membership::MEMBER, &mut conn)
+            .await
+            .unwrap();
+        Membership::set_role(acme.pk, owner.pk, Membership::OWNER, &mut conn)
+            .await
+            .unwrap();
+
+        Membership::remove(acme.pk, member.pk, &mut conn)
+            .await
+            .unwrap();
+        assert!(matches!(
+            Membership::remove(acme.pk, member.pk, &mut conn).await,
+            Err(AppError::DoesNotExist)
+        ));
+        assert!(Organization::for_user(member.pk, &*database)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_owners_demoting_each_other(pool: PgPool) {
+        let database: Database = pool.into();
+        let first = User::create_active("", &*database).await.unwrap();
+        let second = User::create_active("", &*database).await.unwrap();
+        let acme = organization(first.pk, &database).await;
+        Membership::add(acme.pk, second.pk, Membership::OWNER, &*database)
+            .await
+            .unwrap();
+
+        let mut tx = database.start_transaction().await.unwrap();
+        Membership::set_role(acme.pk, second.pk, Membership::MEMBER, &mut tx)
+            .await
+            .unwrap();
+        // Waits on the owner rows the first transaction locked
+        let other = {
+            let database = database.clone();
+            tokio::spawn(async move {
+                let mut tx = database.start_transaction().await.unwrap();
+                let result =
+                    Membership::set_role(acme.pk, first.pk, Membership::MEMBER, &mut tx).await;
+                tx.commit().await.unwrap();
+                result
+            })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!other.is_finished());
+        tx.commit().await.unwrap();
+
+        assert!(matches!(other.await.unwrap(), Err(AppError::Custom(..))));
+        assert!(Membership::find(acme.pk, first.pk, &*database)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_owner());
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_invitations(pool: PgPool) {
+        let database: Database = pool.into();
+        let owner = User::create_active("", &*database).await.unwrap();
+        let acme = organization(owner.pk, &database).await;
+        let invited = User::create_active("", &*database).await.unwrap();
+        EmailAccount::create_primary_active(
+            invited.clone(),
+            "invited@example.com".into(),
+            &*database,
+        )
+        .await
+        .unwrap();
+        // The email is not validated by this user
+        let other = User::create_active("", &*database).await.unwrap();
+        EmailAccount::create_secondary(other.clone(), "INVITED@example.org".into(), &*database)
+            .await
+            .unwrap();
+
+        let invitation = Invitation::create(
+            acme.pk,
+            "Invited@example.com",
+            Membership::ADMIN,
+            owner.pk,
+            72,
+            &*database,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            Invitation::pending_for_organization(acme.pk, &*database)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert!(matches!(
+            Invitation::find_for_user(&invitation.slug, other.pk, &*database).await,
+            Err(AppError::DoesNotExist)
+        ));
+        assert!(
+            !Invitation::find_for_user(&invitation.slug, invited.pk, &*database)
+                .await
+                .unwrap()
+                .is_answered()
+        );
+
+        let mut tx = database.start_transaction().await.unwrap();
+        assert!(matches!(
+            Invitation::accept(&invitation.slug, other.pk, &mut tx).await,
+            Err(AppError::DoesNotExist)
+        ));
+        let accepted = Invitation::accept(&invitation.slug, invited.pk, &mut tx)
+            .await
+            .unwrap();
+        assert!(accepted.accepted_at.is_some());
+        assert!(Invitation::decline(&invitation.slug, invited.pk, &mut tx)
+            .await
+            .is_err());
+        tx.commit().await.unwrap();
+
+        let membership = Membership::find(acme.pk, invited.pk, &*database)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(membership.role, Membership::ADMIN);
+        assert!(Invitation::pending_for_organization(acme.pk, &*database)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let declined = Invitation::create(
+            acme.pk,
+            "invited@example.com",
+            Membership::MEMBER,
+            owner.pk,
+            72,
+            &*database,
+        )
+        .await
+        .unwrap();
+        let mut tx = database.start_transaction().await.unwrap();
+        Invitation::decline(&declined.slug, invited.pk, &mut tx)
+            .await
+            .unwrap();
+        assert!(Invitation::accept(&declined.slug, invited.pk, &mut tx)
+            .await
+            .is_err());
+
+        let expired = Invitation::create(
+            acme.pk,
+            "invited@example.com",
+            Membership::MEMBER,
+            owner.pk,
+            -1,
+            &*database,
+        )
+        .await
+        .unwrap();
+        assert!(Invitation::accept(&expired.slug, invited.pk, &mut tx)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,7 @@
+mod extractors;
+mod infrastructure;
+mod services;
+
+pub use extractors::{CurrentOrganization, TenantTransaction, ORGANIZATION_HEADER};
+pub use infrastructure::{Invitation, Membership, Organization};
+pub use services::{InvitationForm, OrganizationForm, Organizations};
@@ -0,0 +1,231 @@
+use askama::Template;
+use axum::{
+    extract::{OriginalUri, Path, State},
+    response::{Html, IntoResponse, Redirect, Response},
+    Extension,
+};
+use serde::Deserialize;
+use validator::Validate;
+
+use crate::{
+    auth::{basic::login_redirect, CurrentUser},
+    errors::AppError,
+    log_and_wrap_custom_internal,
+    sessions::Session,
+    state::WebsiteState,
+    website::{EmptyForm, SecureForm},
+};
+
+use super::{
+    extractors::CurrentOrganization,
+    infrastructure::{Invitation, Membership, Organization},
+};
+
+#[derive(Debug, Validate, Deserialize)]
+pub struct OrganizationForm {
+    #[validate(length(min = 1, max = 255))]
+    name: String,
+}
+
+#[derive(Debug, Validate, Deserialize)]
+pub struct InvitationForm {
+    #[validate(email)]
+    email: String,
+    role: String,
+}
+
+#[derive(Template)]
+#[template(path = "auth/organization_invitation.html")]
+struct InvitationPage<'a> {
+    organization: &'a str,
+    role: &'a str,
+    answered: bool,
+    expired: bool,
+    accept_url: &'a str,
+    decline_url: &'a str,
+    csrf_token: &'a str,
+}
+
+/// Create organizations, invite members by email and switch the active organization of the
+/// session. The invitation emails link to `/organization-invitation/{slug}`: mount
+/// `invitation_route` there, and `accept_route` and `decline_route` as POST on
+/// `/organization-invitation/{slug}/accept` and `/organization-invitation/{slug}/decline`.
+pub trait Organizations {
+    /// The creator becomes the owner and works in it right away
+    fn create_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        user: CurrentUser,
+        input: SecureForm<OrganizationForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            let database = state.database();
+            let mut tx = database.start_transaction().await?;
+            let organization = Organization::create(&input.data().name, user.pk, &mut tx).await?;
+            tx.commit()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            state
+                .sessions()
+                .set_organization(&session, Some(organization.pk))
+                .await?;
+            Ok(Redirect::to(Self::switch_redirect(&state)))
+        }
+    }
+
+    /// Owners and admins invite into the current organization, nobody is invited as owner
+    fn invite_route(
+        state: State<WebsiteState>,
+        user: CurrentUser,
+        current: CurrentOrganization,
+        input: SecureForm<InvitationForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            let config = state.config();
+            let input = input.data();
+            if !current.membership.can_manage() {
+                return Err(AppError::RoleError);
+            }
+            if input.role != Membership::MEMBER && input.role != Membership::ADMIN {
+                return Err(AppError::custom_bad_request("Invalid role"));
+            }
+
+            Invitation::create(
+                current.pk(),
+                &input.email,
+                &input.role,
+                user.pk,
+                config.organization_invitation_expiration,
+                &**state.database(),
+            )
+            .await?
+            .send(config, state.mailer(), &current.organization.name)
+            .await?;
+            Ok(Redirect::to(Self::invite_redirect(&state)))
+        }
+    }
+
+    /// The confirmation page of the emailed link, answering takes a POST. Anonymous users log in
+    /// first.
+    fn invitation_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        OriginalUri(uri): OriginalUri,
+        user: Option<CurrentUser>,
+        Path(slug): Path<String>,
+    ) -> impl std::future::Future<Output = Result<Response, AppError>> + Send {
+        async move {
+            let Some(user) = user else {
+                return Ok(login_redirect(state.config(), &uri.to_string()));
+            };
+            let database = state.database();
+            let invitation = Invitation::find_for_user(&slug, user.pk, &**database).await?;
+            let organization =
+                Organization::get_by_pk(invitation.organization_pk, &**database).await?;
+            let html = InvitationPage {
+                organization: &organization.name,
+                role: &invitation.role,
+                answered: invitation.is_answered(),
+                expired: invitation.is_expired(),
+                accept_url: &format!("/organization-invitation/{slug}/accept"),
+                decline_url: &format!("/organization-invitation/{slug}/decline"),
+                csrf_token: &session.csrf_token().await,
+            }
+            .render()?;
+            Ok(Html(html).into_response())
+        }
+    }
+
+    /// Join the organization and switch to it
+    fn accept_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        user: CurrentUser,
+        Path(slug): Path<String>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            let mut tx = state.database().start_transaction().await?;
+            let invitation = Invitation::accept(&slug, user.pk, &mut tx).await?;
+            tx.commit()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            state
+                .sessions()
+                .set_organization(&session, Some(invitation.organization_pk))
+                .await?;
+            Ok(Redirect::to(Self::switch_redirect(&state)))
+        }
+    }
+
+    fn decline_route(
+        state: State<WebsiteState>,
+        user: CurrentUser,
+        Path(slug): Path<String>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            let mut tx = state.database().start_transaction().await?;
+            Invitation::decline(&slug, user.pk, &mut tx).await?;
+            tx.commit()
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            Ok(Redirect::to(&state.config().login_redirect_to))
+        }
+    }
+
+    /// Make one of the user's organizations the active one of the session
+    fn switch_route(
+        state: State<WebsiteState>,
+        Extension(session): Extension<Session>,
+        user: CurrentUser,
+        Path(organization_pk): Path<i64>,
+        _input: SecureForm<EmptyForm>,
+    ) -> impl std::future::Future<Output = Result<Redirect, AppError>> + Send {
+        async move {
+            Membership::find(organization_pk, user.pk, &**state.database())
+                .await?
+                .ok_or(AppError::DoesNotExist)?;
+            state
+                .sessions()
+                .set_organization(&session, Some(organization_pk))
+                .await?;
+            Ok(Redirect::to(Self::switch_redirect(&state)))
+        }
+    }
+
+    fn switch_redirect(state: &WebsiteState) -> &str {
+        &state.config().login_redirect_to
+    }
+
+    fn invite_redirect(state: &WebsiteState) -> &str {
+        &state.config().login_redirect_to
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invitation_page() {
+        let mut page = InvitationPage {
+            organization: "Acme",
+            role: Membership::ADMIN,
+            answered: false,
+            expired: false,
+            accept_url: "/organization-invitation/slug/accept",
+            decline_url: "/organization-invitation/slug/decline",
+            csrf_token: "token",
+        };
+        let html = page.render().unwrap();
+        assert!(html.contains(r#"action="/organization-invitation/slug/accept""#));
+        assert!(html.contains(r#"action="/organization-invitation/slug/decline""#));
+        assert_eq!(html.matches(r#"value="token""#).count(), 2);
+
+        page.answered = true;
+        let html = page.render().unwrap();
+        assert!(html.contains("already answered"));
+        assert!(!html.contains("<form"));
+    }
+}
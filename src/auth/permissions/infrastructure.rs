@@ -0,0 +1,272 @@
+use serde::Serialize;
+use sqlx::{FromRow, PgExecutor};
+
+use crate::{
+    database::Database, errors::AppError, log_and_wrap_custom_internal, models::Permissions,
+};
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Permission {
+    pub pk: i64,
+    pub name: String,
+}
+
+impl Permission {
+    pub async fn create<'e, E: PgExecutor<'e>>(name: &str, executor: E) -> Result<Self, AppError> {
+        validate_name(name)?;
+        sqlx::query_as(
+            "INSERT INTO permissions (name) VALUES ($1)
+            ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name RETURNING pk, name;",
+        )
+        .bind(name)
+        .fetch_one(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    pub async fn all<'e, E: PgExecutor<'e>>(executor: E) -> Result<Vec<Self>, AppError> {
+        sqlx::query_as("SELECT pk, name FROM permissions ORDER BY name;")
+            .fetch_all(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Grant the permission to the user alone, on top of their groups'
+    pub async fn grant_to_user<'e, E: PgExecutor<'e>>(
+        name: &str,
+        user_pk: i64,
+        executor: E,
+    ) -> Result<(), AppError> {
+        let result = sqlx::query(
+            "INSERT INTO users_permissions_m2m (user_pk, permission_pk) SELECT $1, pk FROM permissions WHERE name = $2
+            ON CONFLICT DO NOTHING;",
+        )
+        .bind(user_pk)
+        .bind(name)
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::DoesNotExist);
+        }
+        Ok(())
+    }
+
+    pub async fn revoke_from_user<'e, E: PgExecutor<'e>>(
+        name: &str,
+        user_pk: i64,
+        executor: E,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "DELETE FROM users_permissions_m2m
+            WHERE user_pk = $1 AND permission_pk = (SELECT pk FROM permissions WHERE name = $2);",
+        )
+        .bind(user_pk)
+        .bind(name)
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(())
+    }
+
+    /// The current permissions of the user, the session only has the ones of the login
+    pub async fn for_user<'e, E: PgExecutor<'e>>(
+        user_pk: i64,
+        executor: E,
+    ) -> Result<Permissions, AppError> {
+        let names: Vec<String> =
+            sqlx::query_scalar("SELECT name FROM user_permissions WHERE user_pk = $1;")
+                .bind(user_pk)
+                .fetch_all(executor)
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(names.into_iter().collect())
+    }
+}
+
+/// Names are stored comma separated in the sessions, and digits alone are read as the group
+/// pks of the sessions before the dynamic groups
+fn validate_name(name: &str) -> Result<(), AppError> {
+    if name.is_empty() || name.contains(',') {
+        return Err(AppError::custom_bad_request(
+            "Group and permission names can't be empty or contain commas",
+        ));
+    }
+    if name.chars().all(|c| c.is_ascii_digit()) {
+        return Err(AppError::custom_bad_request(
+            "Group and permission names can't be only digits",
+        ));
+    }
+    Ok(())
+}
+
+/// The groups and permissions of the app, declared in code and seeded at startup:
+///
+/// ```ignore
+/// Roles::new()
+///     .group("Accountant", &["billing:read", "billing:write"])
+///     .group(Group::ADMIN, &["billing:read", "billing:write", "users:manage"])
+///     .seed(&database)
+///     .await?;
+/// ```
+///
+/// Seeding is idempotent. The declared groups get exactly the declared permissions, the groups
+/// and permissions it doesn't declare are left as they are.
+#[derive(Debug, Default, Clone)]
+pub struct Roles {
+    permissions: Vec<String>,
+    groups: Vec<(String, Vec<String>)>,
+}
+
+impl Roles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn permission(mut self, name: &str) -> Self {
+        if !self.permissions.iter().any(|p| p == name) {
+            self.permissions.push(name.to_owned());
+        }
+        self
+    }
+
+    pub fn group(mut self, name: &str, permissions: &[&str]) -> Self {
+        for permission in permissions {
+            self = self.permission(permission);
+        }
+        self.groups.push((
+            name.to_owned(),
+            permissions.iter().map(|p| p.to_string()).collect(),
+        ));
+        self
+    }
+
+    pub async fn seed(&self, database: &Database) -> Result<(), AppError> {
+        for name in self
+            .permissions
+            .iter()
+            .chain(self.groups.iter().map(|(name, _)| name))
+        {
+            validate_name(name)?;
+        }
+
+        let mut tx = database.start_transaction().await?;
+        sqlx::query("INSERT INTO permissions (name) SELECT UNNEST($1::TEXT[]) ON CONFLICT (name) DO NOTHING;")
+            .bind(&self.permissions)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+
+        for (group, permissions) in &self.groups {
+            sqlx::query("INSERT INTO groups (name) VALUES ($1) ON CONFLICT (name) DO NOTHING;")
+                .bind(group)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            sqlx::query(
+                "DELETE FROM groups_permissions_m2m
+                WHERE group_pk = (SELECT pk FROM groups WHERE name = $1)
+                AND permission_pk NOT IN (SELECT pk FROM permissions WHERE name = ANY($2));",
+            )
+            .bind(group)
+            .bind(permissions)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+            sqlx::query(
+                "INSERT INTO groups_permissions_m2m (group_pk, permission_pk)
+                SELECT groups.pk, permissions.pk FROM groups, permissions
+                WHERE groups.name = $1 AND permissions.name = ANY($2)
+                ON CONFLICT DO NOTHING;",
+            )
+            .bind(group)
+            .bind(permissions)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        }
+
+        tx.commit()
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::models::{Group, Groups, User};
+
+    use super::*;
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_roles_and_permissions(pool: PgPool) {
+        let database: Database = pool.into();
+        let roles = Roles::new()
+            .permission("reports:read")
+            .group("Accountant", &["billing:read", "billing:write"])
+            .group(Group::ADMIN, &["users:manage"]);
+        roles.seed(&database).await.unwrap();
+        roles.seed(&database).await.unwrap();
+        assert_eq!(Permission::all(&*database).await.unwrap().len(), 4);
+        assert!(Group::find_by_name("Accountant", &*database)
+            .await
+            .unwrap()
+            .is_some());
+
+        let user = User::create_active("", &*database)
+            .await
+            .unwrap()
+            .add_to_group("Accountant", &*database)
+            .await
+            .unwrap();
+        assert!(user.has_permission("billing:write"));
+        assert!(user.clone().for_session().has_permission("billing:read"));
+        assert!(!user.has_permission("users:manage"));
+        assert!(matches!(
+            user.clone().add_to_group("Unknown", &*database).await,
+            Err(AppError::DoesNotExist)
+        ));
+
+        Permission::grant_to_user("reports:read", user.pk, &*database)
+            .await
+            .unwrap();
+        assert!(Permission::grant_to_user("unknown", user.pk, &*database)
+            .await
+            .is_err());
+        let loaded = User::get_by_pk(user.pk, &*database).await.unwrap();
+        assert_eq!(loaded.groups.to_string(), "Accountant");
+        assert!(loaded.has_permission("reports:read"));
+        assert!(loaded.has_permission("billing:read"));
+
+        // Reseeding with fewer permissions takes them away from the group
+        Roles::new()
+            .group("Accountant", &["billing:read"])
+            .seed(&database)
+            .await
+            .unwrap();
+        Permission::revoke_from_user("reports:read", user.pk, &*database)
+            .await
+            .unwrap();
+        let permissions = Permission::for_user(user.pk, &*database).await.unwrap();
+        assert_eq!(permissions.to_string(), "billing:read");
+
+        assert!(Roles::new()
+            .permission("a,b")
+            .seed(&database)
+            .await
+            .is_err());
+        // Sessions stored before the groups had names, so no group is named with digits alone
+        assert_eq!("12".parse::<Groups>().unwrap().to_string(), "Admin,User");
+        assert!(Roles::new()
+            .group("2024", &[])
+            .seed(&database)
+            .await
+            .is_err());
+        assert!(sqlx::query("INSERT INTO groups (name) VALUES ('12');")
+            .execute(&*database)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use std::marker::PhantomData;
+
+use askama::Template;
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+};
+
+use crate::{
+    auth::{basic::login_redirect, CurrentUser},
+    errors::AppError,
+    models::{User, UserSession},
+    sessions::Session,
+    state::{APIState, WebsiteState},
+};
+
+/// A group a route requires:
+///
+/// ```ignore
+/// struct Accountants;
+/// impl RequiredGroup for Accountants {
+///     const GROUP: &'static str = "Accountant";
+/// }
+/// ```
+pub trait RequiredGroup: Send + Sync + 'static {
+    const GROUP: &'static str;
+}
+
+/// A permission a route requires, granted by a group or to the user
+pub trait RequiredPermission: Send + Sync + 'static {
+    const PERMISSION: &'static str;
+}
+
+/// Rejects the users outside of the group. Websites redirect the anonymous users to the login
+/// and render the deny page for the others, APIs answer with a 401 or a 403.
+pub struct RequireGroup<G: RequiredGroup>(PhantomData<G>);
+
+/// Same as [`RequireGroup`] for a permission
+pub struct RequirePermission<P: RequiredPermission>(PhantomData<P>);
+
+#[derive(Template)]
+#[template(path = "auth/deny.html")]
+pub struct DenyPage<'a> {
+    pub kind: &'a str,
+    pub required: &'a str,
+    pub home_url: &'a str,
+}
+
+impl DenyPage<'_> {
+    fn into_response(self) -> Response {
+        match self.render() {
+            Ok(html) => (StatusCode::FORBIDDEN, Html(html)).into_response(),
+            Err(e) => AppError::from(e).into_response(),
+        }
+    }
+}
+
+/// The session's user, its groups and permissions are the ones cached at login
+async fn website_user(parts: &Parts, state: &WebsiteState) -> Result<UserSession, Response> {
+    let session = parts
+        .extensions
+        .get::<Session>()
+        .ok_or_else(|| AppError::Unauthorized.into_response())?;
+    if !session
+        .is_authenticated(state.database())
+        .await
+        .map_err(IntoResponse::into_response)?
+    {
+        return Err(login_redirect(state.config(), &parts.uri.to_string()));
+    }
+    Ok(session.user().await)
+}
+
+/// Loaded again, tokens don't carry the groups
+async fn api_user(parts: &mut Parts, state: &APIState) -> Result<User, AppError> {
+    let user = CurrentUser::from_request_parts(parts, state).await?;
+    User::get_by_pk(user.pk, &**state.database()).await
+}
+
+impl<G: RequiredGroup> FromRequestParts<WebsiteState> for RequireGroup<G> {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &WebsiteState,
+    ) -> Result<Self, Self::Rejection> {
+        let user = website_user(parts, state).await?;
+        if !user
+            .groups()
+            .is_some_and(|groups| groups.contains(G::GROUP))
+        {
+            return Err(DenyPage {
+                kind: "group",
+                required: G::GROUP,
+                home_url: &state.config().login_redirect_to,
+            }
+            .into_response());
+        }
+        Ok(Self(PhantomData))
+    }
+}
+
+impl<G: RequiredGroup> FromRequestParts<APIState> for RequireGroup<G> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &APIState,
+    ) -> Result<Self, Self::Rejection> {
+        if !api_user(parts, state).await?.groups.contains(G::GROUP) {
+            return Err(AppError::RoleError);
+        }
+        Ok(Self(PhantomData))
+    }
+}
+
+impl<P: RequiredPermission> FromRequestParts<WebsiteState> for RequirePermission<P> {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &WebsiteState,
+    ) -> Result<Self, Self::Rejection> {
+        if !website_user(parts, state)
+            .await?
+            .has_permission(P::PERMISSION)
+        {
+            return Err(DenyPage {
+                kind: "permission",
+                required: P::PERMISSION,
+                home_url: &state.config().login_redirect_to,
+            }
+            .into_response());
+        }
+        Ok(Self(PhantomData))
+    }
+}
+
+impl<P: RequiredPermission> FromRequestParts<APIState> for RequirePermission<P> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &APIState,
+    ) -> Result<Self, Self::Rejection> {
+        if !api_user(parts, state).await?.has_permission(P::PERMISSION) {
+            return Err(AppError::RoleError);
+        }
+        Ok(Self(PhantomData))
+    }
+}
+
+/// Layer for a router of a website or an API, after the authentication one:
+/// `middleware::from_fn_with_state(state.clone(), require_group::<Accountants>)`
+pub async fn require_group<G: RequiredGroup>(
+    _: RequireGroup<G>,
+    request: Request,
+    next: Next,
+) -> Response {
+    next.run(request).await
+}
+
+/// Layer for a router of a website or an API, after the authentication one:
+/// `middleware::from_fn_with_state(state.clone(), require_permission::<BillingWrite>)`
+pub async fn require_permission<P: RequiredPermission>(
+    _: RequirePermission<P>,
+    request: Request,
+    next: Next,
+) -> Response {
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::get, Extension, Router};
+    use sqlx::PgPool;
+
+    use crate::{
+        auth::{
+            test_utils::{self, TestSessions},
+            AuthMethod, Roles,
+        },
+        config::{APIConfig, ServiceConfig, WebsiteConfig},
+        database::Database,
+        state::SharedState,
+    };
+
+    use super::*;
+
+    struct Accountants;
+    impl RequiredGroup for Accountants {
+        const GROUP: &'static str = "Accountant";
+    }
+
+    struct BillingWrite;
+    impl RequiredPermission for BillingWrite {
+        const PERMISSION: &'static str = "billing:write";
+    }
+
+    async fn call<S: Clone + Send + Sync + 'static>(
+        router: Router<S>,
+        state: S,
+    ) -> (StatusCode, String) {
+        test_utils::get(router.with_state(state), "/billing").await
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_require_group_and_permission(pool: PgPool) {
+        let database: Database = pool.into();
+        Roles::new()
+            .group("Accountant", &["billing:write"])
+            .seed(&database)
+            .await
+            .unwrap();
+        let accountant = User::create_active("", &*database)
+            .await
+            .unwrap()
+            .add_to_group("Accountant", &*database)
+            .await
+            .unwrap();
+        let other = User::create_active("", &*database).await.unwrap();
+        let shared = SharedState::stub().with_database(database);
+
+        // Websites
+        let website = WebsiteState::new(WebsiteConfig::stub(), shared.clone());
+        let sessions = TestSessions::new().await;
+        let routes = |user: UserSession| async {
+            let session = sessions.create(user).await;
+            Router::new()
+                .route("/billing", get(|_: RequireGroup<Accountants>| async {}))
+                .layer(Extension(session))
+        };
+
+        let (status, location) = call(routes(UserSession::default()).await, website.clone()).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert!(location.starts_with(website.config().login_path()));
+        let (status, body) = call(routes(other.clone().for_session()).await, website.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.contains("Access denied"));
+        assert_eq!(
+            call(
+                routes(accountant.clone().for_session()).await,
+                website.clone()
+            )
+            .await
+            .0,
+            StatusCode::OK
+        );
+
+        // APIs
+        let api = APIState::new(APIConfig::stub(), shared).unwrap();
+        let routes = |user: Option<i64>| {
+            let router = Router::new().route("/billing", get(|| async {})).layer(
+                middleware::from_fn_with_state(api.clone(), require_permission::<BillingWrite>),
+            );
+            match user {
+                Some(pk) => router.layer(Extension(CurrentUser::new(pk, AuthMethod::Token))),
+                None => router,
+            }
+        };
+        assert_eq!(
+            call(routes(Some(accountant.pk)), api.clone()).await.0,
+            StatusCode::OK
+        );
+        let (status, body) = call(routes(Some(other.pk)), api.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.contains("\"message\""));
+        assert_eq!(
+            call(routes(None), api.clone()).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
@@ -0,0 +1,10 @@
+mod infrastructure;
+mod middlewares;
+mod policies;
+
+pub use infrastructure::{Permission, Roles};
+pub use middlewares::{
+    require_group, require_permission, DenyPage, RequireGroup, RequirePermission, RequiredGroup,
+    RequiredPermission,
+};
+pub use policies::{Action, Authorized, Delete, Edit, Policy, View};
@@ -0,0 +1,252 @@
+use std::{collections::HashMap, marker::PhantomData, ops::Deref, str::FromStr};
+
+use axum::{
+    extract::{FromRef, FromRequestParts, OptionalFromRequestParts, Path},
+    http::request::Parts,
+    RequestPartsExt,
+};
+
+use crate::{
+    auth::{AuthMethod, CurrentUser},
+    database::Database,
+    errors::AppError,
+    models::{User, UserSession},
+    sessions::Session,
+};
+
+/// Who can do what with a resource, for checks a group can't express like "only the owner can
+/// edit it". Loaded by [`Authorized`] from the path before the handler runs.
+pub trait Policy: Sized + Send + Sync + 'static {
+    type Id: FromStr + Send;
+
+    /// The path parameter with the id of the resource
+    const PATH_PARAM: &'static str = "id";
+
+    fn load(
+        id: Self::Id,
+        database: &Database,
+    ) -> impl std::future::Future<Output = Result<Option<Self>, AppError>> + Send;
+
+    fn can_view(&self, user: &UserSession) -> bool;
+
+    fn can_edit(&self, _user: &UserSession) -> bool {
+        false
+    }
+
+    fn can_delete(&self, _user: &UserSession) -> bool {
+        false
+    }
+}
+
+/// What the handler does with the resource
+pub trait Action: Send + Sync + 'static {
+    fn allows<T: Policy>(resource: &T, user: &UserSession) -> bool;
+}
+
+pub struct View;
+pub struct Edit;
+pub struct Delete;
+
+impl Action for View {
+    fn allows<T: Policy>(resource: &T, user: &UserSession) -> bool {
+        resource.can_view(user)
+    }
+}
+
+impl Action for Edit {
+    fn allows<T: Policy>(resource: &T, user: &UserSession) -> bool {
+        resource.can_edit(user)
+    }
+}
+
+impl Action for Delete {
+    fn allows<T: Policy>(resource: &T, user: &UserSession) -> bool {
+        resource.can_delete(user)
+    }
+}
+
+/// The resource of the path, when the user of the session, token or API key can do the action
+/// with it: `Authorized<Link, Edit>`. Anything else is a `DoesNotExist`, so the users can't
+/// learn which resources exist.
+pub struct Authorized<T: Policy, A: Action = View>(pub T, PhantomData<A>);
+
+impl<T: Policy, A: Action> Authorized<T, A> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: Policy, A: Action> Deref for Authorized<T, A> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// The anonymous users are checked too, the resource may be public
+async fn request_user(parts: &mut Parts, database: &Database) -> Result<UserSession, AppError> {
+    let current =
+        <CurrentUser as OptionalFromRequestParts<Database>>::from_request_parts(parts, database)
+            .await?;
+    match current {
+        None => Ok(UserSession::new_anonymous()),
+        Some(current) if current.method == AuthMethod::Session => {
+            match parts.extensions.get::<Session>() {
+                Some(session) => Ok(session.user().await),
+                None => Ok(UserSession::new_anonymous()),
+            }
+        }
+        Some(current) => Ok(User::get_by_pk(current.pk, &**database)
+            .await?
+            .for_session()),
+    }
+}
+
+impl<T, A, S> FromRequestParts<S> for Authorized<T, A>
+where
+    T: Policy,
+    A: Action,
+    Database: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let database = Database::from_ref(state);
+        let params: Path<HashMap<String, String>> =
+            parts.extract().await.map_err(|_| AppError::DoesNotExist)?;
+        let id = params
+            .get(T::PATH_PARAM)
+            .and_then(|id| id.parse::<T::Id>().ok())
+            .ok_or(AppError::DoesNotExist)?;
+
+        let user = request_user(parts, &database).await?;
+        match T::load(id, &database).await? {
+            Some(resource) if A::allows(&resource, &user) => Ok(Self(resource, PhantomData)),
+            _ => Err(AppError::DoesNotExist),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::StatusCode,
+        routing::{delete, get, put},
+        Extension, Router,
+    };
+    use sqlx::{FromRow, PgPool};
+
+    use crate::{
+        auth::test_utils,
+        models::{EmailAccount, Group},
+    };
+
+    use super::*;
+
+    #[derive(FromRow)]
+    struct Email {
+        email: String,
+        user_pk: i64,
+    }
+
+    impl Policy for Email {
+        type Id = i64;
+
+        async fn load(id: i64, database: &Database) -> Result<Option<Self>, AppError> {
+            Ok(
+                sqlx::query_as("SELECT email, user_pk FROM emails WHERE pk = $1;")
+                    .bind(id)
+                    .fetch_optional(&**database)
+                    .await?,
+            )
+        }
+
+        fn can_view(&self, user: &UserSession) -> bool {
+            user.pk() == Some(self.user_pk) || user.is_admin()
+        }
+
+        fn can_edit(&self, user: &UserSession) -> bool {
+            user.pk() == Some(self.user_pk)
+        }
+    }
+
+    async fn call(database: &Database, method: &str, uri: &str, user: Option<i64>) -> StatusCode {
+        let router = Router::new()
+            .route(
+                "/emails/{id}",
+                get(|email: Authorized<Email>| async move { email.email.clone() }),
+            )
+            .route("/emails/{id}", put(|_: Authorized<Email, Edit>| async {}))
+            .route(
+                "/emails/{id}",
+                delete(|_: Authorized<Email, Delete>| async {}),
+            );
+        let router = match user {
+            Some(pk) => router.layer(Extension(CurrentUser::new(pk, AuthMethod::Token))),
+            None => router,
+        };
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        test_utils::send(router.with_state(database.clone()), request)
+            .await
+            .0
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_authorized(pool: PgPool) {
+        let database: Database = pool.into();
+        let owner = User::create_active("", &*database).await.unwrap();
+        let email = EmailAccount::create_primary_active(
+            owner.clone(),
+            "policy@example.com".into(),
+            &*database,
+        )
+        .await
+        .unwrap();
+        let admin = User::create_active("", &*database)
+            .await
+            .unwrap()
+            .add_to_group(Group::ADMIN, &*database)
+            .await
+            .unwrap();
+        let other = User::create_active("", &*database).await.unwrap();
+        let uri = format!("/emails/{}", email.pk);
+
+        assert_eq!(
+            call(&database, "GET", &uri, Some(owner.pk)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(&database, "PUT", &uri, Some(owner.pk)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(&database, "GET", &uri, Some(admin.pk)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(&database, "PUT", &uri, Some(admin.pk)).await,
+            StatusCode::NOT_FOUND
+        );
+        // Denied, missing and invalid look the same
+        for (method, uri, user) in [
+            ("DELETE", uri.as_str(), Some(owner.pk)),
+            ("GET", uri.as_str(), Some(other.pk)),
+            ("GET", uri.as_str(), None),
+            ("GET", "/emails/0", Some(owner.pk)),
+            ("GET", "/emails/nope", Some(owner.pk)),
+        ] {
+            assert_eq!(
+                call(&database, method, uri, user).await,
+                StatusCode::NOT_FOUND
+            );
+        }
+    }
+}
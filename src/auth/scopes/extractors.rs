@@ -0,0 +1,136 @@
+use std::marker::PhantomData;
+
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::request::Parts,
+    middleware::Next,
+    response::Response,
+};
+
+use crate::errors::AppError;
+
+/// A scope a route requires, as a type to check it at the extractor or layer:
+///
+/// ```ignore
+/// struct BillingWrite;
+/// impl Scope for BillingWrite {
+///     const SCOPE: &'static str = "billing:write";
+/// }
+/// ```
+pub trait Scope: Send + Sync + 'static {
+    const SCOPE: &'static str;
+}
+
+/// The scopes of the token or API key of the request, inserted by `jwt_middleware` and
+/// `api_key_middleware`
+#[derive(Debug, Clone, Default)]
+pub struct GrantedScopes(pub Vec<String>);
+
+impl GrantedScopes {
+    /// The check behind every `has_scope` of the tokens and keys, scopes only match exactly
+    pub fn grants(scopes: &[String], scope: &str) -> bool {
+        scopes.iter().any(|granted| granted == scope)
+    }
+
+    pub fn has(&self, scope: &str) -> bool {
+        Self::grants(&self.0, scope)
+    }
+
+    pub fn require(&self, scope: &str) -> Result<(), AppError> {
+        if self.has(scope) {
+            Ok(())
+        } else {
+            Err(AppError::MissingScope(scope.to_owned()))
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for GrantedScopes
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Self>()
+            .cloned()
+            .ok_or(AppError::Unauthorized)
+    }
+}
+
+/// Extractor rejecting with a 403 when the scope wasn't granted
+pub struct RequireScope<T: Scope>(PhantomData<T>);
+
+impl<T, S> FromRequestParts<S> for RequireScope<T>
+where
+    T: Scope,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        GrantedScopes::from_request_parts(parts, state)
+            .await?
+            .require(T::SCOPE)?;
+        Ok(Self(PhantomData))
+    }
+}
+
+/// Layer for routes requiring the scope, after the authentication one:
+/// `middleware::from_fn(require_scope::<BillingWrite>)`
+pub async fn require_scope<T: Scope>(
+    scopes: GrantedScopes,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    scopes.require(T::SCOPE)?;
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, middleware, routing::get, Extension, Router};
+
+    use crate::auth::test_utils;
+
+    use super::*;
+
+    struct BillingWrite;
+    impl Scope for BillingWrite {
+        const SCOPE: &'static str = "billing:write";
+    }
+
+    async fn status(router: Router, scopes: &[&str]) -> (StatusCode, String) {
+        let router = router.layer(Extension(GrantedScopes(
+            scopes.iter().map(|scope| scope.to_string()).collect(),
+        )));
+        test_utils::get(router, "/").await
+    }
+
+    #[tokio::test]
+    async fn test_require_scope() {
+        let extractor = Router::new().route("/", get(|_: RequireScope<BillingWrite>| async {}));
+        let layer = Router::new()
+            .route("/", get(|| async {}))
+            .layer(middleware::from_fn(require_scope::<BillingWrite>));
+
+        for router in [extractor, layer] {
+            assert_eq!(
+                status(router.clone(), &["billing:write"]).await.0,
+                StatusCode::OK
+            );
+            let (code, body) = status(router, &["billing:read"]).await;
+            assert_eq!(code, StatusCode::FORBIDDEN);
+            assert!(body.contains("\"required_scope\":\"billing:write\""));
+        }
+
+        // Not authenticated at all
+        let router = Router::new().route("/", get(|_: RequireScope<BillingWrite>| async {}));
+        assert_eq!(
+            test_utils::get(router, "/").await.0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
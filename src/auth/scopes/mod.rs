@@ -0,0 +1,5 @@
+mod extractors;
+mod openapi;
+
+pub use extractors::{require_scope, GrantedScopes, RequireScope, Scope};
+pub use openapi::{SecuritySchemes, API_KEY_SECURITY, BEARER_SECURITY};
@@ -0,0 +1,38 @@
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify,
+};
+
+pub const BEARER_SECURITY: &str = "bearer";
+pub const API_KEY_SECURITY: &str = "api_key";
+
+/// Registers the JWT and API key schemes, so the routes can list the scopes they require:
+///
+/// ```ignore
+/// #[derive(OpenApi)]
+/// #[openapi(modifiers(&SecuritySchemes), paths(pay))]
+/// struct ApiDoc;
+///
+/// #[utoipa::path(post, path = "/pay", security(("bearer" = ["billing:write"]), ("api_key" = ["billing:write"])))]
+/// async fn pay(_: RequireScope<BillingWrite>) {}
+/// ```
+pub struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            BEARER_SECURITY,
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            API_KEY_SECURITY,
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-API-Key"))),
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::PathBuf;
+
+use axum::{body::Body, extract::Request, http::StatusCode, Router};
+use tower::ServiceExt;
+
+use crate::{
+    config::{ServiceConfig, WebsiteConfig},
+    database::Database,
+    models::UserSession,
+    sessions::{Session, Sessions},
+    state::{SharedState, WebsiteState},
+};
+
+/// Send the request, returns the status and the `location` of redirects or else the body
+pub async fn send(router: Router, request: Request<Body>) -> (StatusCode, String) {
+    let response = router.oneshot(request).await.unwrap();
+    let status = response.status();
+    let location = response
+        .headers()
+        .get("location")
+        .map(|l| l.to_str().unwrap().to_owned());
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (
+        status,
+        location.unwrap_or_else(|| String::from_utf8(body.to_vec()).unwrap()),
+    )
+}
+
+pub async fn get(router: Router, uri: &str) -> (StatusCode, String) {
+    send(router, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+/// Sessions in a SQLite file of their own, removed on drop
+pub struct TestSessions {
+    sessions: Sessions,
+    path: PathBuf,
+}
+
+impl TestSessions {
+    pub async fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("stefn-test-{}.sqlite", uuid::Uuid::new_v4()));
+        let sessions = Sessions::new(&format!("sqlite://{}", path.display()));
+        sessions.run_migrations().await;
+        Self { sessions, path }
+    }
+
+    /// A website on the database, keeping its sessions here
+    pub fn website(&self, database: Database) -> WebsiteState {
+        let mut config = WebsiteConfig::stub();
+        config.sessions_db = format!("sqlite://{}", self.path.display());
+        WebsiteState::new(config, SharedState::stub().with_database(database))
+    }
+
+    pub async fn create(&self, user: UserSession) -> Session {
+        self.sessions
+            .create_session(user, 30, "secret", None)
+            .await
+            .unwrap()
+    }
+}
+
+impl Drop for TestSessions {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
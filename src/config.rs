@@ -1,8 +1,17 @@
 use axum::http::HeaderValue;
+use chrono::Duration;
 use menva::FromEnv;
 use oauth2::Scope;
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use sha2::{Digest, Sha256};
 use std::{fmt, net::Ipv4Addr, str::FromStr};
 
+use crate::{
+    auth::{Keys, PasswordHasher, PasswordPolicy, TokenVault},
+    errors::AppError,
+};
+
 #[derive(Debug, Clone)]
 pub enum Env {
     Development,
@@ -115,13 +124,43 @@ pub struct WebsiteConfig {
     pub google_client_id: String,
     pub google_client_secret: String,
     google_scopes: String,
+    // Note: hex AES-256 key of the stored provider tokens, empty derives it from the session key.
+    // Refresh interval in minutes of the tokens about to expire, 0 disables it
+    oauth_token_key: String,
+    pub oauth_token_refresh_interval: u64,
+    // Note: minutes before an unfinished oauth flow expires. The callback path may contain
+    // `{provider}`, empty is `/oauth/{provider}/callback`
+    pub oauth_state_expiration: i64,
+    oauth_callback_path: String,
     // Note: currently cloudflare reCaptcha
     pub captcha_public_key: String,
     pub captcha_secret_key: String,
 
     pub email_validation: bool,
     pub email_validation_redirect: String,
+    // Note: expiration in hours and resend interval in minutes
+    pub email_validation_expiration: i64,
+    pub email_validation_resend_interval: i64,
+    pub email_validation_subject: String,
+    email_validation_sender: String,
     pub email_default_sender: String,
+    // Note: argon2 password hashing, a cost of 0 keeps the argon2 default
+    password_memory_cost: u32,
+    password_time_cost: u32,
+    password_parallelism: u32,
+    password_pepper: String,
+    // Note: stored in every hash to tell the peppers apart, up to 8 bytes, change it with the pepper
+    password_pepper_id: String,
+    pub password_min_length: usize,
+    // Note: days before a deleted account is removed for good
+    pub account_deletion_grace_days: i64,
+    // Note: magic link login, expiration in minutes
+    pub magic_link_expiration: i64,
+    pub magic_link_redirect: String,
+    pub magic_link_subject: String,
+    // Note: organization invitations, expiration in hours
+    pub organization_invitation_expiration: i64,
+    pub organization_invitation_subject: String,
     // Note: stripe payments
     pub stripe_public_key: String,
     pub stripe_webhook_secret: String,
@@ -131,6 +170,7 @@ impl WebsiteConfig {
     pub fn google_scopes(&self) -> Vec<Scope> {
         if self.google_scopes.is_empty() {
             vec![
+                Scope::new("openid".into()),
                 Scope::new("https://www.googleapis.com/auth/userinfo.email".into()),
                 Scope::new("https://www.googleapis.com/auth/userinfo.profile".into()),
             ]
@@ -144,6 +184,66 @@ impl WebsiteConfig {
     pub fn login_path(&self) -> &str {
         &self.login_path
     }
+
+    /// Where to go after logging in: `next` when it is a safe redirect, else
+    /// `login_redirect_to`. Every `next` and return URL of the auth flows goes through it.
+    pub fn safe_redirect<'a>(&'a self, next: Option<&'a str>) -> &'a str {
+        next.filter(|next| self.is_safe_redirect(next))
+            .unwrap_or(&self.login_redirect_to)
+    }
+
+    pub fn is_safe_redirect(&self, target: &str) -> bool {
+        self.allowed_origins()
+            .allows_redirect(target, &self.build_url(""))
+    }
+
+    pub fn oauth_callback_path(&self, provider: &str) -> String {
+        if self.oauth_callback_path.is_empty() {
+            format!("/oauth/{provider}/callback")
+        } else {
+            self.oauth_callback_path.replace("{provider}", provider)
+        }
+    }
+
+    pub fn password_hasher(&self) -> PasswordHasher {
+        PasswordHasher::new(
+            self.password_memory_cost,
+            self.password_time_cost,
+            self.password_parallelism,
+            &self.password_pepper,
+            &self.password_pepper_id,
+        )
+        .expect("The password hashing config is not valid")
+    }
+
+    pub fn token_vault(&self) -> TokenVault {
+        if self.oauth_token_key.is_empty() {
+            tracing::warn!(
+                "No oauth_token_key set, the provider tokens are encrypted with a key derived from the session key"
+            );
+            TokenVault::new(&Sha256::digest(format!(
+                "oauth-tokens:{}",
+                self.session_key
+            )))
+        } else {
+            TokenVault::new(
+                &hex::decode(&self.oauth_token_key).expect("The oauth token key is not hex"),
+            )
+        }
+        .expect("The oauth token key must have 32 bytes")
+    }
+
+    pub fn password_policy(&self) -> PasswordPolicy {
+        PasswordPolicy::new(self.password_min_length)
+    }
+
+    pub fn email_validation_sender(&self) -> &str {
+        if self.email_validation_sender.is_empty() {
+            &self.email_default_sender
+        } else {
+            &self.email_validation_sender
+        }
+    }
 }
 
 impl ServiceConfig for WebsiteConfig {
@@ -163,11 +263,31 @@ impl ServiceConfig for WebsiteConfig {
             google_client_id: "".into(),
             google_client_secret: "".into(),
             google_scopes: "scope1,scope2".into(),
+            oauth_token_key: "".into(),
+            oauth_token_refresh_interval: 10,
+            oauth_state_expiration: 10,
+            oauth_callback_path: "".into(),
             captcha_public_key: "1x00000000000000000000AA".into(),
             captcha_secret_key: "1x0000000000000000000000000000000AA".into(),
             email_validation: false,
             email_validation_redirect: "email_validation_redirect".into(),
+            email_validation_expiration: 48,
+            email_validation_resend_interval: 5,
+            email_validation_subject: "Validate your email".into(),
+            email_validation_sender: "".into(),
             email_default_sender: "email_default_sender@example.com".to_owned(),
+            password_memory_cost: 0,
+            password_time_cost: 0,
+            password_parallelism: 0,
+            password_pepper: "".into(),
+            password_pepper_id: "".into(),
+            password_min_length: 8,
+            account_deletion_grace_days: 30,
+            magic_link_expiration: 15,
+            magic_link_redirect: "magic_link_redirect".into(),
+            magic_link_subject: "Your login link".into(),
+            organization_invitation_expiration: 72,
+            organization_invitation_subject: "You have been invited".into(),
             stripe_public_key: "stripe_public_key".into(),
             stripe_webhook_secret: "stripe_webhook_secret".into(),
         }
@@ -200,6 +320,80 @@ pub struct APIConfig {
     pub sessions_db: String,
     pub session_cookie_name: String,
     pub session_expiration: i64,
+    // Note: access tokens expiration in minutes, refresh tokens in days
+    pub access_token_expiration: i64,
+    pub refresh_token_expiration: i64,
+    // Note: `kid:ALG:path[@active_from]` separated by commas, empty signs with the session key
+    jwt_keys: String,
+    // Note: seconds of clock skew accepted on `exp`
+    pub jwt_leeway: u64,
+    // Note: comma separated among iss, sub, aud, exp and nbf, empty requires all but nbf
+    jwt_required_claims: String,
+    // Note: argon2 password hashing of the token logins, the same as the website's
+    password_memory_cost: u32,
+    password_time_cost: u32,
+    password_parallelism: u32,
+    password_pepper: String,
+    password_pepper_id: String,
+}
+
+/// The claims `jwt_required_claims` can list
+const JWT_SPEC_CLAIMS: [&str; 5] = ["iss", "sub", "aud", "exp", "nbf"];
+
+impl APIConfig {
+    /// Checks the values the types don't, before the service starts
+    pub fn validate(&self) -> Result<(), AppError> {
+        if let Some(claim) = self
+            .jwt_required_claims()
+            .into_iter()
+            .find(|claim| !JWT_SPEC_CLAIMS.contains(claim))
+        {
+            return Err(AppError::custom_internal(&format!(
+                "Unknown claim in jwt_required_claims: {claim}"
+            )));
+        }
+        self.jwt_keys()?;
+        Ok(())
+    }
+
+    pub fn access_token_lifetime(&self) -> Duration {
+        Duration::minutes(self.access_token_expiration)
+    }
+
+    pub fn refresh_token_lifetime(&self) -> Duration {
+        Duration::days(self.refresh_token_expiration)
+    }
+
+    pub fn jwt_required_claims(&self) -> Vec<&str> {
+        if self.jwt_required_claims.is_empty() {
+            vec!["iss", "sub", "aud", "exp"]
+        } else {
+            self.jwt_required_claims
+                .split(',')
+                .map(str::trim)
+                .filter(|claim| !claim.is_empty())
+                .collect()
+        }
+    }
+
+    pub fn password_hasher(&self) -> PasswordHasher {
+        PasswordHasher::new(
+            self.password_memory_cost,
+            self.password_time_cost,
+            self.password_parallelism,
+            &self.password_pepper,
+            &self.password_pepper_id,
+        )
+        .expect("The password hashing config is not valid")
+    }
+
+    pub fn jwt_keys(&self) -> Result<Keys, AppError> {
+        if self.jwt_keys.is_empty() {
+            Ok(Keys::new(self.session_key.as_bytes()))
+        } else {
+            Keys::from_config(&self.jwt_keys)
+        }
+    }
 }
 
 impl ServiceConfig for APIConfig {
@@ -213,6 +407,16 @@ impl ServiceConfig for APIConfig {
             sessions_db: "./test-sessions.sqlite".to_owned(),
             session_cookie_name: "session_id".to_owned(),
             session_expiration: 30,
+            access_token_expiration: 15,
+            refresh_token_expiration: 30,
+            jwt_keys: "".into(),
+            jwt_leeway: 60,
+            jwt_required_claims: "".into(),
+            password_memory_cost: 0,
+            password_time_cost: 0,
+            password_parallelism: 0,
+            password_pepper: "".into(),
+            password_pepper_id: "".into(),
         }
     }
 
@@ -282,6 +486,38 @@ impl AllowedOrigins {
             .filter_map(|s| s.parse::<HeaderValue>().ok())
             .collect()
     }
+
+    /// Relative paths, or http(s) URLs of `own_origin` and the allowed origins. The `*` of CORS
+    /// does not apply to redirects.
+    pub fn allows_redirect(&self, target: &str, own_origin: &str) -> bool {
+        let decoded = percent_decode_str(target).decode_utf8_lossy();
+        // Browsers fold backslashes into slashes and drop tabs and newlines, `/\evil.com` and
+        // `/%09/evil.com` would be `//evil.com`
+        if target.chars().any(|c| c.is_whitespace() || c.is_control())
+            || decoded.chars().any(|c| c == '\\' || c.is_control())
+        {
+            return false;
+        }
+        if target.starts_with('/') {
+            return !target.starts_with("//") && !decoded.starts_with("//");
+        }
+
+        let Ok(url) = Url::parse(target) else {
+            return false;
+        };
+        if !matches!(url.scheme(), "http" | "https")
+            || !url.username().is_empty()
+            || url.password().is_some()
+        {
+            return false;
+        }
+        let origin = url.origin().ascii_serialization();
+        own_origin.trim_end_matches('/') == origin
+            || self
+                .0
+                .iter()
+                .any(|allowed| allowed != "*" && allowed.trim_end_matches('/') == origin)
+    }
 }
 
 #[cfg(test)]
@@ -299,6 +535,16 @@ mod tests {
             sessions_db: "./test-sessions.sqlite".to_owned(),
             session_cookie_name: "session_id".to_owned(),
             session_expiration: 30,
+            access_token_expiration: 15,
+            refresh_token_expiration: 30,
+            jwt_keys: "".into(),
+            jwt_leeway: 60,
+            jwt_required_claims: "".into(),
+            password_memory_cost: 0,
+            password_time_cost: 0,
+            password_parallelism: 0,
+            password_pepper: "".into(),
+            password_pepper_id: "".into(),
         };
 
         assert_eq!(config.build_url("/test"), "https://test.com/test");
@@ -315,6 +561,16 @@ mod tests {
             sessions_db: "./test-sessions.sqlite".to_owned(),
             session_cookie_name: "session_id".to_owned(),
             session_expiration: 30,
+            access_token_expiration: 15,
+            refresh_token_expiration: 30,
+            jwt_keys: "".into(),
+            jwt_leeway: 60,
+            jwt_required_claims: "".into(),
+            password_memory_cost: 0,
+            password_time_cost: 0,
+            password_parallelism: 0,
+            password_pepper: "".into(),
+            password_pepper_id: "".into(),
         };
 
         assert_eq!(config.build_url("/test"), "http://localhost:8000/test");
@@ -331,8 +587,72 @@ mod tests {
             sessions_db: "./test-sessions.sqlite".to_owned(),
             session_cookie_name: "session_id".to_owned(),
             session_expiration: 30,
+            access_token_expiration: 15,
+            refresh_token_expiration: 30,
+            jwt_keys: "".into(),
+            jwt_leeway: 60,
+            jwt_required_claims: "".into(),
+            password_memory_cost: 0,
+            password_time_cost: 0,
+            password_parallelism: 0,
+            password_pepper: "".into(),
+            password_pepper_id: "".into(),
         };
 
         assert_eq!(config.build_url("/test"), "https://192.168.1.10/test");
     }
+
+    #[test]
+    fn test_api_config_validation() {
+        let mut config = APIConfig::stub();
+        assert!(config.validate().is_ok());
+        config.jwt_required_claims = "iss, sub, nbf".into();
+        assert!(config.validate().is_ok());
+        config.jwt_required_claims = "iss,subject".into();
+        assert!(config.validate().is_err());
+        config.jwt_required_claims = "".into();
+        config.jwt_keys = "a:EdDSA:/nope.pem".into();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_safe_redirect() {
+        let mut config = WebsiteConfig::stub();
+        config.allowed_origins = "*,https://app.test.com".into();
+
+        for next in [
+            "/admin",
+            "/search?q=a%20b&page=2#top",
+            "https://test.com/dashboard",
+            "https://app.test.com/",
+        ] {
+            assert_eq!(config.safe_redirect(Some(next)), next);
+        }
+
+        for next in [
+            "//evil.com",
+            "///evil.com",
+            "/\\evil.com",
+            "\\\\evil.com",
+            "/%2F/evil.com",
+            "/%5Cevil.com",
+            "/%09/evil.com",
+            "/\t/evil.com",
+            " //evil.com",
+            "https://evil.com",
+            "https://test.com.evil.com/",
+            "https://test.com@evil.com/",
+            "https://user@test.com/",
+            "javascript:alert(1)",
+            "javascript%3Aalert(1)",
+            "JaVaScRiPt:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "%2F%2Fevil.com",
+            "evil.com",
+            "",
+        ] {
+            assert_eq!(config.safe_redirect(Some(next)), "admin", "{next}");
+        }
+        assert_eq!(config.safe_redirect(None), "admin");
+    }
 }
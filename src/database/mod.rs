@@ -1,3 +1,5 @@
 mod service;
+mod tenancy;
 
 pub use service::{Database, IpsDatabase};
+pub use tenancy::{TenantPolicy, TENANT_SETTING};
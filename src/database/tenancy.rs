@@ -0,0 +1,192 @@
+use sqlx::{Executor, Postgres, Transaction};
+
+use crate::{errors::AppError, log_and_wrap_custom_internal};
+
+use super::Database;
+
+/// The setting read by the policies of [`TenantPolicy`]
+pub const TENANT_SETTING: &str = "app.tenant_id";
+
+impl Database {
+    /// A transaction where the row-level security policies only let through the rows of
+    /// `tenant_id`. It is `SET LOCAL`, so the setting ends with the transaction and never leaks
+    /// to the next user of the pooled connection.
+    pub async fn start_tenant_transaction(
+        &self,
+        tenant_id: i64,
+    ) -> Result<Transaction<'static, Postgres>, AppError> {
+        let mut tx = self
+            .begin()
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        // SET LOCAL does not take parameters, set_config with is_local is the same
+        sqlx::query("SELECT set_config($1, $2, true);")
+            .bind(TENANT_SETTING)
+            .bind(tenant_id.to_string())
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(tx)
+    }
+}
+
+/// Row-level security of a tenant table: rows are only visible and writable when their tenant
+/// column matches `app.tenant_id`. Without a tenant set nothing matches, so a query outside
+/// `start_tenant_transaction` fails closed instead of reading every tenant.
+///
+/// ```rust,ignore
+/// TenantPolicy::new("invoices").apply(&database).await?;
+/// TenantPolicy::new("projects").column("organization_pk").apply(&database).await?;
+/// ```
+///
+/// Superusers and roles with `BYPASSRLS` skip the policies, connect the app with another role.
+#[derive(Debug, Clone)]
+pub struct TenantPolicy {
+    table: String,
+    column: String,
+}
+
+impl TenantPolicy {
+    pub fn new(table: &str) -> Self {
+        Self {
+            table: table.to_owned(),
+            column: "tenant_id".to_owned(),
+        }
+    }
+
+    pub fn column(mut self, column: &str) -> Self {
+        self.column = column.to_owned();
+        self
+    }
+
+    pub fn policy_name(&self) -> String {
+        format!("{}_tenant_isolation", self.table)
+    }
+
+    /// The statements, to paste in a migration. The table owner is subject to them too.
+    pub fn sql(&self) -> Result<String, AppError> {
+        for identifier in [&self.table, &self.column] {
+            if !is_identifier(identifier) {
+                return Err(AppError::custom_internal(&format!(
+                    "Invalid identifier for a tenant policy: {identifier}"
+                )));
+            }
+        }
+        let condition = format!(
+            "{} = NULLIF(current_setting('{TENANT_SETTING}', true), '')::bigint",
+            self.column
+        );
+        Ok(format!(
+            "ALTER TABLE {table} ENABLE ROW LEVEL SECURITY;
+ALTER TABLE {table} FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS {policy} ON {table};
+CREATE POLICY {policy} ON {table} USING ({condition}) WITH CHECK ({condition});",
+            table = self.table,
+            policy = self.policy_name(),
+        ))
+    }
+
+    /// Idempotent, it can run at every start
+    pub async fn apply(&self, database: &Database) -> Result<(), AppError> {
+        let sql = self.sql()?;
+        let mut tx = database.start_transaction().await?;
+        // Several statements, so no prepared statement
+        tx.execute(sql.as_str())
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        tx.commit()
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(())
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+
+    #[test]
+    fn test_tenant_policy_sql() {
+        let sql = TenantPolicy::new("projects")
+            .column("organization_pk")
+            .sql()
+            .unwrap();
+        assert!(sql.contains("CREATE POLICY projects_tenant_isolation ON projects"));
+        assert!(sql.contains("organization_pk = NULLIF(current_setting('app.tenant_id', true)"));
+        assert!(TenantPolicy::new("projects; DROP TABLE users")
+            .sql()
+            .is_err());
+        assert!(TenantPolicy::new("projects").column("1d").sql().is_err());
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_tenant_isolation(pool: PgPool) {
+        let database: Database = pool.into();
+        let role = format!("stefn_tenant_{}", uuid::Uuid::new_v4().simple());
+        sqlx::raw_sql(&format!(
+            "CREATE TABLE notes (pk bigserial PRIMARY KEY, tenant_id BIGINT NOT NULL, body TEXT NOT NULL);
+            INSERT INTO notes (tenant_id, body) VALUES (1, 'first'), (2, 'second');
+            CREATE ROLE {role} NOLOGIN;
+            GRANT SELECT, INSERT ON notes TO {role};
+            GRANT USAGE ON SEQUENCE notes_pk_seq TO {role};"
+        ))
+        .execute(&*database)
+        .await
+        .unwrap();
+        // Roles belong to the cluster and outlive the test database, the checks run in a task so
+        // a failed assert still drops it
+        let checks = tokio::spawn({
+            let database = database.clone();
+            let role = role.clone();
+            async move {
+                TenantPolicy::new("notes").apply(&database).await.unwrap();
+                TenantPolicy::new("notes").apply(&database).await.unwrap();
+
+                // The tests connect as a superuser, which skips the policies
+                let set_role = format!("SET LOCAL ROLE {role};");
+                let mut tx = database.start_tenant_transaction(1).await.unwrap();
+                tx.execute(set_role.as_str()).await.unwrap();
+                let bodies: Vec<String> = sqlx::query_scalar("SELECT body FROM notes;")
+                    .fetch_all(&mut *tx)
+                    .await
+                    .unwrap();
+                assert_eq!(bodies, vec!["first".to_owned()]);
+                assert!(
+                    sqlx::query("INSERT INTO notes (tenant_id, body) VALUES (2, 'intruder');")
+                        .execute(&mut *tx)
+                        .await
+                        .is_err()
+                );
+                tx.rollback().await.unwrap();
+
+                let mut tx = database.start_transaction().await.unwrap();
+                tx.execute(set_role.as_str()).await.unwrap();
+                let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM notes;")
+                    .fetch_one(&mut *tx)
+                    .await
+                    .unwrap();
+                assert_eq!(count, 0);
+                tx.rollback().await.unwrap();
+            }
+        })
+        .await;
+
+        sqlx::raw_sql(&format!("DROP TABLE notes; DROP ROLE {role};"))
+            .execute(&*database)
+            .await
+            .unwrap();
+        if let Err(e) = checks {
+            std::panic::resume_unwind(e.into_panic());
+        }
+    }
+}
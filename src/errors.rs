@@ -2,20 +2,19 @@ use std::num::ParseIntError;
 
 use axum::{
     extract::rejection::JsonRejection,
-    http::StatusCode,
+    http::{header::WWW_AUTHENTICATE, StatusCode},
     response::{IntoResponse, Json, Response},
 };
 use maxminddb::MaxMindDBError;
 
-use sqlx::error::DatabaseError;
-
-use crate::service::ErrorMessage;
+use crate::service::{ErrorMessage, ScopeErrorMessage};
 
 #[derive(Debug)]
 pub enum AppError {
     //
     WrongPassword(argon2::password_hash::Error),
     ErrorHashingPassword(argon2::password_hash::Error),
+    WeakPassword(String),
     // The request body contained invalid JSON
     JsonRejection(JsonRejection),
     JsonEnumDeserialization(serde_json::Error),
@@ -39,6 +38,9 @@ pub enum AppError {
     //
     RoleError,
     Unauthorized,
+    ImpersonationForbidden,
+    AccountDeactivated,
+    MissingScope(String),
     //
     IpError(MaxMindDBError),
     IpDataNotFound,
@@ -74,6 +76,7 @@ impl AppError {
 
             Self::ErrorHashingPassword(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
             Self::WrongPassword(err) => (StatusCode::NOT_FOUND, err.to_string()),
+            Self::WeakPassword(msg) => (StatusCode::BAD_REQUEST, msg),
             //
             Self::JsonEnumDeserialization(err) => (StatusCode::BAD_REQUEST, err.to_string()),
             Self::JsonRejection(rejection) => (rejection.status(), rejection.body_text()),
@@ -81,8 +84,19 @@ impl AppError {
             Self::JWTError(err) => (StatusCode::UNAUTHORIZED, err.to_string()),
             Self::JWTModified(err) => (StatusCode::UNAUTHORIZED, err.to_string()),
 
-            Self::RoleError => (StatusCode::UNAUTHORIZED, "Not authorized".to_string()),
+            Self::RoleError => (StatusCode::FORBIDDEN, "Forbidden".to_string()),
             Self::Unauthorized => (StatusCode::UNAUTHORIZED, "Not authorized".to_string()),
+            Self::ImpersonationForbidden => (
+                StatusCode::FORBIDDEN,
+                "Not allowed while impersonating a user".to_string(),
+            ),
+            Self::AccountDeactivated => (
+                StatusCode::FORBIDDEN,
+                "This account has been deactivated".to_string(),
+            ),
+            Self::MissingScope(scope) => {
+                (StatusCode::FORBIDDEN, format!("Missing the scope {scope}"))
+            }
 
             Self::DoesNotExist => (StatusCode::NOT_FOUND, "Not found".into()),
             Self::UniqueViolation(msg) => (StatusCode::BAD_REQUEST, msg),
@@ -107,6 +121,19 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         tracing::error!(error=?&self);
 
+        // RFC 6750, the client can ask for a token with the scope
+        if let Self::MissingScope(scope) = self {
+            return (
+                StatusCode::FORBIDDEN,
+                [(
+                    WWW_AUTHENTICATE,
+                    format!("Bearer error=\"insufficient_scope\", scope=\"{scope}\""),
+                )],
+                Json(ScopeErrorMessage::new(scope)),
+            )
+                .into_response();
+        }
+
         let (status, message) = self.get_status_code_and_message();
 
         (status, Json(ErrorMessage::new(message))).into_response()
@@ -159,8 +186,7 @@ impl From<sqlx::Error> for AppError {
         match error {
             sqlx::Error::Database(db_err) => {
                 // Check for specific database error codes
-                let sqlite_error = db_err.downcast_ref::<sqlx::sqlite::SqliteError>();
-                match sqlite_error.kind() {
+                match db_err.kind() {
                     sqlx::error::ErrorKind::UniqueViolation => {
                         AppError::UniqueViolation(db_err.message().to_string())
                     }
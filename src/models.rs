@@ -1,46 +1,106 @@
-use std::{
-    error::Error,
-    fmt,
-    str::FromStr,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::{error::Error, fmt, str::FromStr};
 
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
-use sqlx::{postgres::PgRow, prelude::FromRow, sqlite::SqliteRow, Decode, PgExecutor, Row, Type};
+use sqlx::{
+    postgres::PgRow, prelude::FromRow, sqlite::SqliteRow, Decode, PgConnection, PgExecutor, Row,
+    Type,
+};
 
 use crate::{database::Database, errors::AppError, log_and_wrap_custom_internal};
 
-#[derive(Type, Debug, Copy, Clone, Deserialize, Serialize, PartialEq)]
-#[repr(i64)]
-pub enum Group {
-    Admin = 1,
-    User = 2,
+/// A group of users, managed in the database. Apps declare their own with `Roles`, the
+/// built-in ones are [`Group::ADMIN`] and [`Group::USER`].
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Group {
+    pub pk: i64,
+    pub name: String,
 }
 
-impl FromStr for Group {
-    type Err = ();
+impl Group {
+    pub const ADMIN: &'static str = "Admin";
+    pub const USER: &'static str = "User";
 
-    fn from_str(input: &str) -> Result<Self, Self::Err> {
-        match input {
-            "Admin" | "1" => Ok(Self::Admin),
-            "User" | "2" => Ok(Self::User),
-            _ => Err(()),
-        }
+    pub async fn find_by_name<'e, E: PgExecutor<'e>>(
+        name: &str,
+        executor: E,
+    ) -> Result<Option<Self>, AppError> {
+        sqlx::query_as("SELECT pk, name FROM groups WHERE name = $1;")
+            .bind(name)
+            .fetch_optional(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    pub async fn all<'e, E: PgExecutor<'e>>(executor: E) -> Result<Vec<Self>, AppError> {
+        sqlx::query_as("SELECT pk, name FROM groups ORDER BY pk;")
+            .fetch_all(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
     }
 }
 
-impl Group {
-    pub fn name(&self) -> &str {
-        match self {
-            Self::Admin => "Admin",
-            Self::User => "User",
+/// Names aggregated with `STRING_AGG(.., ',')` in the queries and stored the same way in the
+/// sessions database
+macro_rules! name_set {
+    ($name:ident) => {
+        #[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq)]
+        pub struct $name(Vec<String>);
+
+        impl<DB: sqlx::Database> Type<DB> for $name
+        where
+            String: Type<DB>,
+        {
+            fn type_info() -> <DB as sqlx::Database>::TypeInfo {
+                <String as Type<DB>>::type_info()
+            }
         }
-    }
+
+        impl<'r, DB: sqlx::Database> Decode<'r, DB> for $name
+        where
+            // we want to delegate some of the work to string decoding so let's make sure strings
+            // are supported by the database
+            &'r str: Decode<'r, DB>,
+        {
+            fn decode(
+                value: <DB as sqlx::Database>::ValueRef<'r>,
+            ) -> Result<Self, Box<dyn Error + 'static + Send + Sync>> {
+                <&str as Decode<DB>>::decode(value)?.parse()
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.0.join(","))
+            }
+        }
+
+        impl $name {
+            pub fn push(&mut self, name: &str) {
+                if !self.contains(name) {
+                    self.0.push(name.to_owned());
+                }
+            }
+
+            pub fn contains(&self, name: &str) -> bool {
+                self.0.iter().any(|n| n == name)
+            }
+
+            pub fn iter(&self) -> impl Iterator<Item = &str> {
+                self.0.iter().map(String::as_str)
+            }
+        }
+
+        impl<S: Into<String>> FromIterator<S> for $name {
+            fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Self {
+                Self(iter.into_iter().map(Into::into).collect())
+            }
+        }
+    };
 }
 
-#[derive(Debug, Deserialize, Serialize, Default, Clone)]
-pub struct Groups(Vec<Group>);
+name_set!(Groups);
+name_set!(Permissions);
 
 impl FromRow<'_, SqliteRow> for Groups {
     fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
@@ -56,69 +116,30 @@ impl FromRow<'_, PgRow> for Groups {
     }
 }
 
-impl<DB: sqlx::Database> Type<DB> for Groups
-where
-    String: Type<DB>,
-{
-    fn type_info() -> <DB as sqlx::Database>::TypeInfo {
-        <String as Type<DB>>::type_info()
-    }
-}
-
-impl<'r, DB: sqlx::Database> Decode<'r, DB> for Groups
-where
-    // we want to delegate some of the work to string decoding so let's make sure strings
-    // are supported by the database
-    &'r str: Decode<'r, DB>,
-{
-    fn decode(
-        value: <DB as sqlx::Database>::ValueRef<'r>,
-    ) -> Result<Self, Box<dyn Error + 'static + Send + Sync>> {
-        // the interface of ValueRef is largely unstable at the moment
-        // so this is not directly implementable
-
-        // however, you can delegate to a type that matches the format of the type you want
-        // to decode (such as a UTF-8 string)
-
-        let value = <&str as Decode<DB>>::decode(value)?;
-
-        // now you can parse this into your type (assuming there is a `FromStr`)
-
-        value.parse()
-    }
-}
-
 impl FromStr for Groups {
     type Err = Box<dyn Error + 'static + Send + Sync>;
 
     fn from_str(input: &str) -> Result<Self, Self::Err> {
-        Ok(Self(
-            input
-                .split(",")
-                .filter_map(|g| g.parse::<Group>().ok())
-                .collect(),
-        ))
-    }
-}
-
-impl fmt::Display for Groups {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let r: String = self
-            .0
-            .iter()
-            .filter_map(|&g| std::char::from_digit(g as u32, 10))
-            .collect();
-        write!(f, "{}", r)
+        // Sessions created before the groups were dynamic store their pks as digits
+        if !input.is_empty() && input.chars().all(|c| c.is_ascii_digit()) {
+            return Ok(input
+                .chars()
+                .filter_map(|c| match c {
+                    '1' => Some(Group::ADMIN),
+                    '2' => Some(Group::USER),
+                    _ => None,
+                })
+                .collect());
+        }
+        Ok(input.split(',').filter(|g| !g.is_empty()).collect())
     }
 }
 
-impl Groups {
-    pub fn push(&mut self, group: Group) {
-        self.0.push(group);
-    }
+impl FromStr for Permissions {
+    type Err = Box<dyn Error + 'static + Send + Sync>;
 
-    pub fn contains(&self, group: &Group) -> bool {
-        self.0.contains(group)
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Ok(input.split(',').filter(|p| !p.is_empty()).collect())
     }
 }
 
@@ -152,14 +173,35 @@ impl UserSession {
         self.0.as_ref().map(|u| &u.groups)
     }
 
+    /// Cached when the user logs in
+    pub fn permissions(&self) -> Option<&Permissions> {
+        self.0.as_ref().map(|u| &u.permissions)
+    }
+
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions().is_some_and(|p| p.contains(permission))
+    }
+
     pub fn pk(&self) -> Option<i64> {
         self.0.as_ref().map(|u| u.pk)
     }
 
+    pub fn is_admin(&self) -> bool {
+        self.groups().is_some_and(|g| g.contains(Group::ADMIN))
+    }
+
     pub fn is_some(&self) -> bool {
         self.0.is_some()
     }
 
+    /// Build the app's [`UserModel`] of the logged user, `None` for anonymous sessions
+    pub async fn load<U: UserModel>(&self, database: &Database) -> Result<Option<U>, AppError> {
+        match &self.0 {
+            Some(user) => U::load(user.clone(), database).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
     pub async fn is_authenticated(&self, database: &Database) -> Result<bool, AppError> {
         match &self.0 {
             Some(user) => user.is_authenticated(database).await,
@@ -180,9 +222,62 @@ pub struct User {
     #[sqlx(rename = "user_pk")]
     pub pk: i64,
     pub groups: Groups,
+    /// Granted by the groups or directly to the user
+    #[sqlx(default)]
+    pub permissions: Permissions,
+}
+/// The user the app works with. Implement it to attach your own fields (name, locale, tenant...)
+/// to the base [`User`]: it is loaded once per request by `login_required_model_middleware` and
+/// handed to the handlers as an `Extension`, and it is what `Ingress`, `EmailValidation`,
+/// `MagicLink` and `GoogleOauthCallbackHook` put in the session.
+pub trait UserModel: Clone + Send + Sync + 'static {
+    fn pk(&self) -> i64;
+
+    fn groups(&self) -> &Groups;
+
+    fn permissions(&self) -> &Permissions;
+
+    fn load(
+        user: User,
+        database: &Database,
+    ) -> impl std::future::Future<Output = Result<Self, AppError>> + Send;
+
+    fn is_admin(&self) -> bool {
+        self.groups().contains(Group::ADMIN)
+    }
+
+    fn has_permission(&self, permission: &str) -> bool {
+        self.permissions().contains(permission)
+    }
+
+    /// Only the pk, the groups and the permissions are stored in the session, the rest is
+    /// loaded again
+    fn to_session(&self) -> UserSession {
+        UserSession::new_authenticated(User {
+            pk: self.pk(),
+            groups: self.groups().clone(),
+            permissions: self.permissions().clone(),
+        })
+    }
+}
+
+impl UserModel for User {
+    fn pk(&self) -> i64 {
+        self.pk
+    }
+
+    fn groups(&self) -> &Groups {
+        &self.groups
+    }
+
+    fn permissions(&self) -> &Permissions {
+        &self.permissions
+    }
+
+    async fn load(user: User, _database: &Database) -> Result<Self, AppError> {
+        Ok(user)
+    }
 }
-// TODO: by default start with a user like this one to manage state and auth. Create a nice trait
-// and make it public so you can hook your own auth/state struct
 
 impl User {
     pub fn for_session(self) -> UserSession {
@@ -199,13 +294,30 @@ impl User {
         .map_err(|e| log_and_wrap_custom_internal!(e))
     }
 
+    pub async fn get_by_pk<'e, E: PgExecutor<'e>>(pk: i64, executor: E) -> Result<Self, AppError> {
+        sqlx::query_as(
+            "SELECT users.pk AS user_pk, COALESCE(STRING_AGG(groups.name, ',' ORDER BY groups.pk), '') AS groups,
+                COALESCE((SELECT STRING_AGG(name, ',') FROM user_permissions WHERE user_permissions.user_pk = users.pk), '') AS permissions
+            FROM users
+            LEFT JOIN users_groups_m2m ON users_groups_m2m.user_pk = users.pk
+            LEFT JOIN groups ON groups.pk = users_groups_m2m.group_pk
+            WHERE users.pk = $1
+            GROUP BY users.pk;",
+        )
+        .bind(pk)
+        .fetch_one(executor)
+        .await
+        .map_err(AppError::from)
+    }
+
+    /// An empty password creates a user who can't log in with one
     pub async fn create<'e, E: PgExecutor<'e>>(
         password: &str,
         activated_at: Option<NaiveDateTime>,
         executor: E,
     ) -> Result<Self, AppError> {
         let pk = sqlx::query_scalar(
-            "INSERT INTO users (password, activated_at) VALUES ($1, $2) RETURNING pk;",
+            "INSERT INTO users (password, activated_at) VALUES (NULLIF($1, ''), $2) RETURNING pk;",
         )
         .bind(password)
         .bind(activated_at)
@@ -215,6 +327,7 @@ impl User {
         Ok(Self {
             pk,
             groups: Groups::default(),
+            permissions: Permissions::default(),
         })
     }
 
@@ -233,32 +346,51 @@ impl User {
         Self::create(password, Some(activated_at), executor).await
     }
 
+    /// The group must exist, its permissions are added to the user's
     pub async fn add_to_group<'e, E: PgExecutor<'e>>(
         mut self,
-        group: Group,
+        group: &str,
         executor: E,
     ) -> Result<Self, AppError> {
-        if self.groups.contains(&group) {
+        if self.groups.contains(group) {
             return Ok(self);
         }
 
-        sqlx::query("INSERT INTO users_groups_m2m (user_pk, group_pk) VALUES ($1, $2);")
-            .bind(self.pk)
-            .bind(group as i64)
-            .execute(executor)
-            .await
-            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        let (added, permissions): (i64, Option<String>) = sqlx::query_as(
+            "WITH added AS (
+                INSERT INTO users_groups_m2m (user_pk, group_pk) SELECT $1, pk FROM groups WHERE name = $2 RETURNING group_pk
+            )
+            SELECT (SELECT COUNT(*) FROM added), (
+                SELECT STRING_AGG(permissions.name, ',') FROM groups_permissions_m2m
+                INNER JOIN permissions ON permissions.pk = groups_permissions_m2m.permission_pk
+                WHERE groups_permissions_m2m.group_pk IN (SELECT group_pk FROM added)
+            );",
+        )
+        .bind(self.pk)
+        .bind(group)
+        .fetch_one(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        if added == 0 {
+            return Err(AppError::DoesNotExist);
+        }
 
         self.groups.push(group);
+        for permission in permissions.unwrap_or_default().split(',') {
+            if !permission.is_empty() {
+                self.permissions.push(permission);
+            }
+        }
         Ok(self)
     }
 
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.contains(permission)
+    }
+
     pub async fn set_to_active<'e, E: PgExecutor<'e>>(&self, executor: E) -> Result<u64, AppError> {
-        let activated_at = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs() as i64;
-        sqlx::query("UPDATE users SET activated_at = $1 WHERE pk = $2;")
+        let activated_at = chrono::Utc::now().naive_utc();
+        sqlx::query("UPDATE users SET activated_at = $1 WHERE pk = $2 AND activated_at IS NULL;")
             .bind(activated_at)
             .bind(self.pk)
             .execute(executor)
@@ -267,16 +399,182 @@ impl User {
             .map(|q| q.rows_affected())
     }
 
+    /// Users deactivated by an admin can only be reactivated by an admin. Their API keys are
+    /// revoked for good.
+    pub async fn deactivate<'e, E: PgExecutor<'e>>(
+        &self,
+        by_admin: bool,
+        executor: E,
+    ) -> Result<u64, AppError> {
+        // The API keys are revoked in the same statement
+        sqlx::query_scalar(
+            "WITH deactivated AS (
+                UPDATE users SET deactivated_at = COALESCE(deactivated_at, $1), deactivated_by_admin = deactivated_by_admin OR $2 WHERE pk = $3 RETURNING pk
+            ),
+            revoked AS (
+                UPDATE api_keys SET revoked_at = $1 WHERE user_pk IN (SELECT pk FROM deactivated) AND revoked_at IS NULL
+            )
+            SELECT COUNT(*) FROM deactivated;",
+        )
+        .bind(chrono::Utc::now().naive_utc())
+        .bind(by_admin)
+        .bind(self.pk)
+        .fetch_one(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+        .map(|count: i64| count as u64)
+    }
+
+    pub async fn reactivate<'e, E: PgExecutor<'e>>(&self, executor: E) -> Result<u64, AppError> {
+        sqlx::query(
+            "UPDATE users SET deactivated_at = NULL, deactivated_by_admin = FALSE, delete_after = NULL WHERE pk = $1;",
+        )
+        .bind(self.pk)
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+        .map(|q| q.rows_affected())
+    }
+
+    /// At login: reactivate the account, and cancel its deletion, returning whether it was
+    /// deactivated. The accounts an admin deactivated stay so and can't log in.
+    pub async fn reactivate_self<'e, E: PgExecutor<'e>>(
+        &self,
+        executor: E,
+    ) -> Result<u64, AppError> {
+        let (blocked, reactivated): (bool, i64) = sqlx::query_as(
+            "WITH target AS (
+                SELECT pk, deactivated_at IS NOT NULL AND deactivated_by_admin AS blocked FROM users WHERE pk = $1
+            ),
+            reactivated AS (
+                UPDATE users SET deactivated_at = NULL, delete_after = NULL FROM target
+                WHERE users.pk = target.pk AND users.deactivated_at IS NOT NULL AND NOT target.blocked
+                RETURNING users.pk
+            )
+            SELECT target.blocked, (SELECT COUNT(*) FROM reactivated) FROM target;",
+        )
+        .bind(self.pk)
+        .fetch_optional(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))?
+        .ok_or(AppError::DoesNotExist)?;
+        if blocked {
+            return Err(AppError::AccountDeactivated);
+        }
+        Ok(reactivated as u64)
+    }
+
+    /// Deactivate the account now and delete it for good after `grace_days`
+    pub async fn schedule_deletion<'e, E: PgExecutor<'e>>(
+        &self,
+        grace_days: i64,
+        executor: E,
+    ) -> Result<u64, AppError> {
+        let now = chrono::Utc::now().naive_utc();
+        sqlx::query(
+            "UPDATE users SET deactivated_at = COALESCE(deactivated_at, $1), delete_after = $2 WHERE pk = $3;",
+        )
+        .bind(now)
+        .bind(now + chrono::Duration::days(grace_days))
+        .bind(self.pk)
+        .execute(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+        .map(|q| q.rows_affected())
+    }
+
+    /// The users whose grace period is over
+    pub async fn scheduled_for_deletion<'e, E: PgExecutor<'e>>(
+        executor: E,
+    ) -> Result<Vec<i64>, AppError> {
+        sqlx::query_scalar("SELECT pk FROM users WHERE delete_after < $1;")
+            .bind(chrono::Utc::now().naive_utc())
+            .fetch_all(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Hard delete the users among `pks` whose grace period is still over, returning their pks.
+    /// Their identities and provider tokens go with them, revoke the tokens first.
+    pub async fn delete_scheduled<'e, E: PgExecutor<'e>>(
+        pks: &[i64],
+        executor: E,
+    ) -> Result<Vec<i64>, AppError> {
+        sqlx::query_scalar(
+            "DELETE FROM users WHERE pk = ANY($1) AND delete_after < $2 RETURNING pk;",
+        )
+        .bind(pks)
+        .bind(chrono::Utc::now().naive_utc())
+        .fetch_all(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Whether the user can log in with a password
+    pub async fn has_password<'e, E: PgExecutor<'e>>(&self, executor: E) -> Result<bool, AppError> {
+        Ok(
+            sqlx::query_scalar("SELECT password IS NOT NULL FROM users WHERE pk = $1;")
+                .bind(self.pk)
+                .fetch_one(executor)
+                .await?,
+        )
+    }
+
+    /// The stored password hash, `None` for the users that log in with a provider only
+    pub async fn password<'e, E: PgExecutor<'e>>(
+        &self,
+        executor: E,
+    ) -> Result<Option<String>, AppError> {
+        sqlx::query_scalar("SELECT password FROM users WHERE pk = $1;")
+            .bind(self.pk)
+            .fetch_one(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Stop logging in with a password, only when a provider is linked to the account
+    pub async fn remove_password(&self, tx: &mut PgConnection) -> Result<(), AppError> {
+        let methods = LoginMethodCounts::lock(self.pk, tx).await?;
+        if methods.identities == 0 {
+            return Err(AppError::custom_bad_request(
+                "You can't remove your last login method",
+            ));
+        }
+        sqlx::query("UPDATE users SET password = NULL WHERE pk = $1;")
+            .bind(self.pk)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(())
+    }
+
+    pub async fn set_password<'e, E: PgExecutor<'e>>(
+        &self,
+        password: &str,
+        executor: E,
+    ) -> Result<u64, AppError> {
+        sqlx::query("UPDATE users SET password = $1 WHERE pk = $2;")
+            .bind(password)
+            .bind(self.pk)
+            .execute(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+            .map(|q| q.rows_affected())
+    }
+
+    /// Users without a password are not found, they can't log in with one
     pub async fn find_by_email_with_password(
         email: &str,
         database: &Database,
     ) -> Result<Option<UserWithPassword>, AppError> {
         sqlx::query_as(
-            "SELECT emails.user_pk, STRING_AGG(users_groups_m2m.group_pk::TEXT, ',') as groups, users.password
+            "SELECT emails.user_pk, COALESCE(STRING_AGG(groups.name, ',' ORDER BY groups.pk), '') as groups, users.password,
+                    COALESCE((SELECT STRING_AGG(name, ',') FROM user_permissions WHERE user_permissions.user_pk = emails.user_pk), '') AS permissions
                 FROM emails
                 INNER JOIN users ON users.pk = emails.user_pk
                 LEFT JOIN users_groups_m2m ON emails.user_pk = users_groups_m2m.user_pk
-                WHERE emails.email = $1
+                LEFT JOIN groups ON groups.pk = users_groups_m2m.group_pk
+                WHERE emails.email = $1 AND (emails.is_primary OR emails.activated_at IS NOT NULL) AND users.password IS NOT NULL
                 GROUP BY emails.pk, users.password;",
         )
         .bind(email)
@@ -295,6 +593,14 @@ pub struct EmailAccount {
     //TODO: use the Email type in smartlink?
 }
 
+#[derive(Debug, FromRow, Serialize)]
+pub struct EmailAddress {
+    pub pk: i64,
+    pub email: String,
+    pub is_primary: bool,
+    pub activated_at: Option<NaiveDateTime>,
+}
+
 impl EmailAccount {
     pub fn mail_server(&self) -> &str {
         self.email
@@ -313,9 +619,11 @@ impl EmailAccount {
     pub async fn get_by_pk<'e, E: PgExecutor<'e>>(pk: i64, executor: E) -> Result<Self, AppError> {
         sqlx::query_as(
             "
-            SELECT emails.pk, emails.user_pk, emails.email, COALESCE(STRING_AGG(users_groups_m2m.group_pk::TEXT, ','), '') AS groups
+            SELECT emails.pk, emails.user_pk, emails.email, COALESCE(STRING_AGG(groups.name, ',' ORDER BY groups.pk), '') AS groups,
+                COALESCE((SELECT STRING_AGG(name, ',') FROM user_permissions WHERE user_permissions.user_pk = emails.user_pk), '') AS permissions
             FROM emails
             LEFT JOIN users_groups_m2m ON users_groups_m2m.user_pk = emails.user_pk
+            LEFT JOIN groups ON groups.pk = users_groups_m2m.group_pk
             WHERE emails.pk = $1
             GROUP BY emails.pk, emails.user_pk, emails.email;",
         )
@@ -325,6 +633,47 @@ impl EmailAccount {
         .map_err(|e| log_and_wrap_custom_internal!(e))
     }
 
+    pub async fn get_by_email<'e, E: PgExecutor<'e>>(
+        email: &str,
+        executor: E,
+    ) -> Result<Option<Self>, AppError> {
+        sqlx::query_as(
+            "
+            SELECT emails.pk, emails.user_pk, emails.email, COALESCE(STRING_AGG(groups.name, ',' ORDER BY groups.pk), '') AS groups,
+                COALESCE((SELECT STRING_AGG(name, ',') FROM user_permissions WHERE user_permissions.user_pk = emails.user_pk), '') AS permissions
+            FROM emails
+            LEFT JOIN users_groups_m2m ON users_groups_m2m.user_pk = emails.user_pk
+            LEFT JOIN groups ON groups.pk = users_groups_m2m.group_pk
+            WHERE emails.email = $1
+            GROUP BY emails.pk, emails.user_pk, emails.email;",
+        )
+        .bind(email)
+        .fetch_optional(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Find an email that has not been validated yet
+    pub async fn get_pending_by_email<'e, E: PgExecutor<'e>>(
+        email: &str,
+        executor: E,
+    ) -> Result<Option<Self>, AppError> {
+        sqlx::query_as(
+            "
+            SELECT emails.pk, emails.user_pk, emails.email, COALESCE(STRING_AGG(groups.name, ',' ORDER BY groups.pk), '') AS groups,
+                COALESCE((SELECT STRING_AGG(name, ',') FROM user_permissions WHERE user_permissions.user_pk = emails.user_pk), '') AS permissions
+            FROM emails
+            LEFT JOIN users_groups_m2m ON users_groups_m2m.user_pk = emails.user_pk
+            LEFT JOIN groups ON groups.pk = users_groups_m2m.group_pk
+            WHERE emails.email = $1 AND emails.activated_at IS NULL
+            GROUP BY emails.pk, emails.user_pk, emails.email;",
+        )
+        .bind(email)
+        .fetch_optional(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
     pub async fn create_primary_active<'e, E: PgExecutor<'e>>(
         user: User,
         email: String,
@@ -361,10 +710,81 @@ impl EmailAccount {
         Ok(Self { pk, user, email })
     }
 
+    pub async fn create_secondary<'e, E: PgExecutor<'e>>(
+        user: User,
+        email: String,
+        executor: E,
+    ) -> Result<Self, AppError> {
+        Self::create(false, user, email, None, executor).await
+    }
+
+    pub async fn list_for_user<'e, E: PgExecutor<'e>>(
+        user_pk: i64,
+        executor: E,
+    ) -> Result<Vec<EmailAddress>, AppError> {
+        sqlx::query_as(
+            "SELECT pk, email, is_primary, activated_at FROM emails WHERE user_pk = $1 ORDER BY is_primary DESC, pk;",
+        )
+        .bind(user_pk)
+        .fetch_all(executor)
+        .await
+        .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Make a validated email of the user its primary one
+    pub async fn set_primary(
+        email_pk: i64,
+        user_pk: i64,
+        tx: &mut PgConnection,
+    ) -> Result<(), AppError> {
+        let validated: bool = sqlx::query_scalar(
+            "SELECT activated_at IS NOT NULL FROM emails WHERE pk = $1 AND user_pk = $2 FOR UPDATE;",
+        )
+        .bind(email_pk)
+        .bind(user_pk)
+        .fetch_one(&mut *tx)
+        .await?;
+        if !validated {
+            return Err(AppError::custom_bad_request(
+                "The email needs to be validated first",
+            ));
+        }
+
+        // Two statements so the one primary per user index is never violated in between
+        sqlx::query("UPDATE emails SET is_primary = FALSE WHERE user_pk = $1 AND is_primary;")
+            .bind(user_pk)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE emails SET is_primary = TRUE WHERE pk = $1;")
+            .bind(email_pk)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+
+    /// The primary email can't be removed, promote another one first
+    pub async fn delete_secondary<'e, E: PgExecutor<'e>>(
+        email_pk: i64,
+        user_pk: i64,
+        executor: E,
+    ) -> Result<(), AppError> {
+        let deleted =
+            sqlx::query("DELETE FROM emails WHERE pk = $1 AND user_pk = $2 AND NOT is_primary;")
+                .bind(email_pk)
+                .bind(user_pk)
+                .execute(executor)
+                .await?
+                .rows_affected();
+        if deleted == 0 {
+            return Err(AppError::DoesNotExist);
+        }
+        Ok(())
+    }
+
     pub async fn set_to_active<'e, E: PgExecutor<'e>>(self, executor: E) -> Result<Self, AppError> {
         let activated_at = chrono::Utc::now().naive_utc();
 
-        sqlx::query("UPDATE emails SET activated_at = $1 WHERE pk = $2;")
+        sqlx::query("UPDATE emails SET activated_at = $1 WHERE pk = $2 AND activated_at IS NULL;")
             .bind(activated_at)
             .bind(self.pk)
             .execute(executor)
@@ -373,6 +793,113 @@ impl EmailAccount {
     }
 }
 
+/// A way to log in other than the password: a provider account linked to the user
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Identity {
+    pub pk: i64,
+    pub user_pk: i64,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+}
+
+impl Identity {
+    pub async fn find<'e, E: PgExecutor<'e>>(
+        provider: &str,
+        subject: &str,
+        executor: E,
+    ) -> Result<Option<Self>, AppError> {
+        sqlx::query_as("SELECT * FROM user_identities WHERE provider = $1 AND subject = $2;")
+            .bind(provider)
+            .bind(subject)
+            .fetch_optional(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Fails with `UniqueViolation` when the provider account is already linked to a user
+    pub async fn create<'e, E: PgExecutor<'e>>(
+        user_pk: i64,
+        provider: &str,
+        subject: &str,
+        email: Option<&str>,
+        executor: E,
+    ) -> Result<Self, AppError> {
+        Ok(sqlx::query_as(
+            "INSERT INTO user_identities (user_pk, provider, subject, email) VALUES ($1, $2, $3, $4) RETURNING *;",
+        )
+        .bind(user_pk)
+        .bind(provider)
+        .bind(subject)
+        .bind(email)
+        .fetch_one(executor)
+        .await?)
+    }
+
+    pub async fn touch<'e, E: PgExecutor<'e>>(&self, executor: E) -> Result<(), AppError> {
+        sqlx::query("UPDATE user_identities SET last_used_at = CURRENT_TIMESTAMP WHERE pk = $1;")
+            .bind(self.pk)
+            .execute(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(())
+    }
+
+    pub async fn list_for_user<'e, E: PgExecutor<'e>>(
+        user_pk: i64,
+        executor: E,
+    ) -> Result<Vec<Self>, AppError> {
+        sqlx::query_as("SELECT * FROM user_identities WHERE user_pk = $1 ORDER BY pk;")
+            .bind(user_pk)
+            .fetch_all(executor)
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+    }
+
+    /// Unlink a provider, unless it is the last way the user has to log in
+    pub async fn delete(pk: i64, user_pk: i64, tx: &mut PgConnection) -> Result<(), AppError> {
+        let methods = LoginMethodCounts::lock(user_pk, tx).await?;
+        if !methods.password && methods.identities <= 1 {
+            return Err(AppError::custom_bad_request(
+                "You can't remove your last login method",
+            ));
+        }
+        let deleted = sqlx::query("DELETE FROM user_identities WHERE pk = $1 AND user_pk = $2;")
+            .bind(pk)
+            .bind(user_pk)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if deleted == 0 {
+            return Err(AppError::DoesNotExist);
+        }
+        Ok(())
+    }
+}
+
+/// How many ways the user has to log in
+#[derive(Debug, FromRow)]
+struct LoginMethodCounts {
+    password: bool,
+    identities: i64,
+}
+
+impl LoginMethodCounts {
+    /// Locks the user so two concurrent unlinks can't remove every method
+    async fn lock(user_pk: i64, tx: &mut PgConnection) -> Result<Self, AppError> {
+        Ok(sqlx::query_as(
+            "SELECT users.password IS NOT NULL AS password,
+                (SELECT COUNT(*) FROM user_identities WHERE user_pk = users.pk) AS identities
+            FROM users WHERE pk = $1 FOR UPDATE;",
+        )
+        .bind(user_pk)
+        .fetch_one(&mut *tx)
+        .await?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use sqlx::PgPool;
@@ -388,7 +915,7 @@ mod tests {
         let user = User::create_active_default(&mut *tx)
             .await
             .unwrap()
-            .add_to_group(Group::User, &mut *tx)
+            .add_to_group(Group::USER, &mut *tx)
             .await
             .unwrap();
         EmailAccount::create_primary_active(
@@ -411,7 +938,7 @@ mod tests {
         let result = result.unwrap();
 
         assert_eq!(result.password, "SDFddg186DFG&$dfg987qzXZCDf688sf4so34$hl#sdfj");
-        assert_eq!(result.user.groups.0, vec![Group::User]);
+        assert_eq!(result.user.groups.to_string(), Group::USER);
     }
 
     #[sqlx::test(migrations = "./migrations/principal")]
@@ -435,12 +962,12 @@ mod tests {
         let user = User::create_active_default(&mut *tx).await;
 
         assert!(user.is_ok());
-        let user = user.unwrap().add_to_group(Group::Admin, &mut *tx).await;
+        let user = user.unwrap().add_to_group(Group::ADMIN, &mut *tx).await;
         tx.commit().await.unwrap();
         assert!(user.is_ok());
         let user = user.unwrap();
         assert!(user.pk > 0);
-        assert_eq!(user.groups.0, vec![Group::Admin]);
+        assert_eq!(user.groups.to_string(), Group::ADMIN);
     }
 
     #[sqlx::test(migrations = "./migrations/principal")]
@@ -451,19 +978,19 @@ mod tests {
         let user = User::create_active_default(&mut *tx)
             .await
             .unwrap()
-            .add_to_group(Group::Admin, &mut *tx)
+            .add_to_group(Group::ADMIN, &mut *tx)
             .await
             .unwrap()
-            .add_to_group(Group::User, &mut *tx)
+            .add_to_group(Group::USER, &mut *tx)
             .await
             .unwrap()
-            .add_to_group(Group::Admin, &mut *tx)
+            .add_to_group(Group::ADMIN, &mut *tx)
             .await
             .unwrap();
         tx.commit().await.unwrap();
 
         assert!(user.pk > 0);
-        assert_eq!(user.groups.0, vec![Group::Admin, Group::User]);
+        assert_eq!(user.groups.to_string(), "Admin,User");
     }
 
     #[sqlx::test(migrations = "./migrations/principal")]
@@ -497,4 +1024,308 @@ mod tests {
         assert_eq!(db_user_pk, account.user.pk);
         assert_eq!(db_email, "test_create_email_account@example.com");
     }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_deactivation_lifecycle(pool: PgPool) {
+        let database: Database = pool.into();
+        let user = User::create_active_default(&*database).await.unwrap();
+        assert!(user.is_authenticated(&database).await.unwrap());
+
+        user.deactivate(false, &*database).await.unwrap();
+        assert!(!user.is_authenticated(&database).await.unwrap());
+        assert_eq!(user.reactivate_self(&*database).await.unwrap(), 1);
+        assert!(user.is_authenticated(&database).await.unwrap());
+
+        assert_eq!(user.reactivate_self(&*database).await.unwrap(), 0);
+
+        user.deactivate(true, &*database).await.unwrap();
+        assert!(matches!(
+            user.reactivate_self(&*database).await,
+            Err(AppError::AccountDeactivated)
+        ));
+        assert!(!user.is_authenticated(&database).await.unwrap());
+        user.reactivate(&*database).await.unwrap();
+        assert!(user.is_authenticated(&database).await.unwrap());
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_scheduled_deletion(pool: PgPool) {
+        let database: Database = pool.into();
+        let kept = User::create_active_default(&*database).await.unwrap();
+        let deleted = User::create_active_default(&*database).await.unwrap();
+
+        for user in [&kept, &deleted] {
+            let identity =
+                Identity::create(user.pk, "google", &user.pk.to_string(), None, &*database)
+                    .await
+                    .unwrap();
+            sqlx::query(
+                "INSERT INTO oauth_tokens (identity_pk, access_token) VALUES ($1, '\\x00');",
+            )
+            .bind(identity.pk)
+            .execute(&*database)
+            .await
+            .unwrap();
+        }
+
+        kept.schedule_deletion(30, &*database).await.unwrap();
+        deleted.schedule_deletion(-1, &*database).await.unwrap();
+        assert!(!kept.is_authenticated(&database).await.unwrap());
+
+        let pks = User::scheduled_for_deletion(&*database).await.unwrap();
+        assert_eq!(pks, vec![deleted.pk]);
+        // Only the accounts still past their grace period go
+        assert!(User::delete_scheduled(&[kept.pk], &*database)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            User::delete_scheduled(&pks, &*database).await.unwrap(),
+            vec![deleted.pk]
+        );
+        let tokens: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM oauth_tokens;")
+            .fetch_one(&*database)
+            .await
+            .unwrap();
+        assert_eq!(tokens, 1);
+        assert!(User::get_by_pk(kept.pk, &*database).await.is_ok());
+        assert!(matches!(
+            User::get_by_pk(deleted.pk, &*database).await,
+            Err(AppError::DoesNotExist)
+        ));
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_secondary_emails(pool: PgPool) {
+        let database: Database = pool.into();
+        let user = User::create_active_default(&*database).await.unwrap();
+        let primary = EmailAccount::create_primary_active(
+            user.clone(),
+            "test_secondary_emails_primary@example.com".into(),
+            &*database,
+        )
+        .await
+        .unwrap();
+        let secondary = EmailAccount::create_secondary(
+            user.clone(),
+            "test_secondary_emails_secondary@example.com".into(),
+            &*database,
+        )
+        .await
+        .unwrap();
+
+        let login = User::find_by_email_with_password(
+            "test_secondary_emails_secondary@example.com",
+            &database,
+        )
+        .await
+        .unwrap();
+        assert!(login.is_none());
+
+        let mut tx = database.start_transaction().await.unwrap();
+        let result = EmailAccount::set_primary(secondary.pk, user.pk, &mut tx).await;
+        assert!(result.is_err());
+        drop(tx);
+
+        let secondary = secondary.set_to_active(&*database).await.unwrap();
+        let login = User::find_by_email_with_password(
+            "test_secondary_emails_secondary@example.com",
+            &database,
+        )
+        .await
+        .unwrap();
+        assert!(login.is_some());
+
+        let mut tx = database.start_transaction().await.unwrap();
+        EmailAccount::set_primary(secondary.pk, user.pk, &mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let emails = EmailAccount::list_for_user(user.pk, &*database)
+            .await
+            .unwrap();
+        assert_eq!(emails.len(), 2);
+        assert_eq!(emails[0].pk, secondary.pk);
+        assert!(emails[0].is_primary);
+        assert!(!emails[1].is_primary);
+
+        assert!(
+            EmailAccount::delete_secondary(secondary.pk, user.pk, &*database)
+                .await
+                .is_err()
+        );
+        EmailAccount::delete_secondary(primary.pk, user.pk, &*database)
+            .await
+            .unwrap();
+        assert_eq!(
+            EmailAccount::list_for_user(user.pk, &*database)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_duplicated_email(pool: PgPool) {
+        let database: Database = pool.into();
+        let user = User::create_active_default(&*database).await.unwrap();
+        EmailAccount::create_primary_active(
+            user.clone(),
+            "test_duplicated_email@example.com".into(),
+            &*database,
+        )
+        .await
+        .unwrap();
+
+        let result = EmailAccount::create_secondary(
+            user,
+            "test_duplicated_email@example.com".into(),
+            &*database,
+        )
+        .await;
+        assert!(matches!(result, Err(AppError::UniqueViolation(_))));
+    }
+
+    #[derive(Clone)]
+    struct AppUser {
+        user: User,
+        email: String,
+    }
+
+    impl UserModel for AppUser {
+        fn pk(&self) -> i64 {
+            self.user.pk
+        }
+
+        fn groups(&self) -> &Groups {
+            &self.user.groups
+        }
+
+        fn permissions(&self) -> &Permissions {
+            &self.user.permissions
+        }
+
+        async fn load(user: User, database: &Database) -> Result<Self, AppError> {
+            let email = sqlx::query_scalar(
+                "SELECT email FROM emails WHERE user_pk = $1 AND is_primary = TRUE;",
+            )
+            .bind(user.pk)
+            .fetch_one(&**database)
+            .await?;
+            Ok(Self { user, email })
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_user_model(pool: PgPool) {
+        let database: Database = pool.into();
+        let user = User::create_active_default(&*database)
+            .await
+            .unwrap()
+            .add_to_group(Group::ADMIN, &*database)
+            .await
+            .unwrap();
+        EmailAccount::create_primary_active(user.clone(), "model@example.com".into(), &*database)
+            .await
+            .unwrap();
+
+        assert!(UserSession::new_anonymous()
+            .load::<AppUser>(&database)
+            .await
+            .unwrap()
+            .is_none());
+
+        let app_user = user
+            .clone()
+            .for_session()
+            .load::<AppUser>(&database)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(app_user.email, "model@example.com");
+        assert!(app_user.is_admin());
+        assert_eq!(app_user.to_session().pk(), Some(user.pk));
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_identities(pool: PgPool) {
+        let database: Database = pool.into();
+        let user = User::create_active("", &*database).await.unwrap();
+        assert!(!user.has_password(&*database).await.unwrap());
+
+        let google = Identity::create(user.pk, "google", "1", None, &*database)
+            .await
+            .unwrap();
+        assert!(matches!(
+            Identity::create(user.pk, "google", "1", None, &*database).await,
+            Err(AppError::UniqueViolation(_))
+        ));
+        assert_eq!(
+            Identity::find("google", "1", &*database)
+                .await
+                .unwrap()
+                .unwrap()
+                .user_pk,
+            user.pk
+        );
+
+        let mut tx = database.start_transaction().await.unwrap();
+        assert!(Identity::delete(google.pk, user.pk, &mut tx).await.is_err());
+        assert!(user.remove_password(&mut tx).await.is_ok());
+        drop(tx);
+
+        let github = Identity::create(user.pk, "github", "1", None, &*database)
+            .await
+            .unwrap();
+        let mut tx = database.start_transaction().await.unwrap();
+        Identity::delete(google.pk, user.pk, &mut tx).await.unwrap();
+        assert!(Identity::delete(github.pk, user.pk, &mut tx).await.is_err());
+        tx.commit().await.unwrap();
+
+        user.set_password(&crate::auth::hash_password("password").unwrap(), &*database)
+            .await
+            .unwrap();
+        assert!(user.has_password(&*database).await.unwrap());
+        let mut tx = database.start_transaction().await.unwrap();
+        Identity::delete(github.pk, user.pk, &mut tx).await.unwrap();
+        assert!(user.remove_password(&mut tx).await.is_err());
+        tx.commit().await.unwrap();
+        assert!(Identity::list_for_user(user.pk, &*database)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test(migrations = "./migrations/principal")]
+    async fn test_removed_password(pool: PgPool) {
+        let database: Database = pool.into();
+        let password = crate::auth::hash_password("password").unwrap();
+        let user = User::create_active(&password, &*database).await.unwrap();
+        EmailAccount::create_primary_active(user.clone(), "removed@example.com".into(), &*database)
+            .await
+            .unwrap();
+        Identity::create(user.pk, "google", "1", None, &*database)
+            .await
+            .unwrap();
+        assert!(
+            User::find_by_email_with_password("removed@example.com", &database)
+                .await
+                .unwrap()
+                .is_some()
+        );
+
+        let mut tx = database.start_transaction().await.unwrap();
+        user.remove_password(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+        assert!(!user.has_password(&*database).await.unwrap());
+        // The password logins see no user instead of an invalid hash
+        assert!(
+            User::find_by_email_with_password("removed@example.com", &database)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
 }
@@ -6,7 +6,9 @@ mod tests;
 
 mod versioning;
 
-pub use responses::{AppJson, AppResult, ErrorMessage, Pagination, PaginatedResponse};
+pub use responses::{
+    AppJson, AppResult, ErrorMessage, PaginatedResponse, Pagination, ScopeErrorMessage,
+};
 pub use router::get_router;
 pub use services::{shutdown_signal, Service, ServiceExt};
 pub use tests::StubService;
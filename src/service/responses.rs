@@ -67,3 +67,21 @@ impl ErrorMessage {
         Self { message }
     }
 }
+
+/// The 403 of the routes requiring a scope the token or key doesn't have
+#[derive(Serialize, ToResponse, ToSchema)]
+pub struct ScopeErrorMessage {
+    #[schema(example = "Missing the scope billing:write")]
+    message: String,
+    #[schema(example = "billing:write")]
+    required_scope: String,
+}
+
+impl ScopeErrorMessage {
+    pub fn new(required_scope: String) -> Self {
+        Self {
+            message: format!("Missing the scope {required_scope}"),
+            required_scope,
+        }
+    }
+}
@@ -4,6 +4,11 @@ use std::net::SocketAddr;
 use tokio::{net::TcpListener, signal};
 
 use crate::{
+    audit::AuditLog,
+    auth::{
+        delete_scheduled_accounts, CallbackValidation, EmailValidationManager, MagicLinkManager,
+        RevokedToken, TokenVault,
+    },
     config::{APIConfig, ServiceConfig, WebsiteConfig},
     state::{APIState, SharedState, WebsiteState},
 };
@@ -17,6 +22,8 @@ pub struct WebsiteService {
     config: WebsiteConfig,
     router_factory: fn(WebsiteState) -> Router<WebsiteState>,
     router: Option<Router>,
+    // Note: periodic jobs of the state, started with the server
+    jobs: Vec<BoxFuture<'static, ()>>,
 }
 
 impl WebsiteService {
@@ -25,6 +32,7 @@ impl WebsiteService {
             config: WebsiteConfig::from_env_with_prefix(env_prefix),
             router_factory,
             router: None,
+            jobs: Vec::new(),
         }
     }
 }
@@ -35,6 +43,7 @@ impl ServiceExt for WebsiteService {
             config: WebsiteConfig::stub(),
             router_factory: self.router_factory,
             router: None,
+            jobs: Vec::new(),
         }
     }
 
@@ -42,12 +51,27 @@ impl ServiceExt for WebsiteService {
         let state = WebsiteState::new(self.config.clone(), shared);
         state.sessions().run_migrations().await;
         let routes = (self.router_factory)(state.clone());
+        self.jobs = vec![
+            Box::pin(CallbackValidation::run_cleanup(state.clone())),
+            Box::pin(EmailValidationManager::run_cleanup(state.clone())),
+            Box::pin(MagicLinkManager::run_cleanup(state.clone())),
+            Box::pin(AuditLog::run_sealer(state.database().clone())),
+            Box::pin(delete_scheduled_accounts(state.clone())),
+        ];
+        // A zero interval disables the refresh of the stored provider tokens
+        if self.config.oauth_token_refresh_interval > 0 {
+            self.jobs
+                .push(Box::pin(TokenVault::run_refresher(state.clone())));
+        }
         let router = get_router(state, routes);
         self.router = Some(router);
     }
 
     async fn run(self) -> Result<(), std::io::Error> {
         let addr = TcpListener::bind(self.config.socket_addr()).await.unwrap();
+        for job in self.jobs {
+            tokio::spawn(job);
+        }
         axum::serve(
             addr,
             self.router
@@ -63,14 +87,19 @@ pub struct APIService {
     config: APIConfig,
     router_factory: fn(APIState) -> Router<APIState>,
     router: Option<Router>,
+    // Note: periodic jobs of the state, started with the server
+    jobs: Vec<BoxFuture<'static, ()>>,
 }
 
 impl APIService {
     fn new(env_prefix: &str, router_factory: fn(APIState) -> Router<APIState>) -> Self {
+        let config = APIConfig::from_env_with_prefix(env_prefix);
+        config.validate().expect("Invalid API config");
         Self {
-            config: APIConfig::from_env_with_prefix(env_prefix),
+            config,
             router_factory,
             router: None,
+            jobs: Vec::new(),
         }
     }
 }
@@ -81,18 +110,27 @@ impl ServiceExt for APIService {
             config: APIConfig::stub(),
             router_factory: self.router_factory,
             router: None,
+            jobs: Vec::new(),
         }
     }
 
     async fn set_up(&mut self, shared: SharedState) {
-        let state = APIState::new(self.config.clone(), shared);
+        // `new` validated the config, so the keys load
+        let state = APIState::new(self.config.clone(), shared).expect("Invalid API config");
         let routes = (self.router_factory)(state.clone());
+        self.jobs = vec![
+            Box::pin(RevokedToken::run_cleanup(state.clone())),
+            Box::pin(AuditLog::run_sealer(state.database().clone())),
+        ];
         let router = get_router(state, routes);
         self.router = Some(router);
     }
 
     async fn run(self) -> Result<(), std::io::Error> {
         let addr = TcpListener::bind(self.config.socket_addr()).await.unwrap();
+        for job in self.jobs {
+            tokio::spawn(job);
+        }
         axum::serve(
             addr,
             self.router
@@ -51,6 +51,29 @@ impl Session {
         self.0.read().await.user.pk()
     }
 
+    /// The admin impersonating the user of the session, if any
+    pub async fn impersonator_pk(&self) -> Option<i64> {
+        self.0.read().await.impersonator_pk
+    }
+
+    /// The organization the user is working in, see `CurrentOrganization`
+    pub async fn organization_pk(&self) -> Option<i64> {
+        self.0.read().await.organization_pk
+    }
+
+    pub async fn is_impersonating(&self) -> bool {
+        self.0.read().await.impersonator_pk.is_some()
+    }
+
+    /// Guard for the sensitive actions that an admin can't do on behalf of a user
+    pub async fn forbid_impersonation(&self) -> Result<(), AppError> {
+        if self.is_impersonating().await {
+            Err(AppError::ImpersonationForbidden)
+        } else {
+            Ok(())
+        }
+    }
+
     pub async fn id(&self) -> String {
         self.0.read().await.session_id.to_owned()
     }
@@ -122,11 +145,58 @@ impl Sessions {
         Ok(Session(Arc::new(RwLock::new(session))))
     }
 
+    /// Log the user out everywhere
+    pub async fn delete_user_sessions(&self, user_pk: i64) -> Result<u64, AppError> {
+        sqlx::query("DELETE FROM web_sessions WHERE user_pk = $1;")
+            .bind(user_pk)
+            .execute(self.get_connection())
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))
+            .map(|r| r.rows_affected())
+    }
+
+    pub async fn export_user_sessions(
+        &self,
+        user_pk: i64,
+    ) -> Result<Vec<serde_json::Value>, AppError> {
+        let rows: Vec<(NaiveDateTime, NaiveDateTime, NaiveDateTime, Option<String>)> =
+            sqlx::query_as(
+                "SELECT created_at, last_accessed, expiration, country FROM web_sessions WHERE user_pk = $1;",
+            )
+            .bind(user_pk)
+            .fetch_all(self.get_connection())
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        Ok(rows
+            .into_iter()
+            .map(|(created_at, last_accessed, expiration, country)| {
+                serde_json::json!({
+                    "created_at": created_at,
+                    "last_accessed": last_accessed,
+                    "expiration": expiration,
+                    "country": country,
+                })
+            })
+            .collect())
+    }
+
     pub async fn reuse_current_as_new_one(
         &self,
         session: &Session,
         user: UserSession,
         secret: &str,
+    ) -> Result<(), AppError> {
+        self.reuse_current_as_impersonation(session, user, None, secret)
+            .await
+    }
+
+    /// Same as [`Sessions::reuse_current_as_new_one`] but remembering the admin behind the user
+    pub async fn reuse_current_as_impersonation(
+        &self,
+        session: &Session,
+        user: UserSession,
+        impersonator_pk: Option<i64>,
+        secret: &str,
     ) -> Result<(), AppError> {
         //TODO: this is shit
         session
@@ -140,10 +210,29 @@ impl Sessions {
             .update_csrf_token(secret)
             //NOTE: the orden of the date + token is important so the token has the new date. not pretty
             .update_user(user)
+            .update_impersonator(impersonator_pk)
+            .update_organization(None)
             .save(self.get_connection())
             .await?;
         Ok(())
     }
+
+    /// Switch the active organization of the session, the membership is checked by the caller
+    pub async fn set_organization(
+        &self,
+        session: &Session,
+        organization_pk: Option<i64>,
+    ) -> Result<(), AppError> {
+        let mut data = session.0.write().await;
+        sqlx::query("UPDATE web_sessions SET organization_pk = $1 WHERE session_id = $2;")
+            .bind(organization_pk)
+            .bind(&data.session_id)
+            .execute(self.get_connection())
+            .await
+            .map_err(|e| log_and_wrap_custom_internal!(e))?;
+        data.update_organization(organization_pk);
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize, sqlx::FromRow, Clone)]
@@ -158,6 +247,8 @@ pub struct SessionData {
     csrf_token: String,
     data: Option<Vec<u8>>,
     country: Option<String>,
+    impersonator_pk: Option<i64>,
+    organization_pk: Option<i64>,
 }
 
 impl SessionData {
@@ -182,6 +273,8 @@ impl SessionData {
             csrf_token: String::default(),
             data: None,
             country,
+            impersonator_pk: None,
+            organization_pk: None,
         };
         session.update_csrf_token(secret);
         //TODO: improve how token is created and set. this is a little convoluted
@@ -202,6 +295,16 @@ impl SessionData {
         self
     }
 
+    fn update_impersonator(&mut self, impersonator_pk: Option<i64>) -> &mut Self {
+        self.impersonator_pk = impersonator_pk;
+        self
+    }
+
+    fn update_organization(&mut self, organization_pk: Option<i64>) -> &mut Self {
+        self.organization_pk = organization_pk;
+        self
+    }
+
     fn update_csrf_token(&mut self, secret: &str) -> &mut Self {
         self.csrf_token = generate_token(secret, &self.get_token_data());
         self
@@ -239,15 +342,18 @@ impl SessionData {
     }
 
     async fn save(&self, conn: &SqlitePool) -> Result<i64, AppError> {
-        sqlx::query("INSERT INTO web_sessions(session_id, user_pk, groups, last_accessed, created_at, expiration, data, country) VALUES ($1, $2, $3, $4, $5, $6, $7, $8);")
+        sqlx::query("INSERT INTO web_sessions(session_id, user_pk, groups, permissions, last_accessed, created_at, expiration, data, country, impersonator_pk, organization_pk) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11);")
             .bind(&self.session_id)
             .bind(self.user.pk())
             .bind(self.user.groups().map(|u|u.to_string()))
+            .bind(self.user.permissions().map(|p|p.to_string()).unwrap_or_default())
             .bind(self.last_accessed)
             .bind(self.created_at)
             .bind(self.expiration)
             .bind(&self.data)
             .bind(&self.country)
+            .bind(self.impersonator_pk)
+            .bind(self.organization_pk)
             .execute(conn)
             .await
             .map_err(|e| log_and_wrap_custom_internal!(e))
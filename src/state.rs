@@ -1,10 +1,13 @@
 use std::net::SocketAddr;
 
 use axum::extract::FromRef;
-use jsonwebtoken::{DecodingKey, EncodingKey, Validation};
+use jsonwebtoken::Validation;
 
 use crate::{
-    auth::{create_validator, Keys},
+    auth::{
+        create_validator, GoogleProvider, Keys, OAuthProvider, OAuthProviders, PasswordHasher,
+        TokenVault,
+    },
     broker::Broker,
     config::{APIConfig, Env, ServiceConfig, SharedConfig, WebsiteConfig},
     database::{Database, IpsDatabase},
@@ -59,6 +62,12 @@ impl SharedState {
         &self.database
     }
 
+    #[cfg(test)]
+    pub(crate) fn with_database(mut self, database: Database) -> Self {
+        self.database = database;
+        self
+    }
+
     pub fn events_broker(&self) -> &Broker {
         &self.events_broker
     }
@@ -73,17 +82,41 @@ pub struct WebsiteState {
     secrets: WebsiteConfig,
     shared: SharedState,
     sessions: Sessions,
+    password_hasher: PasswordHasher,
+    oauth_providers: OAuthProviders,
+    token_vault: TokenVault,
 }
 
 impl WebsiteState {
     pub fn new(secrets: WebsiteConfig, shared: SharedState) -> Self {
+        let oauth_providers = OAuthProviders::default();
+        if !secrets.google_client_id.is_empty() {
+            oauth_providers.add(GoogleProvider::from_config(&secrets));
+        }
         Self {
             sessions: Sessions::new(&secrets.sessions_db),
+            password_hasher: secrets.password_hasher(),
+            token_vault: secrets.token_vault(),
+            oauth_providers,
             shared,
             secrets,
         }
     }
 
+    /// Register a provider for the `/oauth/{provider}/...` routes, replacing any with its name
+    pub fn with_oauth_provider(self, provider: impl OAuthProvider + 'static) -> Self {
+        self.oauth_providers.add(provider);
+        self
+    }
+
+    pub fn oauth_providers(&self) -> &OAuthProviders {
+        &self.oauth_providers
+    }
+
+    pub fn token_vault(&self) -> &TokenVault {
+        &self.token_vault
+    }
+
     pub fn events_broker(&self) -> &Broker {
         &self.shared.events_broker
     }
@@ -122,6 +155,10 @@ impl WebsiteState {
         &self.sessions
     }
 
+    pub fn password_hasher(&self) -> &PasswordHasher {
+        &self.password_hasher
+    }
+
     pub fn database(&self) -> &Database {
         &self.shared.database
     }
@@ -149,22 +186,28 @@ pub struct APIState {
     shared: SharedState,
     keys: Keys,
     jwt_validator: Validation,
+    password_hasher: PasswordHasher,
 }
 
 impl APIState {
-    pub fn new(secrets: APIConfig, shared: SharedState) -> Self {
-        Self {
-            keys: Keys::new(secrets.session_key.as_bytes()),
-            jwt_validator: create_validator(secrets.domain()),
+    pub fn new(secrets: APIConfig, shared: SharedState) -> Result<Self, AppError> {
+        Ok(Self {
+            keys: secrets.jwt_keys()?,
+            jwt_validator: create_validator(&secrets),
+            password_hasher: secrets.password_hasher(),
             shared,
             secrets,
-        }
+        })
     }
 
     pub fn domain(&self) -> &str {
         self.secrets.domain()
     }
 
+    pub fn config(&self) -> &APIConfig {
+        &self.secrets
+    }
+
     pub fn events_broker(&self) -> &Broker {
         &self.shared.events_broker
     }
@@ -177,17 +220,17 @@ impl APIState {
         &self.shared.database
     }
 
-    pub fn encoding(&self) -> &EncodingKey {
-        &self.keys.encoding
-    }
-
-    pub fn decoding(&self) -> &DecodingKey {
-        &self.keys.decoding
+    pub fn keys(&self) -> &Keys {
+        &self.keys
     }
 
     pub fn validator(&self) -> &Validation {
         &self.jwt_validator
     }
+
+    pub fn password_hasher(&self) -> &PasswordHasher {
+        &self.password_hasher
+    }
 }
 
 impl FromRef<APIState> for Database {
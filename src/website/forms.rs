@@ -113,6 +113,10 @@ where
     }
 }
 
+/// The data of the forms that only carry the CSRF token, e.g. a confirmation button
+#[derive(Debug, Default, Validate, Deserialize)]
+pub struct EmptyForm {}
+
 #[derive(Debug, Deserialize)]
 pub struct SecureForm<T> {
     #[serde(flatten)]
@@ -3,4 +3,4 @@ pub mod html;
 pub mod meta_tags;
 pub mod views;
 
-pub use forms::{CaptchaForm, SecureForm, SecureJson};
+pub use forms::{CaptchaForm, EmptyForm, SecureForm, SecureJson};